@@ -0,0 +1,307 @@
+extern crate base64;
+extern crate chrono;
+extern crate native_tls;
+extern crate reqwest;
+extern crate serde_json;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use self::chrono::Utc;
+use self::native_tls::TlsConnector;
+
+use bot;
+use config::{Config, WebhookConfig, XmppConfig};
+use hash;
+use http::escape_html;
+
+// Seconds of the webhook requests and of every read from the XMPP server
+const TIMEOUT: u64 = 10;
+// XMPP answers are read until the expected element, the longer ones are broken
+const XMPP_LIMIT: usize = 64 * 1024;
+
+/// Kind of the admin alert, the webhooks get it as `{event}` and filter the alerts by it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The file is waiting for the approval
+    NewRequest,
+    /// The printer refused the file
+    PrinterError,
+    /// The file was sent to the printer
+    JobDone,
+    /// The API client withdrew the file or canceled its job
+    JobCanceled,
+    /// The worker thread failed and is restarted
+    WorkerFailed,
+    /// Report of the spool cleanup
+    Cleanup,
+}
+
+/// Alert for the admin
+pub struct Alert {
+    pub event: Event,
+    pub text: String,
+    /// ID of the spooled file, which the alert is about
+    pub file: Option<String>,
+}
+
+impl Alert {
+    pub fn new(event: Event, text: &str) -> Alert {
+        Alert { event, text: text.to_string(), file: None }
+    }
+
+    pub fn with_file(mut self, file_id: &str) -> Alert {
+        self.file = Some(file_id.to_string());
+        self
+    }
+}
+
+/// Destination of the admin alerts
+pub trait AlertSink: Send {
+    /// Name of the destination in the logs
+    fn name(&self) -> String;
+
+    /// Whether the destination wants the alerts of the kind
+    fn accepts(&self, _event: Event) -> bool {
+        true
+    }
+
+    fn send(&self, alert: &Alert) -> Result<(), String>;
+}
+
+/// The admin chat of the Telegram bot
+pub struct TelegramAdmin {
+    pub token: String,
+    pub admin: i64,
+}
+
+impl AlertSink for TelegramAdmin {
+    fn name(&self) -> String {
+        "Telegram".to_string()
+    }
+
+    fn send(&self, alert: &Alert) -> Result<(), String> {
+        bot::send_message(&self.token, self.admin, &alert.text)
+    }
+}
+
+/// HTTP webhook, like the incoming webhook of Slack or Mattermost
+pub struct Webhook {
+    pub config: WebhookConfig,
+}
+
+// Escapes the value for the JSON string, the quotes are in the template
+fn json_escape(text: &str) -> String {
+    let quoted = serde_json::to_string(text).unwrap_or("\"\"".to_string());
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+impl Webhook {
+    /// Returns the body of the request by the template
+    pub fn render(&self, alert: &Alert, time: i64) -> String {
+        self.config.template
+            .replace("{event}", &json_escape(&format!("{:?}", alert.event)))
+            .replace("{text}", &json_escape(&alert.text))
+            .replace("{file}", &json_escape(alert.file.as_ref().map_or("", |id| id.as_str())))
+            .replace("{time}", &format!("{}", time))
+    }
+}
+
+impl AlertSink for Webhook {
+    fn name(&self) -> String {
+        // the path of the webhooks often contains their secret
+        match reqwest::Url::parse(&self.config.url) {
+            Ok(url) => format!("webhook {}", url.host_str().unwrap_or("")),
+            Err(_) => "webhook".to_string(),
+        }
+    }
+
+    fn accepts(&self, event: Event) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&event)
+    }
+
+    fn send(&self, alert: &Alert) -> Result<(), String> {
+        let body = self.render(alert, Utc::now().timestamp());
+        let client = match reqwest::Client::builder().timeout(Duration::from_secs(TIMEOUT)).build() {
+            Ok(data) => data,
+            Err(err) => return Err(format!("{:?}", err)),
+        };
+        let mut request = client.post(&self.config.url)
+            .header("Content-Type", "application/json")
+            .header("X-Teleprint-Event", format!("{:?}", alert.event));
+        if !self.config.secret.is_empty() {
+            let signature = hash::hmac_sha256(self.config.secret.as_bytes(), body.as_bytes());
+            request = request.header("X-Teleprint-Signature", format!("sha256={}", signature));
+        }
+        match request.body(body).send() {
+            Ok(ref resp) if resp.status().is_success() => Ok(()),
+            Ok(resp) => Err(format!("The webhook responded with {}", resp.status())),
+            Err(err) => Err(err.get_ref().map_or("request failed".to_string(), |err| format!("{}", err))),
+        }
+    }
+}
+
+/// XMPP account, which sends the alerts as the chat messages
+pub struct Xmpp {
+    pub config: XmppConfig,
+}
+
+// Reads the answer of the server until one of the elements
+fn read_until<S: Read>(stream: &mut S, markers: &[&str]) -> Result<String, String> {
+    let mut answer: Vec<u8> = vec![];
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = match stream.read(&mut buffer) {
+            Ok(0) => return Err("The XMPP server closed the connection".to_string()),
+            Ok(data) => data,
+            Err(err) => return Err(format!("Error on reading from the XMPP server: {}", err)),
+        };
+        answer.extend_from_slice(&buffer[..read]);
+        let text = String::from_utf8_lossy(&answer).to_string();
+        if markers.iter().any(|marker| text.contains(marker)) {
+            return Ok(text);
+        }
+        if answer.len() > XMPP_LIMIT {
+            return Err("Too long answer of the XMPP server".to_string());
+        }
+    }
+}
+
+fn write_all<S: Write>(stream: &mut S, text: &str) -> Result<(), String> {
+    match stream.write_all(text.as_bytes()).and_then(|_| stream.flush()) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error on writing to the XMPP server: {}", err)),
+    }
+}
+
+// Opens the stream, it is opened again after STARTTLS and the authentication, returns the stream features
+fn open_stream<S: Read + Write>(stream: &mut S, domain: &str) -> Result<String, String> {
+    write_all(stream, &format!("<?xml version='1.0'?><stream:stream to='{}' version='1.0' xmlns='jabber:client' \
+                                xmlns:stream='http://etherx.jabber.org/streams'>", escape_html(domain)))?;
+    read_until(stream, &["</stream:features>", "<stream:error"])
+}
+
+impl AlertSink for Xmpp {
+    fn name(&self) -> String {
+        "XMPP".to_string()
+    }
+
+    fn accepts(&self, event: Event) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&event)
+    }
+
+    fn send(&self, alert: &Alert) -> Result<(), String> {
+        let jid = self.config.jid.split('/').next().unwrap_or("");
+        let (user, domain) = match jid.find('@') {
+            Some(at) => (&jid[..at], &jid[at + 1..]),
+            None => return Err(format!("Wrong JID {}", self.config.jid)),
+        };
+        let address = if self.config.server.is_empty() { format!("{}:5222", domain) } else { self.config.server.clone() };
+
+        let mut plain = match TcpStream::connect(&address) {
+            Ok(data) => data,
+            Err(err) => return Err(format!("Error on connecting to {}: {}", address, err)),
+        };
+        match plain.set_read_timeout(Some(Duration::from_secs(TIMEOUT))) {
+            Ok(_) => (),
+            Err(err) => return Err(format!("{}", err)),
+        };
+
+        // the password is never sent without TLS
+        let features = open_stream(&mut plain, domain)?;
+        if !features.contains("<starttls") {
+            return Err("The XMPP server doesn't offer STARTTLS".to_string());
+        }
+        write_all(&mut plain, "<starttls xmlns='urn:ietf:params:xml:ns:xmpp-tls'/>")?;
+        if !read_until(&mut plain, &["<proceed", "<failure"])?.contains("<proceed") {
+            return Err("The XMPP server refused STARTTLS".to_string());
+        }
+        let connector = match TlsConnector::new() {
+            Ok(data) => data,
+            Err(err) => return Err(format!("{}", err)),
+        };
+        let mut stream = match connector.connect(domain, plain) {
+            Ok(data) => data,
+            Err(err) => return Err(format!("Error on TLS handshake with the XMPP server: {}", err)),
+        };
+
+        let features = open_stream(&mut stream, domain)?;
+        if !features.contains(">PLAIN<") {
+            return Err("The XMPP server doesn't offer SASL PLAIN".to_string());
+        }
+        let credentials = base64::encode(format!("\0{}\0{}", user, self.config.password).as_bytes());
+        write_all(&mut stream, &format!("<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>{}</auth>",
+                                        credentials))?;
+        if !read_until(&mut stream, &["<success", "<failure"])?.contains("<success") {
+            return Err("XMPP authentication failed".to_string());
+        }
+
+        open_stream(&mut stream, domain)?;
+        write_all(&mut stream, "<iq type='set' id='bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+                                <resource>teleprint</resource></bind></iq>")?;
+        let bound = read_until(&mut stream, &["</iq>", "<stream:error"])?;
+        if !bound.contains("type='result'") && !bound.contains("type=\"result\"") {
+            return Err("The XMPP server refused to bind the resource".to_string());
+        }
+
+        for to in &self.config.to {
+            write_all(&mut stream, &format!("<message to='{}' type='chat'><body>{}</body></message>",
+                                            escape_html(to), escape_html(&alert.text)))?;
+        }
+        write_all(&mut stream, "</stream:stream>")
+    }
+}
+
+
+// Destinations of the alerts besides Telegram, which are set in the `[alerts]` section
+fn sinks(config: &Config) -> Vec<Box<dyn AlertSink>> {
+    let mut sinks: Vec<Box<dyn AlertSink>> = vec![];
+    for webhook in config.alerts.webhooks.iter().filter(|webhook| !webhook.url.is_empty()) {
+        sinks.push(Box::new(Webhook { config: webhook.clone() }));
+    }
+    if config.alerts.xmpp.enabled {
+        sinks.push(Box::new(Xmpp { config: config.alerts.xmpp.clone() }));
+    }
+    sinks
+}
+
+fn deliver(sinks: Vec<Box<dyn AlertSink>>, alert: Alert) {
+    if sinks.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        for sink in sinks.iter().filter(|sink| sink.accepts(alert.event)) {
+            match sink.send(&alert) {
+                Ok(_) => (),
+                Err(err) => error!("Error on sending the alert to {}: {}", sink.name(), err),
+            };
+        }
+    });
+}
+
+/// Sends the alert to the webhooks and XMPP in the background, used for the events,
+/// which the admin already sees in Telegram as the approval requests and the answers to the commands
+///
+/// # Examples
+///
+/// ```rust
+/// notify(&config, Alert::new(Event::JobDone, "File 4f2a9c01d3e5b768 has been sent to the printer").with_file("4f2a9c01d3e5b768"));
+/// ```
+pub fn notify(config: &Config, alert: Alert) {
+    deliver(sinks(config), alert);
+}
+
+/// Sends the alert to the admin in Telegram, the webhooks and XMPP in the background
+///
+/// # Examples
+///
+/// ```rust
+/// notify_admin(&config, admin, Alert::new(Event::WorkerFailed, "Teleprint: IMAP bot panicked"));
+/// ```
+pub fn notify_admin(config: &Config, admin: i64, alert: Alert) {
+    let mut sinks: Vec<Box<dyn AlertSink>> = vec![Box::new(TelegramAdmin { token: config.token.clone(), admin })];
+    sinks.append(&mut self::sinks(config));
+    deliver(sinks, alert);
+}
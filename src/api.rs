@@ -0,0 +1,255 @@
+extern crate serde;
+extern crate serde_json;
+
+use std::sync::{Arc, Mutex};
+
+use self::serde::Serialize;
+
+use alerts::{self, Alert, Event};
+use audit;
+use config::Config;
+use database;
+use hash;
+use http::{self, Handler, Request, Response};
+use pipeline::{self, Attachment, Identity, IngestChannel, Notifier, Silent};
+use printer;
+use spool::{self, Channel, FileMeta};
+use supervisor;
+use transform;
+
+const PREFIX: &str = "/api/v1";
+
+/// State of the submitted file, returned by the API
+#[derive(Serialize)]
+struct JobStatus {
+    id: String,
+    name: String,
+    /// `pending`, `printed`, `rejected` or `canceled`
+    status: String,
+    pages: Option<u32>,
+    size: u64,
+    options: Option<String>,
+    received: i64,
+    printed: Option<i64>,
+    rejected: Option<i64>,
+    /// CUPS job ID of the printed file
+    job: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Printer {
+    name: String,
+    state: String,
+    /// The files are printed on this printer
+    default: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// The HTTP API as the source of the print requests, the clients get the answers in the responses
+pub struct ApiChannel;
+
+impl IngestChannel for ApiChannel {
+    fn channel(&self) -> Channel {
+        Channel::Api
+    }
+
+    fn notifier(&self, _identity: &Identity) -> Box<dyn Notifier> {
+        Box::new(Silent)
+    }
+}
+
+impl JobStatus {
+    fn new(meta: &FileMeta) -> JobStatus {
+        let status = match (meta.printed, meta.rejected) {
+            (Some(_), _) => "printed",
+            (None, Some(_)) => "rejected",
+            (None, None) => "pending",
+        };
+        JobStatus {
+            id: meta.id.clone(),
+            name: meta.original_name.clone(),
+            status: status.to_string(),
+            pages: meta.pages,
+            size: meta.size,
+            options: meta.options.clone(),
+            received: meta.received,
+            printed: meta.printed,
+            rejected: meta.rejected,
+            job: meta.job.clone(),
+        }
+    }
+}
+
+
+fn json<T: Serialize>(status: u16, value: &T) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(data) => Response::new(status, "application/json", data),
+        Err(err) => Response::text(500, &format!("Error on serializing the response: {}", err)),
+    }
+}
+
+fn error_json(status: u16, message: &str) -> Response {
+    json(status, &ErrorBody { error: message.to_string() })
+}
+
+// Returns the token from `Authorization: Bearer <token>`
+fn bearer(request: &Request) -> Option<String> {
+    request.header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer ").map(|token| token.trim().to_string()))
+        .filter(|token| !token.is_empty())
+}
+
+// Returns the file, if it was submitted with the token, the files keep the fingerprint of the token
+fn own_file(config: &Config, token: &str, id: &str) -> Option<FileMeta> {
+    let submitter = hash::mask_submitter(token);
+    spool::read_meta(&config.spool.dir, id).ok()
+        .filter(|meta| meta.channel == Channel::Api && meta.submitter == submitter)
+}
+
+
+fn list_printers(config: &Config) -> Response {
+    match printer::printers() {
+        Ok(printers) => {
+            let printers: Vec<Printer> = printers.into_iter().map(|status| Printer {
+                default: status.name == config.printer,
+                name: status.name,
+                state: status.state,
+            }).collect();
+            json(200, &printers)
+        }
+        Err(err) => error_json(500, &err),
+    }
+}
+
+fn list_jobs(config: &Config, token: &str) -> Response {
+    let submitter = hash::mask_submitter(token);
+    match spool::list(&config.spool.dir) {
+        Ok(files) => {
+            let jobs: Vec<JobStatus> = files.iter()
+                .filter(|meta| meta.channel == Channel::Api && meta.submitter == submitter && meta.derived_from.is_none())
+                .map(JobStatus::new)
+                .collect();
+            json(200, &jobs)
+        }
+        Err(err) => error_json(500, &err),
+    }
+}
+
+// Sends the file from the body to the admin for the approval like the files from the bots
+fn submit(request: &Request, token: &str, a_config: &Arc<Mutex<Config>>,
+          a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    if supervisor::shutting_down() {
+        return error_json(503, "Teleprint is restarting, send the file again in a minute");
+    }
+    if request.body.is_empty() {
+        return error_json(400, "No file was sent");
+    }
+
+    let args: Vec<&str> = request.query.get("options").map_or(vec![], |options| options.split_whitespace().collect());
+    let options = match transform::Options::parse(&args) {
+        Ok(data) => data,
+        Err(err) => return error_json(400, &format!("Error in the print options: {}", err)),
+    };
+    let name = request.query.get("name").map_or("upload", |name| name.as_str());
+
+    // the type is detected by the contents, so the file passes the same checks as on the upload page
+    let attachment = Attachment::Document { name: name.to_string(), data: request.body.clone() };
+    let meta = match pipeline::submit(ApiChannel.request(Identity::ApiToken(token.to_string()), attachment, options),
+                                      a_config, a_users_table) {
+        Ok(data) => data,
+        Err(ref err) if err == pipeline::UNAUTHORIZED => return error_json(401, err),
+        Err(err) => return error_json(400, &err),
+    };
+
+    json(201, &JobStatus::new(&meta)).with_header("Location", &format!("{}/jobs/{}", PREFIX, meta.id))
+}
+
+// Withdraws the pending file or cancels the CUPS job of the printed one
+fn cancel(config: &Config, token: &str, meta: FileMeta, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    let actor = hash::mask_token(token);
+    let result = match (meta.is_pending(), meta.job.as_ref()) {
+        (true, _) => spool::delete(&config.spool.dir, &meta.id).map(|_| meta.id.clone()),
+        (false, Some(job)) if meta.rejected.is_none() => printer::cancel(job).map(|_| job.clone()),
+        _ => return error_json(409, &format!("The file is already {}", JobStatus::new(&meta).status)),
+    };
+
+    match result {
+        Ok(target) => {
+            audit::record(&actor, Channel::Api, audit::Action::Cancel, &target);
+            let admin = { a_users_table.lock().unwrap().get_admin() };
+            alerts::notify_admin(config, admin, Alert::new(Event::JobCanceled, &format!("API client {} canceled {}", actor, meta.id))
+                .with_file(&meta.id));
+            let mut status = JobStatus::new(&meta);
+            status.status = "canceled".to_string();
+            json(200, &status)
+        }
+        Err(err) => error_json(409, &err),
+    }
+}
+
+
+fn handle(request: &Request, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    let token = match bearer(request) {
+        Some(ref data) if a_users_table.lock().unwrap().check_api_token(data) => data.clone(),
+        other => {
+            let actor = other.map_or("anonymous".to_string(), |token| hash::mask_token(&token));
+            audit::record(&actor, Channel::Api, audit::Action::Unauthorized, &request.path);
+            return error_json(401, "Unknown API token").with_header("WWW-Authenticate", "Bearer");
+        }
+    };
+
+    let config = { a_config.lock().unwrap().clone() };
+    let path = request.path.trim_end_matches('/');
+    if !path.starts_with(PREFIX) {
+        return error_json(404, "Not found");
+    }
+    let parts: Vec<&str> = path[PREFIX.len()..].trim_start_matches('/').split('/').collect();
+
+    match (request.method.as_str(), parts.as_slice()) {
+        ("GET", ["printers"]) => list_printers(&config),
+        ("GET", ["jobs"]) => list_jobs(&config, &token),
+        ("POST", ["jobs"]) => submit(request, &token, a_config, a_users_table),
+        ("GET", ["jobs", id]) => match own_file(&config, &token, id) {
+            Some(meta) => json(200, &JobStatus::new(&meta)),
+            None => error_json(404, "No such job"),
+        },
+        ("DELETE", ["jobs", id]) => match own_file(&config, &token, id) {
+            Some(meta) => cancel(&config, &token, meta, a_users_table),
+            None => error_json(404, "No such job"),
+        },
+        (_, ["printers"]) | (_, ["jobs"]) | (_, ["jobs", _]) => error_json(405, "Method not allowed"),
+        _ => error_json(404, "Not found"),
+    }
+}
+
+/// Runs the HTTP API, if it is enabled in the `[api]` section
+///
+/// Clients authenticate with `Authorization: Bearer <token>`, the tokens are managed like the mail tokens.
+/// Submitted files go to the admin for the approval like the files from Telegram and mail.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let users_table = Arc::new(Mutex::new(read_users().unwrap()));
+/// let api = thread::spawn(move || {
+///        run_server(Arc::clone(&config), Arc::clone(&users_table));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if !config.api.enabled {
+        return;
+    }
+
+    let max_body = if config.api.max_size == 0 { http::MAX_BODY } else { config.api.max_size.saturating_mul(1024 * 1024) };
+    let handler: Handler = Arc::new(move |request: &Request| handle(request, &a_config, &a_users_table));
+    match http::serve(&config.api.address, max_body, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the API: {}", err),
+    };
+}
@@ -0,0 +1,217 @@
+extern crate chrono;
+extern crate serde_json;
+extern crate toml;
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use self::chrono::{Local, NaiveDate, TimeZone, Utc};
+
+use hash;
+use io_tools;
+use spool::{self, Channel};
+
+const AUDIT_FILE: &str = "audit.toml";
+const CSV_HEADER: &str = "time,actor,channel,action,target\n";
+
+
+/// Kind of the audited action
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    AddUser,
+    DelUser,
+    AddToken,
+    DelToken,
+    AddApiToken,
+    DelApiToken,
+    GenToken,
+    /// One-time code of the upload page was generated or deleted
+    GenCode,
+    DelCode,
+    Print,
+    Cancel,
+    DeleteFile,
+    RejectFile,
+    PinFile,
+    UnpinFile,
+    EditDomain,
+    /// Telegram user was made the admin
+    SetAdmin,
+    /// Dashboard account was added, deleted or got the new role
+    EditAccount,
+    EditConfig,
+    /// The command was used by somebody without access to it
+    Unauthorized,
+    /// Mail with the unknown token
+    MailRejected,
+}
+
+/// Record of the audit log, appended to `audit.toml`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    /// Unix timestamp
+    pub time: i64,
+    /// Telegram user ID, the masked mail or API token, or the dashboard account
+    pub actor: String,
+    pub channel: Channel,
+    pub action: Action,
+    /// User, token, file ID, job or command, which the action was applied to
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Log {
+    #[serde(default)]
+    entries: Vec<Entry>,
+}
+
+impl Entry {
+    /// Returns the one-line description of the entry
+    pub fn describe(&self) -> String {
+        format!("{} {:?} {} {:?} {}", spool::format_time(self.time), self.channel, self.actor, self.action, self.target)
+    }
+}
+
+
+/// Appends the entry to the audit log, returns error on Err()
+///
+/// The log is only appended, so the existing entries are never rewritten.
+pub fn append(entry: &Entry) -> Result<(), String> {
+    let record = match toml::to_string(entry) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on serializing the audit entry: {}", err)),
+    };
+
+    let mut file = match OpenOptions::new().create(true).append(true).open(AUDIT_FILE) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on opening the audit log: {}", err)),
+    };
+    match file.write_all(format!("[[entries]]\n{}\n", record).as_bytes()) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error on writing the audit log: {}", err)),
+    }
+}
+
+/// Records the action to the audit log with the current time, errors are only logged
+///
+/// # Examples
+///
+/// ```rust
+/// record("123456", Channel::Telegram, Action::AddUser, "654321");
+/// ```
+pub fn record(actor: &str, channel: Channel, action: Action, target: &str) {
+    let entry = Entry {
+        time: Utc::now().timestamp(),
+        actor: actor.to_string(),
+        channel,
+        action,
+        target: target.to_string(),
+    };
+    match append(&entry) {
+        Ok(_) => (),
+        Err(err) => error!("{}", err),
+    };
+}
+
+/// Reads all the entries of the audit log, returns them on Ok()
+pub fn read_entries() -> Result<Vec<Entry>, String> {
+    if !io_tools::exists(AUDIT_FILE) {
+        return Ok(vec![]);
+    }
+
+    match toml::from_str::<Log>(&io_tools::read_str(AUDIT_FILE)) {
+        Ok(data) => Ok(data.entries),
+        Err(err) => Err(format!("Error on parsing the audit log: {}", err)),
+    }
+}
+
+/// Selects the entries by the query of the `/audit` command
+///
+/// The query is the number of the last entries (20 by default), the date `YYYY-MM-DD`
+/// to get the entries since that day, or the user ID, token or one-time code to get the entries with them.
+///
+/// # Examples
+///
+/// ```rust
+/// let entries = filter(read_entries().unwrap(), "2019-03-01");
+/// ```
+pub fn filter(entries: Vec<Entry>, query: &str) -> Vec<Entry> {
+    let query = query.trim();
+    if query.is_empty() {
+        return last(entries, 20);
+    }
+    if let Ok(count) = query.parse::<usize>() {
+        return last(entries, count);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(query, "%Y-%m-%d") {
+        let since = match date.and_hms_opt(0, 0, 0).and_then(|time| Local.from_local_datetime(&time).earliest()) {
+            Some(data) => data.timestamp(),
+            None => return vec![],
+        };
+        return entries.into_iter().filter(|entry| entry.time >= since).collect();
+    }
+
+    // mail tokens and one-time codes are stored masked, so they are matched by their fingerprints
+    let masked = hash::mask_token(query);
+    let code = hash::mask_code(query);
+    entries.into_iter()
+        .filter(|entry| entry.actor == query || entry.target == query || entry.actor == masked || entry.target == masked ||
+            entry.target == code)
+        .collect()
+}
+
+fn last(entries: Vec<Entry>, count: usize) -> Vec<Entry> {
+    let skip = entries.len().saturating_sub(count);
+    entries.into_iter().skip(skip).collect()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Returns the entries as CSV with the header, time is in RFC 3339
+pub fn to_csv(entries: &Vec<Entry>) -> String {
+    let mut csv = CSV_HEADER.to_string();
+    for entry in entries {
+        let fields = [
+            Local.timestamp_opt(entry.time, 0).single().map_or(format!("{}", entry.time), |time| time.to_rfc3339()),
+            entry.actor.clone(),
+            format!("{:?}", entry.channel),
+            format!("{:?}", entry.action),
+            entry.target.clone(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&format!("{}\n", fields.join(",")));
+    }
+    csv
+}
+
+/// Returns the entries as the JSON array
+pub fn to_json(entries: &Vec<Entry>) -> Result<String, String> {
+    match serde_json::to_string_pretty(entries) {
+        Ok(data) => Ok(data),
+        Err(err) => Err(format!("Error on serializing the audit log: {}", err)),
+    }
+}
+
+/// Prints the whole audit log to stdout in `csv` or `json` format
+///
+/// # Examples
+///
+/// ```rust
+/// export("csv").unwrap(); // teleprint --audit-export csv > audit.csv
+/// ```
+pub fn export(format: &str) -> Result<(), String> {
+    let entries = read_entries()?;
+    let output = match format {
+        "csv" => to_csv(&entries),
+        "json" => to_json(&entries)?,
+        _ => return Err(format!("Unknown export format: {}, use csv or json", format)),
+    };
+    println!("{}", output.trim_end());
+    Ok(())
+}
@@ -3,18 +3,32 @@ extern crate reqwest;
 extern crate telebot;
 extern crate tokio_core;
 
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use audit;
 use config;
+use convert;
 use database;
 use downloader;
-use hash::generate_token;
+use hash::{generate_code, generate_token, mask_code, mask_token};
+use health;
+use jobs;
+use metrics;
+use pipeline::{self, Attachment, Identity, IngestChannel, Notifier, Silent};
+use preview;
 use printer;
+use spool;
+use supervisor;
+use transform;
+use webhook;
 
 use self::futures::IntoFuture;
 use self::futures::stream::Stream;
 use self::telebot::functions::*;
+use self::telebot::objects;
 use self::telebot::RcBot;
 use self::tokio_core::reactor::Core;
 
@@ -22,21 +36,89 @@ use self::tokio_core::reactor::Core;
 
 #[derive(Debug, Deserialize)]
 struct FileJS {
-    file_id: String,
-    file_size: i64,
     file_path: String,
 }
 
 #[derive(Debug, Deserialize)]
 struct ResultFile {
-    ok: bool,
     result: FileJS,
 }
 
+#[derive(Serialize)]
+struct KeyboardButton {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct Keyboard {
+    keyboard: Vec<Vec<KeyboardButton>>,
+    one_time_keyboard: bool,
+    resize_keyboard: bool,
+}
+
+#[derive(Serialize)]
+struct KeyboardMessage {
+    chat_id: i64,
+    text: String,
+    reply_markup: Keyboard,
+}
+
+// Photos of the Telegram album, which are waiting for the rest of the album
+struct Album {
+    user_id: i64,
+    name: String,
+    layout: Option<convert::Layout>,
+    images: Vec<String>,
+    updated: Instant,
+}
+
+type Albums = Arc<Mutex<HashMap<String, Album>>>;
+
+// Telegram doesn't send messages longer than 4096 characters
+const MESSAGE_LIMIT: usize = 4000;
+
+fn audit_telegram(user_id: i64, action: audit::Action, target: &str) {
+    audit::record(&format!("{}", user_id), spool::Channel::Telegram, action, target);
+}
+
+/// Telegram as the source of the print requests, the users get the answers in the chat
+pub struct TelegramChannel {
+    /// Token of the bot
+    pub token: String,
+}
+
+// Answers the Telegram user in the chat
+struct Reply {
+    token: String,
+    user: i64,
+}
+
+impl Notifier for Reply {
+    fn notify(&self, text: &str) {
+        match send_message(&self.token, self.user, text) {
+            Ok(_) => (),
+            Err(err) => error!("Error on sending message: {:?}", err),
+        };
+    }
+}
+
+impl IngestChannel for TelegramChannel {
+    fn channel(&self) -> spool::Channel {
+        spool::Channel::Telegram
+    }
+
+    fn notifier(&self, identity: &Identity) -> Box<dyn Notifier> {
+        match *identity {
+            Identity::TelegramUser(user) => Box::new(Reply { token: self.token.clone(), user }),
+            _ => Box::new(Silent),
+        }
+    }
+}
+
 fn cmd_auth(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/auth").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user = match msg.from {
             Some(u) => u,
             None => return bot.message(admin, "Not a user. Error.".to_string()).send(),
@@ -47,14 +129,8 @@ fn cmd_auth(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
         }
 
         let user_name = format!("{} {} @{}, id: {}", user.first_name,
-                                match user.last_name {
-                                    Some(name) => name,
-                                    None => String::from(""),
-                                },
-                                match user.username {
-                                    Some(name) => name,
-                                    None => String::from(""),
-                                }, user.id
+                                user.last_name.unwrap_or_default(),
+                                user.username.unwrap_or_default(), user.id
         );
 
         bot.message(admin, format!("User {} wants to auth", user_name)).send()
@@ -67,25 +143,29 @@ fn cmd_auth(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 fn cmd_add_user(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/adduser").and_then(move |(bot, mut msg)| {
         let mut users_table = a_users_table.lock().unwrap();
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
 
         let sender = match msg.from {
             Some(data) => data.id,
             None => -1,
         };
 
-        if !sender == admin {
+        if sender != admin {
+            audit_telegram(sender, audit::Action::Unauthorized, "/adduser");
             return bot.message(admin, format!("User {} tried to change users", sender)).send();
         }
 
-        let _state = if let Some(text) = msg.text.take() {
+        if let Some(text) = msg.text.take() {
             let mut user_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<i64>().ok());
 
             if let Some(user) = user_id.next() {
                 //let mut user_table = &users_table;
                 users_table.add_user(user);
                 match database::write_database(&users_table) {
-                    Ok(_) => return bot.message(admin, "Ok".to_string()).send(),
+                    Ok(_) => {
+                        audit_telegram(admin, audit::Action::AddUser, &format!("{}", user));
+                        return bot.message(admin, "Ok".to_string()).send();
+                    }
                     Err(err) => return bot.message(admin, format!("Error on writing config: {}", err)).send(),
                 };
             }
@@ -97,28 +177,38 @@ fn cmd_add_user(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     bot.register(handle);
 }
 
-fn cmd_add_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
-    let handle = bot.new_cmd("/addtoken").and_then(move |(bot, mut msg)| {
+// Mail tokens are managed by `/addtoken`, `/deltoken` and `/tokens`, API tokens by the same commands with `api`
+fn cmd_add_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, command: &'static str, api: bool) {
+    let handle = bot.new_cmd(command).and_then(move |(bot, mut msg)| {
         let mut users_table = a_users_table.lock().unwrap();
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
 
         let sender = match msg.from {
             Some(data) => data.id,
             None => -1,
         };
 
-        if !sender == admin {
+        if sender != admin {
+            audit_telegram(sender, audit::Action::Unauthorized, command);
             return bot.message(admin, format!("User {} tried to change users", sender)).send();
         }
 
-        let _state = if let Some(text) = msg.text.take() {
+        if let Some(text) = msg.text.take() {
             let mut token_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
 
             if let Some(token) = token_id.next() {
                 //let mut user_table = &users_table;
-                users_table.add_token(token);
+                let target = mask_token(&token);
+                if api {
+                    users_table.add_api_token(token);
+                } else {
+                    users_table.add_token(token);
+                }
                 match database::write_database(&users_table) {
-                    Ok(_) => return bot.message(admin, "Ok".to_string()).send(),
+                    Ok(_) => {
+                        audit_telegram(admin, if api { audit::Action::AddApiToken } else { audit::Action::AddToken }, &target);
+                        return bot.message(admin, "Ok".to_string()).send();
+                    }
                     Err(err) => return bot.message(admin, format!("Error on writing config: {}", err)).send(),
                 };
             }
@@ -133,22 +223,24 @@ fn cmd_add_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 fn cmd_gen_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/gentoken").and_then(move |(bot, mut msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
 
         let sender = match msg.from {
             Some(data) => data.id,
             None => -1,
         };
 
-        if !sender == admin {
+        if sender != admin {
+            audit_telegram(sender, audit::Action::Unauthorized, "/gentoken");
             return bot.message(admin, format!("User {} tried to change users", sender)).send();
         }
 
-        let _state = if let Some(text) = msg.text.take() {
+        if let Some(text) = msg.text.take() {
             let mut token_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
 
             if let Some(token) = token_id.next() {
-                return bot.message(admin, format!("{}", generate_token(token))).send();
+                audit_telegram(admin, audit::Action::GenToken, &token);
+                return bot.message(admin, generate_token(token)).send();
             }
         };
 
@@ -158,29 +250,67 @@ fn cmd_gen_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     bot.register(handle);
 }
 
+fn cmd_gen_code(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let handle = bot.new_cmd("/gencode").and_then(move |(bot, msg)| {
+        let mut users_table = a_users_table.lock().unwrap();
+        let admin = users_table.get_admin();
+
+        let sender = match msg.from {
+            Some(data) => data.id,
+            None => -1,
+        };
+
+        if sender != admin {
+            audit_telegram(sender, audit::Action::Unauthorized, "/gencode");
+            return bot.message(admin, format!("User {} tried to change users", sender)).send();
+        }
 
-fn cmd_del_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
-    let handle = bot.new_cmd("/deltoken").and_then(move |(bot, mut msg)| {
+        let code = generate_code();
+        users_table.add_code(code.clone());
+        match database::write_database(&users_table) {
+            Ok(_) => {
+                audit_telegram(admin, audit::Action::GenCode, &mask_code(&code));
+                bot.message(admin, format!("One-time code of the upload page: {}", code)).send()
+            }
+            Err(err) => bot.message(admin, format!("Error on writing config: {}", err)).send(),
+        }
+    });
+
+    bot.register(handle);
+}
+
+
+fn cmd_del_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, command: &'static str, api: bool) {
+    let handle = bot.new_cmd(command).and_then(move |(bot, mut msg)| {
         let mut users_table = a_users_table.lock().unwrap();
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
 
         let sender = match msg.from {
             Some(data) => data.id,
             None => -1,
         };
 
-        if !sender == admin {
+        if sender != admin {
+            audit_telegram(sender, audit::Action::Unauthorized, command);
             return bot.message(admin, format!("User {} tried to change users", sender)).send();
         }
 
-        let _state = if let Some(text) = msg.text.take() {
+        if let Some(text) = msg.text.take() {
             let mut token_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
 
             if let Some(token) = token_id.next() {
                 //let mut user_table = &users_table;
-                users_table.del_token(token);
+                let target = mask_token(&token);
+                if api {
+                    users_table.del_api_token(token);
+                } else {
+                    users_table.del_token(token);
+                }
                 match database::write_database(&users_table) {
-                    Ok(_) => return bot.message(admin, "Ok".to_string()).send(),
+                    Ok(_) => {
+                        audit_telegram(admin, if api { audit::Action::DelApiToken } else { audit::Action::DelToken }, &target);
+                        return bot.message(admin, "Ok".to_string()).send();
+                    }
                     Err(err) => return bot.message(admin, format!("Error on writing config: {}", err)).send(),
                 };
             }
@@ -196,26 +326,30 @@ fn cmd_del_token(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 fn cmd_del_user(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/deluser").and_then(move |(bot, mut msg)| {
         let mut users_table = a_users_table.lock().unwrap();
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
 
         let sender = match msg.from {
             Some(data) => data.id,
             None => -1,
         };
 
-        if !sender == admin {
+        if sender != admin {
+            audit_telegram(sender, audit::Action::Unauthorized, "/deluser");
             return bot.message(admin, format!("User {} tried to change users", sender)).send();
         }
 
-        let _state = if let Some(text) = msg.text.take() {
+        if let Some(text) = msg.text.take() {
             let mut user_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<i64>().ok());
 
             if let Some(user) = user_id.next() {
                 users_table.del_user(user);
 
                 match database::write_database(&users_table) {
-                    Ok(_) => return bot.message(admin, "Ok".to_string()).send(),
-                    Err(err) => bot.message(admin, format!("Error on writing config: {}", err)).send(),
+                    Ok(_) => {
+                        audit_telegram(admin, audit::Action::DelUser, &format!("{}", user));
+                        return bot.message(admin, "Ok".to_string()).send();
+                    }
+                    Err(err) => return bot.message(admin, format!("Error on writing config: {}", err)).send(),
                 };
             }
         };
@@ -230,13 +364,14 @@ fn cmd_del_user(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 fn cmd_users(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/users").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/users");
             return bot.message(admin, format!("{} tried to see users", user_id)).send();
         }
 
@@ -248,21 +383,23 @@ fn cmd_users(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 }
 
 
-fn cmd_tokens(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
-    let handle = bot.new_cmd("/tokens").and_then(move |(bot, msg)| {
+fn cmd_tokens(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, command: &'static str, api: bool) {
+    let handle = bot.new_cmd(command).and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, command);
             return bot.message(admin, format!("{} tried to see users", user_id)).send();
         }
 
 
-        bot.message(admin, format!("{:?}", users_table.vectorize().mail_tokens)).send()
+        let users = users_table.vectorize();
+        bot.message(admin, format!("{:?}", if api { users.api_tokens } else { users.mail_tokens })).send()
     });
 
     bot.register(handle);
@@ -272,14 +409,14 @@ fn cmd_tokens(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 fn cmd_print(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
     let handle = bot.new_cmd("/print").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let config = { a_config.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/print");
             return bot.message(admin, format!("{} tried to print", user_id)).send();
         }
 
@@ -288,35 +425,28 @@ fn cmd_print(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_con
             None => return bot.message(admin, "No text error".to_string()).send(),
         };
 
-        let mut itr_filename = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
+        let args: Vec<&str> = text.split_whitespace().collect();
 
-        let filename = match itr_filename.next() {
-            Some(data) => data,
-            None => return bot.message(admin, "No filename was specified. Error".to_string()).send()
+        let file_id = match args.first() {
+            Some(data) => data.to_string(),
+            None => return bot.message(admin, "No file ID was specified. Error".to_string()).send()
         };
 
-        let file_parts: Vec<&str> = filename.split(".").collect();
-        let mut sender: Option<i64> = None;
-        if file_parts.len() > 1 {
-            match file_parts[1].trim().parse::<i64>() {
-                Ok(v) => sender = Some(v),
-                Err(_) => sender = None,
-            }
-        }
-
+        let options = match transform::Options::parse(&args[1..]) {
+            Ok(data) => data,
+            Err(err) => return bot.message(admin, format!("Error in the print options: {}", err)).send()
+        };
 
-        match printer::print_from_file(&filename, Arc::clone(&a_config)) {
-            Ok(_state) => {
-                match sender {
-                    Some(se) => {thread::spawn(move || {
-                        send_message(&config.token, se,
-                                     &"Your file has been started printing".to_string())
-                    });},
-                    None => {println!("No sender");},
+        match jobs::print(&file_id, &options, Arc::clone(&a_config)) {
+            Ok(printed) => {
+                audit_telegram(admin, audit::Action::Print, &printed.target());
+                let text = match printed.job {
+                    Some(job) => format!("The file has been started printing, job {}", job),
+                    None => "The file has been started printing...".to_string(),
                 };
-                return bot.message(admin, "The file has been started printing...".to_string()).send();
+                bot.message(admin, text).send()
             }
-            Err(err) => return bot.message(admin, format!("Error on printing the file: {}", err)).send()
+            Err(err) => bot.message(admin, format!("Error on printing the file: {}", err)).send()
         }
     });
 
@@ -324,22 +454,24 @@ fn cmd_print(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_con
 }
 
 
-fn cmd_files(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
+fn cmd_files(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
     let handle = bot.new_cmd("/files").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let spool_dir = { a_config.lock().unwrap().spool.dir.clone() };
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/files");
             return bot.message(admin, format!("{} tried to see files", user_id)).send();
         }
 
-        match printer::get_files() {
-            Ok(data) => return bot.message(admin, data).send(),
-            Err(err) => return bot.message(admin, format!("Error on getting files: {}", err)).send(),
+        match spool::describe_files(&spool_dir) {
+            Ok(data) => bot.message(admin, data).send(),
+            Err(err) => bot.message(admin, format!("Error on getting files: {}", err)).send(),
         }
     });
 
@@ -347,17 +479,19 @@ fn cmd_files(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 }
 
 
-fn cmd_get_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
+fn cmd_get_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
     let handle = bot.new_cmd("/getfile").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
+        let spool_dir = { a_config.lock().unwrap().spool.dir.clone() };
         let token = &bot.inner.key;
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/getfile");
             return bot.message(admin, format!("{} tried to get file", user_id)).send();
         }
 
@@ -368,33 +502,40 @@ fn cmd_get_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 
         let mut itr_file_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
 
-        let filename = match itr_file_id.next() {
+        let file_id = match itr_file_id.next() {
             Some(data) => data,
-            None => return bot.message(admin, "No filename was specified. Error".to_string()).send()
+            None => return bot.message(admin, "No file ID was specified. Error".to_string()).send()
+        };
+
+        let meta = match spool::read_meta(&spool_dir, &file_id) {
+            Ok(data) => data,
+            Err(err) => return bot.message(admin, format!("Error on getting file: {}", err)).send()
         };
 
-        match send_document(&token, admin, &filename) {
-            Ok(_) => print!(""),
-            Err(err) => eprintln!("{:?}", err),
+        match send_document(token, admin, &spool::file_path(&spool_dir, &meta)) {
+            Ok(_) => (),
+            Err(err) => error!("{:?}", err),
         };
 
-        bot.message(admin, format!("/|\\ Your file\n | ")).send()
+        bot.message(admin, format!("/|\\ Your file\n | \n{}", meta.describe())).send()
     });
 
     bot.register(handle);
 }
 
 
-fn cmd_delete_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
+fn cmd_delete_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
     let handle = bot.new_cmd("/delfile").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let spool_dir = { a_config.lock().unwrap().spool.dir.clone() };
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/delfile");
             return bot.message(admin, format!("{} tried to delete file", user_id)).send();
         }
 
@@ -403,16 +544,19 @@ fn cmd_delete_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>)
             None => return bot.message(admin, "No text error".to_string()).send(),
         };
 
-        let mut itr_filename = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
+        let mut itr_file_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
 
-        let filename = match itr_filename.next() {
+        let file_id = match itr_file_id.next() {
             Some(data) => data,
-            None => return bot.message(admin, "No filename was specified. Error".to_string()).send()
+            None => return bot.message(admin, "No file ID was specified. Error".to_string()).send()
         };
 
 
-        match printer::delete_file(&filename) {
-            Ok(_) => bot.message(admin, "Ok".to_string()).send(),
+        match spool::delete(&spool_dir, &file_id) {
+            Ok(_) => {
+                audit_telegram(admin, audit::Action::DeleteFile, &file_id);
+                bot.message(admin, "Ok".to_string()).send()
+            }
             Err(err) => bot.message(admin, format!("Error on deleting file: {}", err)).send()
         }
     });
@@ -421,16 +565,222 @@ fn cmd_delete_file(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>)
 }
 
 
+fn cmd_reject(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
+    let handle = bot.new_cmd("/reject").and_then(move |(bot, msg)| {
+        let users_table = { a_users_table.lock().unwrap().clone() };
+        let config = { a_config.lock().unwrap().clone() };
+        let admin = users_table.get_admin();
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/reject");
+            return bot.message(admin, format!("{} tried to reject file", user_id)).send();
+        }
+
+        let text = match msg.text {
+            Some(data) => data,
+            None => return bot.message(admin, "No text error".to_string()).send(),
+        };
+
+        let mut itr_file_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
+
+        let file_id = match itr_file_id.next() {
+            Some(data) => data,
+            None => return bot.message(admin, "No file ID was specified. Error".to_string()).send()
+        };
+
+        match jobs::reject(&file_id, &config) {
+            Ok(meta) => {
+                audit_telegram(admin, audit::Action::RejectFile, &meta.id);
+                bot.message(admin, "The file has been rejected".to_string()).send()
+            }
+            Err(err) => bot.message(admin, format!("Error on rejecting the file: {}", err)).send()
+        }
+    });
+
+    bot.register(handle);
+}
+
+
+fn cmd_pin(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>,
+           command: &'static str, pinned: bool) {
+    let handle = bot.new_cmd(command).and_then(move |(bot, msg)| {
+        let users_table = { a_users_table.lock().unwrap().clone() };
+        let spool_dir = { a_config.lock().unwrap().spool.dir.clone() };
+        let admin = users_table.get_admin();
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, command);
+            return bot.message(admin, format!("{} tried to pin file", user_id)).send();
+        }
+
+        let text = match msg.text {
+            Some(data) => data,
+            None => return bot.message(admin, "No text error".to_string()).send(),
+        };
+
+        let mut itr_file_id = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
+
+        let file_id = match itr_file_id.next() {
+            Some(data) => data,
+            None => return bot.message(admin, "No file ID was specified. Error".to_string()).send()
+        };
+
+        let mut meta = match spool::read_meta(&spool_dir, &file_id) {
+            Ok(data) => data,
+            Err(err) => return bot.message(admin, format!("Error on pinning the file: {}", err)).send()
+        };
+
+        meta.pinned = pinned;
+        match spool::write_meta(&spool_dir, &meta) {
+            Ok(_) => {
+                audit_telegram(admin, if pinned { audit::Action::PinFile } else { audit::Action::UnpinFile }, &meta.id);
+                bot.message(admin, "Ok".to_string()).send()
+            }
+            Err(err) => bot.message(admin, format!("Error on pinning the file: {}", err)).send()
+        }
+    });
+
+    bot.register(handle);
+}
+
+
+fn cmd_url(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
+    let handle = bot.new_cmd("/url").and_then(move |(bot, msg)| {
+        let token = { a_config.lock().unwrap().token.clone() };
+        let admin = { a_users_table.lock().unwrap().get_admin() };
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        let text = match msg.text {
+            Some(data) => data,
+            None => return bot.message(user_id, "No text error".to_string()).send(),
+        };
+
+        let mut itr_url = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
+
+        let url = match itr_url.next() {
+            Some(data) => data,
+            None => return bot.message(user_id, "No link was specified. Error".to_string()).send()
+        };
+
+        // the command answers the user itself
+        let mut request = TelegramChannel { token }.request(Identity::TelegramUser(user_id), Attachment::Link(url),
+                                                                   transform::Options::default());
+        request.reply = Box::new(Silent);
+        let result = pipeline::submit(request, &a_config, &a_users_table);
+        bot.message(user_id, pipeline::receipt("file", &result)).send()
+    });
+
+    bot.register(handle);
+}
+
+
+// Returns the text after `/printtext` (or `/printtext@<bot>`) with its line breaks, which are lost
+// in the commands of telebot, so the command is handled with the other messages
+fn print_text_body(text: &str) -> Option<String> {
+    let text = text.trim_start();
+    let command = text.split_whitespace().next()?;
+    if command.split('@').next() != Some("/printtext") {
+        return None;
+    }
+    Some(text[command.len()..].trim_start_matches([' ', '\t']).to_string())
+}
+
+
+fn cmd_domains(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
+    let handle = bot.new_cmd("/domains").and_then(move |(bot, msg)| {
+        let users_table = { a_users_table.lock().unwrap().clone() };
+        let config = { a_config.lock().unwrap().clone() };
+        let admin = users_table.get_admin();
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/domains");
+            return bot.message(admin, format!("{} tried to see domains", user_id)).send();
+        }
+
+        bot.message(admin, format!("Allowed: {:?}\nDenied: {:?}", config.download.allowed_domains,
+                                   config.download.denied_domains)).send()
+    });
+
+    bot.register(handle);
+}
+
+
+fn cmd_edit_domain(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>,
+                   command: &'static str) {
+    let handle = bot.new_cmd(command).and_then(move |(bot, msg)| {
+        let users_table = { a_users_table.lock().unwrap().clone() };
+        let admin = users_table.get_admin();
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, command);
+            return bot.message(admin, format!("{} tried to change domains", user_id)).send();
+        }
+
+        let text = match msg.text {
+            Some(data) => data,
+            None => return bot.message(admin, "No text error".to_string()).send(),
+        };
+
+        let mut itr_domain = text.split_whitespace().take(1).filter_map(|x| x.parse::<String>().ok());
+
+        let domain = match itr_domain.next() {
+            Some(data) => data.to_lowercase(),
+            None => return bot.message(admin, "No domain was specified. Error".to_string()).send()
+        };
+
+        let target = format!("{} {}", command, domain);
+        let mut config = a_config.lock().unwrap();
+        config.download.allowed_domains.retain(|x| *x != domain);
+        config.download.denied_domains.retain(|x| *x != domain);
+        match command {
+            "/allowdomain" => config.download.allowed_domains.push(domain),
+            "/denydomain" => config.download.denied_domains.push(domain),
+            _ => (),
+        };
+
+        match config::write_config(&config) {
+            Ok(_) => {
+                audit_telegram(admin, audit::Action::EditDomain, &target);
+                bot.message(admin, "Ok".to_string()).send()
+            }
+            Err(err) => bot.message(admin, format!("Error on writing config: {}", err)).send(),
+        }
+    });
+
+    bot.register(handle);
+}
+
+
 fn cmd_lpstat(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/lpstat").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/lpstat");
             return bot.message(admin, format!("{} tried to use lpstat", user_id)).send();
         }
 
@@ -444,13 +794,14 @@ fn cmd_lpstat(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 fn cmd_cancel(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/cancel").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
         };
 
         if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/cancel");
             return bot.message(admin, format!("{} tried to use cancel", user_id)).send();
         }
 
@@ -468,7 +819,10 @@ fn cmd_cancel(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 
 
         match printer::cancel(&job_name) {
-            Ok(_) => bot.message(admin, "The job was canceled successfully.".to_string()).send(),
+            Ok(_) => {
+                audit_telegram(admin, audit::Action::Cancel, &job_name);
+                bot.message(admin, "The job was canceled successfully.".to_string()).send()
+            }
             Err(err) => bot.message(admin, format!("Error on canceling the job:\n{}", err)).send(),
         }
     });
@@ -477,10 +831,74 @@ fn cmd_cancel(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
 }
 
 
+fn cmd_audit(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let handle = bot.new_cmd("/audit").and_then(move |(bot, msg)| {
+        let users_table = { a_users_table.lock().unwrap().clone() };
+        let admin = users_table.get_admin();
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/audit");
+            return bot.message(admin, format!("{} tried to see audit log", user_id)).send();
+        }
+
+        let entries = match audit::read_entries() {
+            Ok(data) => audit::filter(data, &msg.text.unwrap_or("".to_string())),
+            Err(err) => return bot.message(admin, format!("Error on reading audit log: {}", err)).send(),
+        };
+        if entries.is_empty() {
+            return bot.message(admin, "No entries".to_string()).send();
+        }
+
+        // the latest entries are kept, if all of them don't fit into one message
+        let mut lines: Vec<String> = vec![];
+        let mut length = 0;
+        for entry in entries.iter().rev() {
+            let line = entry.describe();
+            length += line.len() + 1;
+            if length > MESSAGE_LIMIT {
+                break;
+            }
+            lines.push(line);
+        }
+        lines.reverse();
+
+        bot.message(admin, lines.join("\n")).send()
+    });
+
+    bot.register(handle);
+}
+
+
+fn cmd_diag(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>, a_config: Arc<Mutex<config::Config>>) {
+    let handle = bot.new_cmd("/diag").and_then(move |(bot, msg)| {
+        let users_table = { a_users_table.lock().unwrap().clone() };
+        let config = { a_config.lock().unwrap().clone() };
+        let admin = users_table.get_admin();
+        let user_id = match msg.from {
+            Some(data) => data.id,
+            None => return bot.message(admin, "Some error with user_id".to_string()).send(),
+        };
+
+        if user_id != admin {
+            audit_telegram(user_id, audit::Action::Unauthorized, "/diag");
+            return bot.message(admin, format!("{} tried to use diag", user_id)).send();
+        }
+
+        bot.message(admin, health::report(&health::checks(&config))).send()
+    });
+
+    bot.register(handle);
+}
+
+
 fn cmd_help(bot: &RcBot, a_users_table: Arc<Mutex<database::UsersTable>>) {
     let handle = bot.new_cmd("/help").and_then(move |(bot, msg)| {
         let users_table = { a_users_table.lock().unwrap().clone() };
-        let admin = users_table.get_admin() as i64;
+        let admin = users_table.get_admin();
         let user_id = match msg.from {
             Some(data) => data.id,
             None => return bot.message(admin, "Some error with user_id".to_string()).send(),
@@ -491,22 +909,37 @@ Use the commands below to manage users, tokens, files and printer:
 * `/adduser <user_id>` -  add user to the access list
 * `/addtoken <token>` - add token to the access list
 * `/gentoken <name>` - generate token
+* `/gencode` - generate one-time code of the upload page
 * `/deluser <user_id>` - delete user from the access list
 * `/deltoken <token>` - delete token from the access list
-* `/print <filename>` - print the file
+* `/addapitoken <token>` - add token of the HTTP API
+* `/delapitoken <token>` - delete token of the HTTP API
+* `/print <file ID> [pages=3-7,1] [nup=2|4|6|9] [rotate=90] [fit] [booklet]` - print the file, transformed by the options
 * `/users` - get users list
 * `/tokens` - get tokens list
+* `/apitokens` - get tokens list of the HTTP API
 * `/files` - get files list
-* `/getfile <filename>` - get file
-* `/delfile <filename>` - delete file
+* `/getfile <file ID>` - get file
+* `/delfile <file ID>` - delete file
+* `/reject <file ID>` - reject the file
+* `/pin <file ID>` - keep the file from the automatic cleanup
+* `/unpin <file ID>` - allow the automatic cleanup of the file
+* `/domains` - get allowed and denied domains for the links
+* `/allowdomain <domain>` - allow downloading from the domain
+* `/denydomain <domain>` - deny downloading from the domain
+* `/deldomain <domain>` - remove the domain from both lists
 * `/lpstat` - see lpstat output
 * `/cancel <job ID or name>` - cancel the job
+* `/diag` - check the Telegram poller, IMAP, Matrix, printer and spool disk space
+* `/audit [count|user ID|token|YYYY-MM-DD]` - see the last actions, actions of the user or since the date
 * `/help` - print the list of commands above";
 
         if user_id == admin {
             bot.message(admin, helper.to_string()).send()
         } else if users_table.check_user(user_id) {
-            bot.message(user_id, "Just send PDF file".to_string()).send()
+            bot.message(user_id, "Just send PDF file, photos or the link to the file (`/url <link>`). \
+Add `fit`, `fill` or `actual` to the caption of the photo to choose how it is placed on the page. \
+Use `/printtext` followed by the text to print the text itself.".to_string()).send()
         } else {
             bot.message(user_id, "You must authenticate by `/auth` command.".to_string()).send()
         }
@@ -516,6 +949,106 @@ Use the commands below to manage users, tokens, files and printer:
 }
 
 
+/// Returns the first line of the approval request, which tells who sent the file
+pub fn announce_header(meta: &spool::FileMeta) -> String {
+    match meta.channel {
+        spool::Channel::Telegram => format!("User {} wants to print:", meta.submitter),
+        spool::Channel::Mail => format!("Mail user {} wants to print:", meta.submitter_label()),
+        spool::Channel::Web => format!("Web user {} wants to print:", meta.submitter_label()),
+        spool::Channel::Api => format!("API client {} wants to print:", meta.submitter_label()),
+        spool::Channel::Ipp => format!("IPP client {} wants to print:", meta.submitter_label()),
+        spool::Channel::Matrix => format!("Matrix user {} wants to print:", meta.submitter),
+    }
+}
+
+
+/// Sends the file to the admin for the approval: the preview, the description with the approval buttons
+/// and the warning about the duplicates
+pub fn announce_file(admin: i64, meta: &spool::FileMeta, config: &config::Config) {
+    metrics::inc(metrics::JOBS_SUBMITTED, &[("channel", &metrics::channel_label(meta.channel))]);
+
+    let header = announce_header(meta);
+    match send_message(&config.token, admin, &header) {
+        Ok(_) => (),
+        Err(err) => error!("Error on sending message: {:?}", err),
+    };
+
+    preview::send_preview(config, admin, meta);
+
+    if let Some(warning) = spool::duplicate_warning(&config.spool.dir, meta, config.spool.duplicate_window) {
+        match send_message(&config.token, admin, &warning) {
+            Ok(_) => (),
+            Err(err) => error!("Error on sending message: {:?}", err),
+        };
+    }
+
+    match send_keyboard(&config.token, admin, &meta.describe(), &approval_buttons(meta)) {
+        Ok(_) => (),
+        Err(err) => error!("Error on sending message: {:?}", err),
+    };
+
+    match spool::mark_announced(&config.spool.dir, &meta.id) {
+        Ok(_) => (),
+        Err(err) => error!("{}", err),
+    };
+}
+
+
+/// Announces the files, which were stored, but not sent to the admin before the restart,
+/// and reminds the admin about the files, which are still waiting for the approval
+pub fn restore_pending(a_config: Arc<Mutex<config::Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    let admin = { a_users_table.lock().unwrap().get_admin() };
+    let pending = match spool::pending(&config.spool.dir) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on restoring the pending files: {}", err);
+            return;
+        }
+    };
+
+    let (announced, unannounced): (Vec<spool::FileMeta>, Vec<spool::FileMeta>) =
+        pending.into_iter().partition(|meta| meta.announced);
+
+    for meta in unannounced {
+        info!("Announcing {} after the restart", meta.id);
+        announce_file(admin, &meta, &config);
+    }
+
+    if announced.is_empty() {
+        return;
+    }
+    let mut lines = vec![format!("Teleprint restarted, {} files are waiting for the approval:", announced.len())];
+    for meta in announced {
+        lines.push(format!("{} - {} from {:?} {}, received {}", meta.id, meta.original_name, meta.channel,
+                           meta.submitter_label(), spool::format_time(meta.received)));
+    }
+    lines.push("Use `/print <file ID>` or `/reject <file ID>`".to_string());
+    match send_message(&config.token, admin, &lines.join("\n")) {
+        Ok(_) => (),
+        Err(err) => error!("Error on sending message: {:?}", err),
+    };
+}
+
+
+/// Returns the buttons for the approval of the file: printing as is or transformed, and rejecting
+///
+/// If the submitter asked for the options, printing with them is offered first.
+pub fn approval_buttons(meta: &spool::FileMeta) -> Vec<Vec<String>> {
+    let file_id = &meta.id;
+    let mut buttons = vec![
+        vec![format!("/print {}", file_id)],
+        vec![format!("/print {} nup=2", file_id), format!("/print {} nup=4", file_id)],
+        vec![format!("/print {} fit", file_id), format!("/print {} booklet", file_id)],
+        vec![format!("/reject {}", file_id)],
+    ];
+    if let Some(ref options) = meta.options {
+        buttons.insert(0, vec![format!("/print {} {}", file_id, options)]);
+    }
+    buttons
+}
+
+
 /// Sends message
 ///
 /// Needs Telegram Bot API token, chat_id and text
@@ -525,21 +1058,51 @@ Use the commands below to manage users, tokens, files and printer:
 /// ```rust
 /// send_message(&bot_token, admin, &format!("Mail user {} wants to print:", user_token)).unwrap();
 /// ```
-pub fn send_message(token: &String, chat_id: i64, text: &String) -> Result<(), String> {
+pub fn send_message(token: &str, chat_id: i64, text: &str) -> Result<(), String> {
     let mut url = match reqwest::Url::parse(&format!("https://api.telegram.org/bot{}/sendMessage", token)) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return Err(format!("{:?}", err));
         }
     };
-    url.query_pairs_mut().append_pair("chat_id", format!("{}", chat_id).as_str()).append_pair("text", text.as_str());
+    url.query_pairs_mut().append_pair("chat_id", format!("{}", chat_id).as_str()).append_pair("text", text);
     let response = reqwest::get(url.as_str());
-    println!("{:?}", response);
-    match response {
-        Ok(_) => Ok(()),
-        Err(err) => Err(format!("{:?}", err)),
-    }
+    api_result("sendMessage", response)
+}
+
+/// Sends message with the keyboard, every button sends its text back to the bot
+///
+/// The keyboard is hidden after the first press, so the buttons are used as the one-time menu.
+///
+/// # Example
+///
+/// ```rust
+/// send_keyboard(&bot_token, admin, &meta.describe(), &approval_buttons(&meta)).unwrap();
+/// ```
+pub fn send_keyboard(token: &str, chat_id: i64, text: &str, buttons: &[Vec<String>]) -> Result<(), String> {
+    let url = match reqwest::Url::parse(&format!("https://api.telegram.org/bot{}/sendMessage", token)) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("{:?}", err);
+            return Err(format!("{:?}", err));
+        }
+    };
+
+    let message = KeyboardMessage {
+        chat_id,
+        text: text.to_string(),
+        reply_markup: Keyboard {
+            keyboard: buttons.iter()
+                .map(|row| row.iter().map(|text| KeyboardButton { text: text.clone() }).collect())
+                .collect(),
+            one_time_keyboard: true,
+            resize_keyboard: true,
+        },
+    };
+
+    let response = reqwest::Client::new().post(url).json(&message).send();
+    api_result("sendMessage", response)
 }
 
 /// Sends message
@@ -555,7 +1118,7 @@ pub fn send_document(token: &String, chat_id: i64, filename: &String) -> Result<
     let mut url = match reqwest::Url::parse(&format!("https://api.telegram.org/bot{}/sendDocument", token)) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -564,7 +1127,7 @@ pub fn send_document(token: &String, chat_id: i64, filename: &String) -> Result<
     let form = match reqwest::multipart::Form::new().file("document", filename) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -574,11 +1137,61 @@ pub fn send_document(token: &String, chat_id: i64, filename: &String) -> Result<
     let response = client.post(url).multipart(form).send();
 
     //let response = reqwest::get(url.as_str());
-    println!("{:?}", response);
-    match response {
-        Ok(_) => Ok(()),
-        Err(err) => Err(format!("{:?}", err)),
-    }
+    api_result("sendDocument", response)
+}
+
+
+/// Sends photo with the caption
+///
+/// Needs Telegram Bot API token, chat_id, path to the image and the caption (may be empty)
+///
+/// # Example
+///
+/// ```rust
+/// send_photo(&bot_token, admin, &"spool/tmp/preview-4f2a9c01d3e5b768-1.png".to_string(), "report.pdf: 3 pages, A4").unwrap();
+/// ```
+pub fn send_photo(token: &String, chat_id: i64, filename: &String, caption: &str) -> Result<(), String> {
+    let mut url = match reqwest::Url::parse(&format!("https://api.telegram.org/bot{}/sendPhoto", token)) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("{:?}", err);
+            return Err(format!("{:?}", err));
+        }
+    };
+    let client = reqwest::Client::new();
+
+    let form = match reqwest::multipart::Form::new().file("photo", filename) {
+        Ok(data) => data.text("caption", caption.to_string()),
+        Err(err) => {
+            error!("{:?}", err);
+            return Err(format!("{:?}", err));
+        }
+    };
+
+    url.query_pairs_mut().append_pair("chat_id", format!("{}", chat_id).as_str());
+
+    let response = client.post(url).multipart(form).send();
+    api_result("sendPhoto", response)
+}
+
+
+// Counts the failed requests, the responses with the error status are failed too
+fn api_result(method: &str, response: reqwest::Result<reqwest::Response>) -> Result<(), String> {
+    debug!("{:?}", response);
+    let result = match response {
+        Ok(ref resp) if resp.status().is_success() => Ok(()),
+        Ok(resp) => Err(format!("Telegram API responded with {}", resp.status())),
+        // the error of reqwest contains the URL with the bot token
+        Err(err) => Err(err.get_ref().map_or("request failed".to_string(), |err| format!("{}", err))),
+    };
+    match result {
+        Ok(_) => health::record_ok(health::TELEGRAM),
+        Err(ref err) => {
+            metrics::inc(metrics::TELEGRAM_ERRORS, &[("method", method)]);
+            health::record_error(health::TELEGRAM, &format!("{}: {}", method, err));
+        }
+    };
+    result
 }
 
 
@@ -586,26 +1199,126 @@ fn get_link(token: &String, file_id: String) -> Result<String, String> {
     let mut url = match reqwest::Url::parse(&format!("https://api.telegram.org/bot{}/getFile", token)) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return Err(format!("{:?}", err));
         }
     };
     url.query_pairs_mut().append_pair("file_id", file_id.as_str());
     let response = reqwest::get(url.as_str());
-    println!("{:?}", response);
+    debug!("{:?}", response);
     let mut result = match response {
         Ok(res) => res,
-        Err(err) => return Err(format!("{:?}", err)),
+        Err(err) => {
+            metrics::inc(metrics::TELEGRAM_ERRORS, &[("method", "getFile")]);
+            return Err(format!("{:?}", err));
+        }
     };
     let js_res = match result.json::<ResultFile>() {
         Ok(data) => data,
-        Err(err) => return Err(format!("{:?}", err)),
+        Err(err) => {
+            metrics::inc(metrics::TELEGRAM_ERRORS, &[("method", "getFile")]);
+            return Err(format!("{:?}", err));
+        }
     };
 
     Ok(js_res.result.file_path)
 }
 
 
+fn download_file(token: &String, file_id: String, config: &config::Config) -> Result<(downloader::Download, String), String> {
+    // errors of getFile contain the bot token, so they are not shown to the user
+    let link = match get_link(token, file_id) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on getting the file link: {}", err);
+            return Err("Telegram didn't give the link to the file".to_string());
+        }
+    };
+    spool::init(&config.spool.dir)?;
+
+    let download = downloader::download_from_url(&format!("https://api.telegram.org/file/bot{}/{}", token, link),
+                                                 &config.download, &spool::tmp_dir(&config.spool.dir))?;
+    Ok((download, link))
+}
+
+
+fn album_attachment(album: Album) -> Attachment {
+    Attachment::Images { name: album.name, paths: album.images, layout: album.layout }
+}
+
+
+fn wait_album(group: String, albums: Albums, a_config: Arc<Mutex<config::Config>>,
+              a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    let delay = Duration::from_secs(config.images.album_delay);
+
+    let album = loop {
+        thread::sleep(delay);
+        let mut albums = albums.lock().unwrap();
+        let complete = match albums.get(&group) {
+            Some(album) => album.updated.elapsed() >= delay,
+            None => return,
+        };
+        if complete {
+            break albums.remove(&group);
+        }
+    };
+
+    let album = match album {
+        Some(data) => data,
+        None => return,
+    };
+    let user_id = album.user_id;
+    let request = TelegramChannel { token: config.token.clone() }
+        .request(Identity::TelegramUser(user_id), album_attachment(album), transform::Options::default());
+    let _ = pipeline::submit(request, &a_config, &a_users_table);
+}
+
+
+// Downloads the image, returns it, if it was a single image,
+// or None, if it is the part of the album, which is sent after all its images arrive
+#[allow(clippy::too_many_arguments)]
+fn receive_image(token: &String, a_config: &Arc<Mutex<config::Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>,
+                 albums: &Albums, user_id: i64, file_id: String, name: String, layout: Option<convert::Layout>,
+                 group: Option<String>) -> Result<Option<Attachment>, String> {
+    let config = { a_config.lock().unwrap().clone() };
+    let (download, _) = download_file(token, file_id, &config)?;
+
+    let album = Album {
+        user_id,
+        name,
+        layout,
+        images: vec![download.path],
+        updated: Instant::now(),
+    };
+
+    let group = match group {
+        Some(data) => data,
+        None => return Ok(Some(album_attachment(album))),
+    };
+
+    let mut albums_map = albums.lock().unwrap();
+    if let Some(existing) = albums_map.get_mut(&group) {
+        existing.images.extend(album.images);
+        existing.updated = album.updated;
+        if layout.is_some() {
+            existing.layout = layout;
+        }
+        return Ok(None);
+    }
+    albums_map.insert(group.clone(), album);
+
+    // the album is sent before the shutdown
+    let task = supervisor::task();
+    let (a_albums, a_config, a_users_table) = (Arc::clone(albums), Arc::clone(a_config), Arc::clone(a_users_table));
+    thread::spawn(move || {
+        wait_album(group, a_albums, a_config, a_users_table);
+        drop(task);
+    });
+    Ok(None)
+}
+
+
 /// Runs Telegram bot
 ///
 /// You should provide Config and UsersTable as shared state `Arc<Mutex>`
@@ -620,77 +1333,196 @@ fn get_link(token: &String, file_id: String) -> Result<String, String> {
 ///  });
 /// ```
 pub fn run_bot(a_config: Arc<Mutex<config::Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let _guard = health::running(health::TELEGRAM);
     let mut lp = Core::new().unwrap();
     let config = { a_config.lock().unwrap().clone() };
-    let bot: RcBot = RcBot::new(lp.handle(), &config.token).update_interval(1000);
+    let bot: RcBot = RcBot::new(lp.handle(), &config.token)
+        .update_interval(config.telegram.update_interval);
 
     cmd_auth(&bot, Arc::clone(&a_users_table)); //          /auth
     cmd_add_user(&bot, Arc::clone(&a_users_table)); //      /adduser
-    cmd_add_token(&bot, Arc::clone(&a_users_table)); //     /addtoken
+    cmd_add_token(&bot, Arc::clone(&a_users_table),
+                  "/addtoken", false);               //         /addtoken
+    cmd_add_token(&bot, Arc::clone(&a_users_table),
+                  "/addapitoken", true);             //         /addapitoken
     cmd_gen_token(&bot, Arc::clone(&a_users_table)); //     /gentoken
+    cmd_gen_code(&bot, Arc::clone(&a_users_table)); //      /gencode
     cmd_del_user(&bot, Arc::clone(&a_users_table)); //      /deluser
-    cmd_del_token(&bot, Arc::clone(&a_users_table)); //     /deltoken
+    cmd_del_token(&bot, Arc::clone(&a_users_table),
+                  "/deltoken", false);               //         /deltoken
+    cmd_del_token(&bot, Arc::clone(&a_users_table),
+                  "/delapitoken", true);             //         /delapitoken
     cmd_print(&bot, Arc::clone(&a_users_table),
               Arc::clone(&a_config));                //         /print
     cmd_users(&bot, Arc::clone(&a_users_table)); //         /users
-    cmd_tokens(&bot, Arc::clone(&a_users_table)); //        /tokens
-    cmd_files(&bot, Arc::clone(&a_users_table)); //         /files
-    cmd_get_file(&bot, Arc::clone(&a_users_table)); //      /getfile
-    cmd_delete_file(&bot, Arc::clone(&a_users_table)); //   /delfile
+    cmd_tokens(&bot, Arc::clone(&a_users_table),
+               "/tokens", false);                    //         /tokens
+    cmd_tokens(&bot, Arc::clone(&a_users_table),
+               "/apitokens", true);                  //         /apitokens
+    cmd_files(&bot, Arc::clone(&a_users_table),
+              Arc::clone(&a_config));                //         /files
+    cmd_get_file(&bot, Arc::clone(&a_users_table),
+                 Arc::clone(&a_config));             //         /getfile
+    cmd_delete_file(&bot, Arc::clone(&a_users_table),
+                    Arc::clone(&a_config));          //         /delfile
+    cmd_reject(&bot, Arc::clone(&a_users_table),
+               Arc::clone(&a_config));               //         /reject
+    cmd_pin(&bot, Arc::clone(&a_users_table),
+            Arc::clone(&a_config), "/pin", true);    //         /pin
+    cmd_pin(&bot, Arc::clone(&a_users_table),
+            Arc::clone(&a_config), "/unpin", false); //         /unpin
+    cmd_url(&bot, Arc::clone(&a_users_table),
+            Arc::clone(&a_config));                  //         /url
+    cmd_domains(&bot, Arc::clone(&a_users_table),
+                Arc::clone(&a_config));              //         /domains
+    cmd_edit_domain(&bot, Arc::clone(&a_users_table),
+                    Arc::clone(&a_config), "/allowdomain"); //  /allowdomain
+    cmd_edit_domain(&bot, Arc::clone(&a_users_table),
+                    Arc::clone(&a_config), "/denydomain"); //   /denydomain
+    cmd_edit_domain(&bot, Arc::clone(&a_users_table),
+                    Arc::clone(&a_config), "/deldomain"); //    /deldomain
     cmd_lpstat(&bot, Arc::clone(&a_users_table)); //        /lpstat
     cmd_cancel(&bot, Arc::clone(&a_users_table)); //        /cancel
+    cmd_audit(&bot, Arc::clone(&a_users_table)); //         /audit
+    cmd_diag(&bot, Arc::clone(&a_users_table),
+             Arc::clone(&a_config));                 //         /diag
     cmd_help(&bot, Arc::clone(&a_users_table)); //          /help
     // cmd_from_file(&bot);
 
-    let handle = (&bot).get_stream().and_then(|(bot, upd)| {
+    let albums: Albums = Arc::new(Mutex::new(HashMap::new()));
+    let handler = |(bot, upd): (RcBot, objects::Update)| {
         let user_table = { a_users_table.lock().unwrap().clone() };
-        let admin = user_table.get_admin() as i64;
+        let config = { a_config.lock().unwrap().clone() };
+        let admin = user_table.get_admin();
         let tg_token = &bot.inner.key;
+        health::record_ok(health::TELEGRAM);
 
         let msg = match upd.message {
             Some(data) => data,
             None => return None,
         };
-        let user_id = match msg.from {
-            Some(data) => data.id,
+        let user = match msg.from {
+            Some(data) => data,
             None => return Some(bot.message(admin, "Some error with user_id".to_string()).send()),
         };
+        let user_id = user.id;
+
+        if supervisor::shutting_down() {
+            return Some(bot.message(user_id, "The printer bot is restarting, please send it again in a minute."
+                .to_string()).send());
+        }
 
         if !user_table.check_user(user_id) {
+            audit_telegram(user_id, audit::Action::Unauthorized, "message");
             return Some(bot.message(user_id, "You don't have access to printer.".to_string()).send());
         }
 
-        let file_id = match msg.document {
-            Some(data) => data.file_id,
-            None => return Some(bot.message(user_id, "Error: no file or unknown command".to_string()).send()),
+        let text = msg.text.unwrap_or("".to_string());
+
+        if let Some(body) = print_text_body(&text) {
+            let author = match user.username {
+                Some(name) => format!("{} @{}", user.first_name, name),
+                None => user.first_name.clone(),
+            };
+
+            // the command answers the user itself
+            let mut request = TelegramChannel { token: tg_token.clone() }.request(Identity::TelegramUser(user_id),
+                                                                                 Attachment::Text { text: body, author },
+                                                                                 transform::Options::default());
+            request.reply = Box::new(Silent);
+            let result = pipeline::submit(request, &a_config, &a_users_table);
+            return Some(bot.message(user_id, pipeline::receipt("text", &result)).send());
+        }
+        let layout = msg.caption.unwrap_or("".to_string()).split_whitespace()
+            .filter_map(convert::Layout::parse).next();
+
+        let image = match (msg.photo, &msg.document) {
+            (Some(photos), _) => photos.into_iter()
+                .max_by_key(|photo| photo.width * photo.height)
+                .map(|photo| (photo.file_id, "photo.jpg".to_string())),
+            (None, Some(document)) if document.mime_type.as_ref().is_some_and(|mime| mime.starts_with("image/")) =>
+                Some((document.file_id.clone(), document.file_name.clone().unwrap_or("image".to_string()))),
+            _ => None,
         };
 
-        let link = match get_link(&tg_token, file_id.clone()) {
-            Ok(data) => data,
-            Err(err) => {
-                eprintln!("Error on getting file path: {}", err);
+        let channel = TelegramChannel { token: tg_token.clone() };
+        let identity = Identity::TelegramUser(user_id);
+
+        if let Some((file_id, name)) = image {
+            return match receive_image(tg_token, &a_config, &a_users_table, &albums, user_id, file_id, name,
+                                       layout, msg.media_group_id) {
+                Ok(Some(attachment)) => {
+                    let _ = pipeline::submit(channel.request(identity, attachment, transform::Options::default()),
+                                             &a_config, &a_users_table);
+                    None
+                }
+                Ok(None) => None,
+                Err(err) => Some(bot.message(user_id, format!("Error in receiving image: {}", err)).send()),
+            };
+        }
+
+        let document = match msg.document {
+            Some(data) => data,
+            None if downloader::is_link(text.trim()) => {
+                let attachment = Attachment::Link(text.trim().to_string());
+                let _ = pipeline::submit(channel.request(identity, attachment, transform::Options::default()),
+                                         &a_config, &a_users_table);
                 return None;
             }
+            None => return Some(bot.message(user_id, "Error: no file or unknown command".to_string()).send()),
         };
 
-
-        let filename = match downloader::download_from_url(&format!("https://api.telegram.org/file/bot{}/{}", tg_token, link), Some(user_id)) {
+        let (download, link) = match download_file(tg_token, document.file_id, &config) {
             Ok(data) => data,
-            Err(err) => return Some(bot.message(admin, format!("Error in downloading file: {:?}", err)).send()),
+            Err(err) => {
+                match send_message(&tg_token.clone(), user_id, &format!("Error in downloading file: {}", err)) {
+                    Ok(_) => (),
+                    Err(err) => error!("Error on sending message: {:?}", err),
+                };
+                return Some(bot.message(admin, format!("Error in downloading file from {}: {}", user_id, err)).send());
+            }
         };
 
-        match send_message(&tg_token.clone(), admin, &format!("User {} wants to print:\n{}", user_id, filename)) {
-            Ok(_) => println!("Ok"),
-            Err(err) => eprintln!("Error on sending message: {:?}", err),
+        let attachment = Attachment::Downloaded {
+            name: document.file_name.unwrap_or(link),
+            mime: document.mime_type.unwrap_or("application/pdf".to_string()),
+            download,
         };
-
-        Some(bot.message(admin, format!("{}", filename)).send())
+        let _ = pipeline::submit(channel.request(identity, attachment, transform::Options::default()),
+                                 &a_config, &a_users_table);
+        None
         // bot.message(admin, format!("{}", filename)).send()
-    });
+    };
 
-    match lp.run(handle.for_each(|_| Ok(())).into_future()) {
-        Ok(_) => println!("Ok"),
-        Err(err) => eprintln!("{:?}", err),
+    // the same handlers get the updates from getUpdates or from the webhook
+    let result = match config.telegram.transport {
+        config::Transport::Polling => {
+            // getUpdates is refused while the webhook is set
+            match webhook::delete_webhook(&config.token) {
+                Ok(_) => (),
+                Err(err) => error!("Error on deleting the webhook: {}", err),
+            };
+            lp.run(bot.get_stream().and_then(handler).for_each(|_| Ok(())).into_future())
+        }
+        config::Transport::Webhook => {
+            let updates = webhook::updates(&bot);
+            match webhook::set_webhook(&config) {
+                Ok(_) => info!("Telegram webhook is set to {}", config.telegram.webhook_url),
+                Err(err) => {
+                    error!("Error on setting the webhook: {}", err);
+                    return;
+                }
+            };
+            lp.run(updates.and_then(handler).for_each(|_| Ok(())).into_future())
+        }
+    };
+
+    match result {
+        Ok(_) => (),
+        Err(err) => {
+            error!("{:?}", err);
+            health::record_error(health::TELEGRAM, &format!("{:?}", err));
+        }
     };
     //bot.register(handle);
 
@@ -0,0 +1,75 @@
+extern crate chrono;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use self::chrono::Utc;
+
+use alerts::{self, Alert, Event};
+use config::Config;
+use database;
+use spool;
+use supervisor;
+
+const CHECK_INTERVAL: u64 = 60 * 60;
+const REPORT_INTERVAL: i64 = 24 * 60 * 60;
+
+
+fn report(spool_dir: &str, removed: &Vec<(spool::FileMeta, spool::Removal)>) -> String {
+    let mut lines: Vec<String> = vec![];
+    lines.push("Daily cleanup report:".to_string());
+
+    let freed: u64 = removed.iter().map(|(meta, _)| meta.size).sum();
+    lines.push(format!("Removed {} files, {} bytes freed", removed.len(), freed));
+    for (meta, reason) in removed {
+        lines.push(format!("{:?}: {} ({}, {:?} {})", reason, meta.id, meta.original_name,
+                           meta.channel, meta.submitter_label()));
+    }
+
+    match spool::usage(spool_dir) {
+        Ok((count, size)) => lines.push(format!("Spool now holds {} files, {} bytes", count, size)),
+        Err(err) => lines.push(format!("Error on getting the spool usage: {}", err)),
+    };
+
+    lines.join("\n")
+}
+
+
+/// Runs cleanup of the spool
+///
+/// Applies retention policies every hour and sends the cleanup report to the admin once a day.
+/// You should provide `Config` and `UsersTable` as shared state `Arc<Mutex>`
+///
+/// # Examples
+///
+/// ```rust
+/// let users_table = Arc::new(Mutex::new(read_users().unwrap()));
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let cleaner = thread::spawn(move || {
+///        run_cleaner(Arc::clone(&config), Arc::clone(&users_table));
+///  });
+/// ```
+pub fn run_cleaner(a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let mut removed: Vec<(spool::FileMeta, spool::Removal)> = vec![];
+    let mut last_report = Utc::now().timestamp();
+
+    loop {
+        let config = { a_config.lock().unwrap().clone() };
+        match spool::cleanup(&config.spool, Utc::now().timestamp()) {
+            Ok(mut data) => removed.append(&mut data),
+            Err(err) => error!("Error on cleaning the spool: {}", err),
+        };
+
+        let now = Utc::now().timestamp();
+        if now - last_report >= REPORT_INTERVAL {
+            let admin = { a_users_table.lock().unwrap().get_admin() };
+            alerts::notify_admin(&config, admin, Alert::new(Event::Cleanup, &report(&config.spool.dir, &removed)));
+            removed.clear();
+            last_report = now;
+        }
+
+        if !supervisor::sleep(Duration::from_secs(CHECK_INTERVAL)) {
+            return;
+        }
+    }
+}
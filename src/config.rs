@@ -1,5 +1,9 @@
 extern crate toml;
 
+use std::collections::HashMap;
+
+use alerts::Event;
+use convert::{Font, Layout};
 use database::{init_db};
 use io_tools;
 use printer::get_printers;
@@ -13,12 +17,575 @@ pub struct IMAPConfig {
     pub password: String,
 }
 
+/// Structure, that contains the settings of the spool, where received files are stored,
+/// and its retention policies.
+///
+/// Ages are set in hours and sizes in megabytes, `0` disables the corresponding limit.
+/// All the limits are disabled by default, so nothing is deleted until they are set.
+/// `duplicate_window` is set in minutes: the admin is warned if the user sends the same
+/// document again within it.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SpoolConfig {
+    pub dir: String,
+    pub delete_printed_after: u64,
+    pub delete_rejected: bool,
+    pub max_age: u64,
+    pub max_size: u64,
+    pub duplicate_window: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> SpoolConfig {
+        SpoolConfig {
+            dir: String::from("spool"),
+            delete_printed_after: 0,
+            delete_rejected: false,
+            max_age: 0,
+            max_size: 0,
+            duplicate_window: 60,
+        }
+    }
+}
+
+/// Structure, that contains the settings of the downloader
+///
+/// `max_size` is set in megabytes (`0` disables the limit), timeouts in seconds and `backoff`
+/// (the delay before the first retry, doubled on every next one) in milliseconds.
+///
+/// Links sent by users are downloaded only from the domains in `allowed_domains` (any domain, if it is empty)
+/// and not in `denied_domains`, and only if they have one of the `allowed_types`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DownloadConfig {
+    pub max_size: u64,
+    pub connect_timeout: u64,
+    pub read_timeout: u64,
+    pub retries: u32,
+    pub backoff: u64,
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+    pub allowed_types: Vec<String>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> DownloadConfig {
+        DownloadConfig {
+            max_size: 20,
+            connect_timeout: 10,
+            read_timeout: 30,
+            retries: 3,
+            backoff: 1000,
+            allowed_domains: vec![],
+            denied_domains: vec![],
+            allowed_types: vec![String::from("application/pdf")],
+        }
+    }
+}
+
+/// Structure, that contains the settings of the conversion of photos and images to PDF
+///
+/// `layout` is used when the user didn't choose it in the caption, `album_delay` is the time in seconds
+/// to wait for the next photo of the album before it is converted. The images, which aren't JPEG,
+/// are decoded only if they have no more than `max_megapixels` millions of pixels (`0` disables the limit).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ImageConfig {
+    pub layout: Layout,
+    pub album_delay: u64,
+    pub max_megapixels: u64,
+}
+
+impl Default for ImageConfig {
+    fn default() -> ImageConfig {
+        ImageConfig {
+            layout: Layout::Fit,
+            album_delay: 3,
+            max_megapixels: 50,
+        }
+    }
+}
+
+/// Structure, that contains the default settings of printing the text messages
+///
+/// `monospace` overrides `font` with Courier, long lines are wrapped by words if `wrap` is set.
+/// All of them can be changed for the single message by the options of `/printtext`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TextConfig {
+    pub font: Font,
+    pub size: f64,
+    pub monospace: bool,
+    pub wrap: bool,
+}
+
+impl Default for TextConfig {
+    fn default() -> TextConfig {
+        TextConfig {
+            font: Font::Helvetica,
+            size: 11.0,
+            monospace: false,
+            wrap: true,
+        }
+    }
+}
+
+/// Structure, that contains the settings of the thumbnails, sent to the admin with the new files
+///
+/// `renderer` is the command, compatible with `pdftoppm`, first `pages` pages are rendered
+/// and the longest side of the thumbnail is scaled to `size` pixels.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PreviewConfig {
+    pub enabled: bool,
+    pub renderer: String,
+    pub pages: u32,
+    pub size: u32,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> PreviewConfig {
+        PreviewConfig {
+            enabled: true,
+            renderer: "pdftoppm".to_string(),
+            pages: 1,
+            size: 800,
+        }
+    }
+}
+
+/// Structure, that contains the settings of stamping the job information on the printouts
+///
+/// `footer` adds the line with the submitter, channel, job ID and time to the bottom of every page,
+/// `watermark` prints the same text diagonally across the page, `cover` adds the cover page with the job details.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct StampConfig {
+    pub footer: bool,
+    pub watermark: bool,
+    pub cover: bool,
+}
+
+/// Format of the log messages
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Plain,
+    Json,
+}
+
+/// Structure, that contains the settings of logging
+///
+/// `level` is one of `error`, `warn`, `info`, `debug` or `trace`. Messages are written to `file`
+/// (to stderr if it is empty), which is rotated after `max_size` megabytes, keeping `keep` old files.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: String,
+    pub format: LogFormat,
+    pub file: String,
+    pub max_size: u64,
+    pub keep: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Plain,
+            file: "".to_string(),
+            max_size: 10,
+            keep: 5,
+        }
+    }
+}
+
+/// Structure, that contains the settings of the Prometheus metrics endpoint
+///
+/// Metrics are served on `http://<address>/metrics`, the server is started only if `enabled` is set.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> MetricsConfig {
+        MetricsConfig {
+            enabled: false,
+            address: "127.0.0.1:9898".to_string(),
+        }
+    }
+}
+
+/// Structure, that contains the health endpoint and the thresholds of the health checks
+///
+/// The checks are served on `http://<address>/healthz` if `enabled` is set, which is the default.
+/// IMAP is unhealthy if it had no successful polls for `imap_timeout` seconds,
+/// the spool is unhealthy if its disk has less than `min_free_space` megabytes.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub imap_timeout: u64,
+    pub min_free_space: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            enabled: true,
+            address: "127.0.0.1:9897".to_string(),
+            imap_timeout: 300,
+            min_free_space: 100,
+        }
+    }
+}
+
+/// Structure, that contains the settings of restarting the bot threads and the shutdown, in seconds
+///
+/// The thread is restarted after `backoff`, and the delay is doubled on every next crash up to `max_backoff`.
+/// On SIGTERM the running downloads are waited for `shutdown_timeout`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    pub backoff: u64,
+    pub max_backoff: u64,
+    pub shutdown_timeout: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> SupervisorConfig {
+        SupervisorConfig {
+            backoff: 1,
+            max_backoff: 300,
+            shutdown_timeout: 60,
+        }
+    }
+}
+
+/// Structure, that contains the settings of the HTTP API for submitting the files
+///
+/// The API is served on `http://<address>/api/v1/` if `enabled` is set, the uploaded files are limited
+/// to `max_size` megabytes (`0` means 512 megabytes, the most the server accepts).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub max_size: u64,
+}
+
+impl Default for ApiConfig {
+    fn default() -> ApiConfig {
+        ApiConfig {
+            enabled: false,
+            address: "127.0.0.1:9900".to_string(),
+            max_size: 50,
+        }
+    }
+}
+
+/// Structure, that contains the settings of the upload page for printing without Telegram
+///
+/// The page is served on `http://<address>/` if `enabled` is set, the files are accepted with a mail token
+/// or a one-time code and limited to `max_size` megabytes (`0` means 512 megabytes, the most the server
+/// accepts).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct UploadConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub max_size: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> UploadConfig {
+        UploadConfig {
+            enabled: false,
+            address: "127.0.0.1:9901".to_string(),
+            max_size: 20,
+        }
+    }
+}
+
+/// Structure, that contains the daily limits of every submitter, applied by all channels except IPP,
+/// which has its own limits in the `[ipp]` section
+///
+/// Every Telegram user, token or code may send up to `daily_jobs` files and `daily_pages` pages in 24 hours,
+/// `0` disables the corresponding limit. The files of the admin are not limited.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+#[derive(Default)]
+pub struct QuotaConfig {
+    pub daily_jobs: u32,
+    pub daily_pages: u32,
+}
+
+
+/// Structure, that contains the settings of the IPP listener, which acts as the virtual printer
+///
+/// The printer is served on `ipp://<address>/ipp/print` if `enabled` is set, the documents are limited
+/// to `max_size` megabytes (`0` means 512 megabytes, the most the server accepts). Every mail token
+/// may send up to `daily_jobs` documents and `daily_pages` pages in 24 hours, `0` disables the corresponding limit.
+/// The tokens are sent over plain HTTP, so the listener should be reachable only through the TLS proxy.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct IppConfig {
+    pub enabled: bool,
+    pub address: String,
+    /// Name of the printer, shown by the clients
+    pub name: String,
+    pub max_size: u64,
+    pub daily_jobs: u32,
+    pub daily_pages: u32,
+}
+
+impl Default for IppConfig {
+    fn default() -> IppConfig {
+        IppConfig {
+            enabled: false,
+            address: "127.0.0.1:8631".to_string(),
+            name: "Teleprint".to_string(),
+            max_size: 50,
+            daily_jobs: 20,
+            daily_pages: 100,
+        }
+    }
+}
+
+/// Structure, that contains the settings of the Matrix bot
+///
+/// The bot logs in to `homeserver` with `access_token` of its account and accepts the files in `rooms`
+/// and, if `direct` is set, in the direct chats started by the known users. `users` maps Matrix IDs
+/// to the user IDs of `users.toml`, the one mapped to the admin gets the admin commands. The files are
+/// announced in `admin_room` in addition to Telegram, if it is set.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MatrixConfig {
+    pub enabled: bool,
+    pub homeserver: String,
+    pub access_token: String,
+    pub rooms: Vec<String>,
+    pub direct: bool,
+    pub admin_room: String,
+    /// File with the sync position and the rooms of the users, so nothing is handled twice after the restart
+    pub state_file: String,
+    /// Seconds of the long polling of `/sync`
+    pub sync_timeout: u64,
+    pub users: HashMap<String, i64>,
+}
+
+impl Default for MatrixConfig {
+    fn default() -> MatrixConfig {
+        MatrixConfig {
+            enabled: false,
+            homeserver: "http://127.0.0.1:6167".to_string(),
+            access_token: "".to_string(),
+            rooms: vec![],
+            direct: true,
+            admin_room: "".to_string(),
+            state_file: "matrix.toml".to_string(),
+            sync_timeout: 30,
+            users: HashMap::new(),
+        }
+    }
+}
+
+/// Webhook, which receives the admin alerts as the JSON requests
+///
+/// `template` is the body of the request, `{event}`, `{text}`, `{file}` and `{time}` are replaced with
+/// the escaped values of the alert. If `secret` is set, the body is signed by HMAC-SHA256 in the
+/// `X-Teleprint-Signature: sha256=<hex digest>` header. Only the `events` are sent, all of them if it is empty.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub template: String,
+    pub secret: String,
+    pub events: Vec<Event>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> WebhookConfig {
+        WebhookConfig {
+            url: "".to_string(),
+            template: "{\"event\": \"{event}\", \"text\": \"{text}\", \"file\": \"{file}\", \"time\": {time}}".to_string(),
+            secret: "".to_string(),
+            events: vec![],
+        }
+    }
+}
+
+/// Account, which sends the admin alerts to the XMPP addresses in `to`
+///
+/// The client connects to `server` (the domain of `jid` on port 5222 if it is empty), always uses
+/// STARTTLS and logs in by SASL PLAIN. Only the `events` are sent, all of them if it is empty.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct XmppConfig {
+    pub enabled: bool,
+    pub server: String,
+    pub jid: String,
+    pub password: String,
+    pub to: Vec<String>,
+    pub events: Vec<Event>,
+}
+
+impl Default for XmppConfig {
+    fn default() -> XmppConfig {
+        XmppConfig {
+            enabled: false,
+            server: "".to_string(),
+            jid: "".to_string(),
+            password: "".to_string(),
+            to: vec![],
+            events: vec![],
+        }
+    }
+}
+
+/// Structure, that contains the outbound notifiers of the admin alerts besides Telegram
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AlertsConfig {
+    pub webhooks: Vec<WebhookConfig>,
+    pub xmpp: XmppConfig,
+}
+
+/// How the Telegram bot receives the updates
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// The bot asks Telegram for the updates every `update_interval` milliseconds
+    Polling,
+    /// Telegram posts the updates to `webhook_url`, which leads to `address`
+    Webhook,
+}
+
+/// Structure, that contains the settings of the Telegram transport
+///
+/// With `Webhook` the bot registers `webhook_url` in Telegram on the start and accepts the updates
+/// on `http://<address>/`, so `webhook_url` must be the HTTPS address of the reverse proxy before it.
+/// The updates without `secret_token` in the `X-Telegram-Bot-Api-Secret-Token` header are refused,
+/// the token is generated on every start, if it is empty. With `Polling` the webhook is deleted.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TelegramConfig {
+    pub transport: Transport,
+    pub update_interval: u64,
+    pub webhook_url: String,
+    pub address: String,
+    pub secret_token: String,
+    /// Simultaneous connections of Telegram to the webhook
+    pub max_connections: u32,
+}
+
+impl Default for TelegramConfig {
+    fn default() -> TelegramConfig {
+        TelegramConfig {
+            transport: Transport::Polling,
+            update_interval: 1000,
+            webhook_url: "".to_string(),
+            address: "127.0.0.1:8443".to_string(),
+            secret_token: "".to_string(),
+            max_connections: 10,
+        }
+    }
+}
+
+/// Role of the dashboard account
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Everything, including the users, tokens and the config
+    Admin,
+    /// Only the jobs: approving, rejecting, printing and canceling
+    Operator,
+}
+
+/// Account of the web dashboard, `password` is the hash from `teleprint --hash-password`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DashboardAccount {
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+}
+
+/// Structure, that contains the settings of the web dashboard
+///
+/// The dashboard is served on `http://<address>/` if `enabled` is set, the sessions expire
+/// after `session_timeout` seconds without requests.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DashboardConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub session_timeout: u64,
+    pub accounts: Vec<DashboardAccount>,
+}
+
+impl Default for DashboardConfig {
+    fn default() -> DashboardConfig {
+        DashboardConfig {
+            enabled: false,
+            address: "127.0.0.1:9899".to_string(),
+            session_timeout: 60 * 60,
+            accounts: vec![],
+        }
+    }
+}
+
 /// Structure, that contains necessary information for getting access to the Telegram and IMAP
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub token: String,
     pub printer: String,
     pub imap: IMAPConfig,
+    #[serde(default)]
+    pub telegram: TelegramConfig,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
+    #[serde(default)]
+    pub images: ImageConfig,
+    #[serde(default)]
+    pub text: TextConfig,
+    #[serde(default)]
+    pub preview: PreviewConfig,
+    #[serde(default)]
+    pub stamp: StampConfig,
+    #[serde(default)]
+    pub printer_stamps: HashMap<String, StampConfig>,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+    #[serde(default)]
+    pub dashboard: DashboardConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub ipp: IppConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub matrix: MatrixConfig,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+}
+
+impl Config {
+    /// Returns stamping settings of the printer, the global ones are used if it has no own settings
+    pub fn stamp_config(&self, printer: &str) -> &StampConfig {
+        self.printer_stamps.get(printer).unwrap_or(&self.stamp)
+    }
 }
 
 
@@ -37,7 +604,7 @@ pub fn read_config() -> Result<Config, String> {
     let config: Config = match toml::from_str(&config_str) {
         Ok(value) => value,
         Err(err) => {
-            println!("Something goes wrong while reading the users: {}", err);
+            error!("Something goes wrong while reading the config: {}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -61,19 +628,19 @@ pub fn write_config(config: &Config) -> Result<(), String> {
     let conf_str = match toml::to_string(config) {
         Ok(value) => value,
         Err(err) => {
-            println!("Something went wrong while parsing the config: {}", err);
+            error!("Something went wrong while parsing the config: {}", err);
             panic!("{}", err);
         }
     };
 
 
     match io_tools::write_to_file("config.toml", conf_str) {
-        Ok(_) => return Ok(()),
+        Ok(_) => Ok(()),
         Err(err) => {
-            println!("An error occured while writing to the config: {}", err);
-            return Err(format!("{:?}", err));
+            error!("An error occured while writing to the config: {}", err);
+            Err(format!("{:?}", err))
         }
-    };
+    }
 }
 
 /// Setups your Telegram/IMAP bots by command prompt
@@ -97,6 +664,25 @@ pub fn setup() {
             user: m_user,
             password: m_password,
         },
+        telegram: TelegramConfig::default(),
+        spool: SpoolConfig::default(),
+        download: DownloadConfig::default(),
+        images: ImageConfig::default(),
+        text: TextConfig::default(),
+        preview: PreviewConfig::default(),
+        stamp: StampConfig::default(),
+        printer_stamps: HashMap::new(),
+        log: LogConfig::default(),
+        metrics: MetricsConfig::default(),
+        health: HealthConfig::default(),
+        supervisor: SupervisorConfig::default(),
+        dashboard: DashboardConfig::default(),
+        api: ApiConfig::default(),
+        upload: UploadConfig::default(),
+        ipp: IppConfig::default(),
+        quota: QuotaConfig::default(),
+        matrix: MatrixConfig::default(),
+        alerts: AlertsConfig::default(),
     }) {
         Ok(_) => println!("Ok"),
         Err(err) => panic!("{:?}", err),
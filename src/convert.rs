@@ -0,0 +1,461 @@
+extern crate chrono;
+extern crate image;
+extern crate lopdf;
+
+use std::io::Cursor;
+
+use self::chrono::Utc;
+
+use self::image::{ColorType, ImageDecoder};
+use self::image::jpeg::JPEGDecoder;
+use self::lopdf::{Document, Object, ObjectId, Stream, StringFormat};
+use self::lopdf::content::{Content, Operation};
+use self::lopdf::dictionary;
+
+use config::{Config, TextConfig};
+use spool;
+
+/// Width of the A4 page in points
+pub const PAGE_WIDTH: f64 = 595.0;
+/// Height of the A4 page in points
+pub const PAGE_HEIGHT: f64 = 842.0;
+const MARGIN: f64 = 28.0;
+// Images without physical size are printed at 96 DPI in the `Actual` layout
+const POINTS_PER_PIXEL: f64 = 72.0 / 96.0;
+const LINE_SPACING: f64 = 1.2;
+const TAB_WIDTH: usize = 4;
+
+/// How the image is placed on the page
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    /// Scale the image to fit into the page margins
+    Fit,
+    /// Scale the image to cover the whole page, cropping the edges
+    Fill,
+    /// Keep the size of the image, cropping it if it is bigger than the page
+    Actual,
+}
+
+impl Layout {
+    /// Parses layout from the user input: `fit`, `fill` or `actual`
+    pub fn parse(text: &str) -> Option<Layout> {
+        match text.trim().trim_start_matches('/').to_lowercase().as_str() {
+            "fit" => Some(Layout::Fit),
+            "fill" => Some(Layout::Fill),
+            "actual" => Some(Layout::Actual),
+            _ => None,
+        }
+    }
+}
+
+/// Standard PDF font, which is used for printing the text
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Font {
+    Helvetica,
+    Times,
+    Courier,
+}
+
+impl Font {
+    /// Parses font from the user input: `helvetica`, `times` or `courier`
+    pub fn parse(text: &str) -> Option<Font> {
+        match text.trim().to_lowercase().as_str() {
+            "helvetica" => Some(Font::Helvetica),
+            "times" => Some(Font::Times),
+            "courier" => Some(Font::Courier),
+            _ => None,
+        }
+    }
+
+    fn base_font(&self, bold: bool) -> &'static str {
+        match (*self, bold) {
+            (Font::Helvetica, false) => "Helvetica",
+            (Font::Helvetica, true) => "Helvetica-Bold",
+            (Font::Times, false) => "Times-Roman",
+            (Font::Times, true) => "Times-Bold",
+            (Font::Courier, false) => "Courier",
+            (Font::Courier, true) => "Courier-Bold",
+        }
+    }
+
+    /// Returns the average width of the character relative to the font size, exact for Courier
+    pub fn char_width(&self) -> f64 {
+        match *self {
+            Font::Helvetica => 0.52,
+            Font::Times => 0.47,
+            Font::Courier => 0.6,
+        }
+    }
+}
+
+
+struct Picture {
+    width: u64,
+    height: u64,
+    stream: Stream,
+}
+
+fn to_pdf_error(err: lopdf::Error) -> String {
+    format!("Error on creating PDF: {:?}", err)
+}
+
+fn jpeg_picture(data: &[u8]) -> Option<Picture> {
+    let decoder = match JPEGDecoder::new(Cursor::new(data)) {
+        Ok(data) => data,
+        Err(_) => return None,
+    };
+    let color_space = match decoder.colortype() {
+        ColorType::Gray(8) => "DeviceGray",
+        ColorType::RGB(8) => "DeviceRGB",
+        _ => return None,
+    };
+    let (width, height) = decoder.dimensions();
+
+    let stream = Stream::new(dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => width as i64,
+        "Height" => height as i64,
+        "ColorSpace" => color_space,
+        "BitsPerComponent" => 8,
+        "Filter" => "DCTDecode"
+    }, data.to_vec()).with_compression(false);
+
+    Some(Picture { width, height, stream })
+}
+
+// The dimensions are read from the header first, so the small file of the huge image
+// is refused before it is decoded into memory
+fn decoded_picture(data: &[u8], max_megapixels: u64) -> Result<Picture, String> {
+    let reader = match image::io::Reader::new(Cursor::new(data)).with_guessed_format() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on reading the image: {}", err)),
+    };
+    let (width, height) = match reader.into_dimensions() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on decoding the image: {}", err)),
+    };
+    if max_megapixels > 0 && width as u64 * height as u64 > max_megapixels * 1_000_000 {
+        return Err(format!("The image is too big: {}x{} pixels, the limit is {} megapixels",
+                           width, height, max_megapixels));
+    }
+
+    let decoded = match image::load_from_memory(data) {
+        Ok(data) => data.to_rgb(),
+        Err(err) => return Err(format!("Error on decoding the image: {}", err)),
+    };
+    let (width, height) = (decoded.width() as u64, decoded.height() as u64);
+
+    let stream = Stream::new(dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => width as i64,
+        "Height" => height as i64,
+        "ColorSpace" => "DeviceRGB",
+        "BitsPerComponent" => 8
+    }, decoded.into_raw());
+
+    Ok(Picture { width, height, stream })
+}
+
+/// Returns true if the data looks like an image, which can be converted by `images_to_pdf`
+pub fn is_image(data: &[u8]) -> bool {
+    image::guess_format(data).is_ok()
+}
+
+// Returns page size and the rectangle (x, y, width, height) of the image on it
+fn place(picture: &Picture, layout: Layout) -> ((f64, f64), (f64, f64, f64, f64)) {
+    let (width, height) = (picture.width as f64, picture.height as f64);
+    let page = if width > height { (PAGE_HEIGHT, PAGE_WIDTH) } else { (PAGE_WIDTH, PAGE_HEIGHT) };
+
+    let scale = match layout {
+        Layout::Fit => ((page.0 - 2.0 * MARGIN) / width).min((page.1 - 2.0 * MARGIN) / height),
+        Layout::Fill => (page.0 / width).max(page.1 / height),
+        Layout::Actual => POINTS_PER_PIXEL,
+    };
+    let (w, h) = (width * scale, height * scale);
+
+    (page, ((page.0 - w) / 2.0, (page.1 - h) / 2.0, w, h))
+}
+
+/// Converts images to the PDF, one image per page, returns PDF contents on Ok()
+///
+/// JPEG images are embedded as is, other formats are decoded and recompressed, if they have
+/// no more than `max_megapixels` millions of pixels (`0` disables the limit).
+/// The page is turned to the landscape orientation for the wide images.
+///
+/// # Examples
+///
+/// ```rust
+/// let photos = vec![read("photo1.jpg").unwrap(), read("photo2.png").unwrap()];
+/// let pdf = images_to_pdf(&photos, Layout::Fit, 50).unwrap();
+/// ```
+pub fn images_to_pdf(images: &Vec<Vec<u8>>, layout: Layout, max_megapixels: u64) -> Result<Vec<u8>, String> {
+    if images.is_empty() {
+        return Err("No images to convert".to_string());
+    }
+
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let mut kids: Vec<Object> = vec![];
+
+    for data in images {
+        let picture = match jpeg_picture(data) {
+            Some(picture) => picture,
+            None => decoded_picture(data, max_megapixels)?,
+        };
+        let ((page_width, page_height), (x, y, w, h)) = place(&picture, layout);
+        let image_id = doc.add_object(picture.stream);
+
+        let content = Content {
+            operations: vec![
+                Operation::new("q", vec![]),
+                Operation::new("re", vec![0.into(), 0.into(), page_width.into(), page_height.into()]),
+                Operation::new("W", vec![]),
+                Operation::new("n", vec![]),
+                Operation::new("cm", vec![w.into(), 0.into(), 0.into(), h.into(), x.into(), y.into()]),
+                Operation::new("Do", vec!["Im0".into()]),
+                Operation::new("Q", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().map_err(to_pdf_error)?));
+
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), page_width.into(), page_height.into()],
+            "Contents" => content_id,
+            "Resources" => dictionary! {
+                "XObject" => dictionary! {
+                    "Im0" => image_id
+                }
+            }
+        });
+        kids.push(page_id.into());
+    }
+
+    finish(doc, pages_id, kids)
+}
+
+// Adds the page tree and the catalog, returns the compressed PDF
+fn finish(mut doc: Document, pages_id: ObjectId, kids: Vec<Object>) -> Result<Vec<u8>, String> {
+    let count = kids.len() as i64;
+    doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+        "Type" => "Pages",
+        "Kids" => kids,
+        "Count" => count
+    }));
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id
+    });
+    doc.trailer.set("Root", catalog_id);
+    doc.compress();
+
+    let mut buffer: Vec<u8> = vec![];
+    match doc.save_to(&mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(err) => Err(format!("Error on writing PDF: {}", err)),
+    }
+}
+
+
+/// Parses options of the text printing, like `courier size=9 nowrap`, on top of `defaults`
+///
+/// Known options are the font name, `mono` and `nomono`, `wrap` and `nowrap`, `size=<points>`.
+/// Returns None if some word is not an option, so the line can be treated as the text itself.
+pub fn parse_text_options(line: &str, defaults: &TextConfig) -> Option<TextConfig> {
+    let mut options = defaults.clone();
+    let mut found = false;
+    for word in line.split_whitespace() {
+        let word = word.to_lowercase();
+        match word.as_str() {
+            "mono" => options.monospace = true,
+            "nomono" => options.monospace = false,
+            "wrap" => options.wrap = true,
+            "nowrap" => options.wrap = false,
+            _ if word.starts_with("size=") => match word["size=".len()..].parse::<f64>() {
+                Ok(size) if (4.0..=72.0).contains(&size) => options.size = size,
+                _ => return None,
+            },
+            _ => match Font::parse(&word) {
+                Some(font) => options.font = font,
+                None => return None,
+            },
+        };
+        found = true;
+    }
+    if found { Some(options) } else { None }
+}
+
+/// Encodes the line for the standard fonts with WinAnsiEncoding, which matches Latin-1 for the printable characters
+///
+/// Other characters are replaced with `?`.
+pub fn encode_line(line: &str) -> Vec<u8> {
+    line.chars().map(|c| {
+        let code = c as u32;
+        if (0x20..0x7f).contains(&code) || (0xa0..=0xff).contains(&code) { code as u8 } else { b'?' }
+    }).collect()
+}
+
+fn wrap_line(line: &str, width: usize, wrap: bool) -> Vec<String> {
+    let line = line.replace('\t', &" ".repeat(TAB_WIDTH));
+    if !wrap || line.chars().count() <= width {
+        return vec![line];
+    }
+
+    let mut lines: Vec<String> = vec![];
+    let mut current = String::new();
+    for word in line.split(' ') {
+        let mut word = word.to_string();
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > width {
+            lines.push(current);
+            current = String::new();
+        }
+        while word.chars().count() > width {
+            let rest: String = word.chars().skip(width).collect();
+            lines.push(word.chars().take(width).collect());
+            word = rest;
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&word);
+    }
+    lines.push(current);
+    lines
+}
+
+/// Renders the text to the PDF on A4 pages, returns PDF contents on Ok()
+///
+/// Long lines are wrapped by words if `wrap` is set, otherwise they are cut by the page margin.
+/// `header`, if given, is printed in bold at the top of every page with the page number.
+/// Only Latin-1 characters can be printed with the standard fonts, others are replaced with `?`.
+///
+/// # Examples
+///
+/// ```rust
+/// let pdf = text_to_pdf("Hello, world!", &config.text, Some("From 123456, 2019-05-01 12:00:00")).unwrap();
+/// ```
+pub fn text_to_pdf(text: &str, options: &TextConfig, header: Option<&str>) -> Result<Vec<u8>, String> {
+    if text.trim().is_empty() {
+        return Err("No text to print".to_string());
+    }
+
+    let font = if options.monospace { Font::Courier } else { options.font };
+    let leading = options.size * LINE_SPACING;
+    let width = ((PAGE_WIDTH - 2.0 * MARGIN) / (font.char_width() * options.size)).max(1.0) as usize;
+    let mut per_page = ((PAGE_HEIGHT - 2.0 * MARGIN) / leading).max(1.0) as usize;
+    if header.is_some() && per_page > 2 {
+        per_page -= 2;
+    }
+
+    let lines: Vec<String> = text.lines().flat_map(|line| wrap_line(line.trim_end(), width, options.wrap)).collect();
+    let pages: Vec<&[String]> = lines.chunks(per_page).collect();
+
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let regular_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => font.base_font(false),
+        "Encoding" => "WinAnsiEncoding"
+    });
+    let bold_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => font.base_font(true),
+        "Encoding" => "WinAnsiEncoding"
+    });
+    let mut kids: Vec<Object> = vec![];
+
+    for (number, page) in pages.iter().enumerate() {
+        let mut operations = vec![
+            Operation::new("BT", vec![]),
+            Operation::new("TL", vec![leading.into()]),
+            Operation::new("Td", vec![MARGIN.into(), (PAGE_HEIGHT - MARGIN - options.size).into()]),
+        ];
+        if let Some(title) = header {
+            let title = format!("{} (page {} of {})", title, number + 1, pages.len());
+            operations.push(Operation::new("Tf", vec!["F2".into(), options.size.into()]));
+            operations.push(Operation::new("Tj", vec![Object::String(encode_line(&title), StringFormat::Literal)]));
+            operations.push(Operation::new("T*", vec![]));
+            operations.push(Operation::new("T*", vec![]));
+        }
+        operations.push(Operation::new("Tf", vec!["F1".into(), options.size.into()]));
+        for line in page.iter() {
+            operations.push(Operation::new("Tj", vec![Object::String(encode_line(line), StringFormat::Literal)]));
+            operations.push(Operation::new("T*", vec![]));
+        }
+        operations.push(Operation::new("ET", vec![]));
+
+        let content = Content { operations };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().map_err(to_pdf_error)?));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), PAGE_WIDTH.into(), PAGE_HEIGHT.into()],
+            "Contents" => content_id,
+            "Resources" => dictionary! {
+                "Font" => dictionary! {
+                    "F1" => regular_id,
+                    "F2" => bold_id
+                }
+            }
+        });
+        kids.push(page_id.into());
+    }
+
+    finish(doc, pages_id, kids)
+}
+
+/// Renders the text message to the PDF and stores it to the spool like the uploaded file
+///
+/// The first line of the message may contain the options (see `parse_text_options`),
+/// the defaults are taken from the `[text]` section of the config.
+/// `author` and the current time are printed in the header of every page.
+///
+/// # Examples
+///
+/// ```rust
+/// let meta = text_to_spool("mono size=9\nerror: something failed", &config, spool::Channel::Telegram,
+///                          "123456", "Thomas @thomasfire").unwrap();
+/// println!("{}", meta.describe());
+/// ```
+pub fn text_to_spool(message: &str, config: &Config, channel: spool::Channel, submitter: &str,
+                     author: &str) -> Result<spool::FileMeta, String> {
+    let message = message.trim_start_matches([' ', '\t']);
+    let mut parts = message.splitn(2, '\n');
+    let first = parts.next().unwrap_or("");
+
+    let (options, text) = match parse_text_options(first, &config.text) {
+        Some(options) => (options, parts.next().unwrap_or("")),
+        None => (config.text.clone(), message),
+    };
+
+    let header = format!("From {}, {}", author, spool::format_time(Utc::now().timestamp()));
+    let pdf = text_to_pdf(text, &options, Some(&header))?;
+    spool::store(&config.spool.dir, pdf, "text.pdf", "application/pdf", channel, submitter)
+}
+
+/// Stores the PDF file or the image, converted to PDF with the layout from the `[images]` section,
+/// to the spool, other documents are refused
+///
+/// # Examples
+///
+/// ```rust
+/// let meta = document_to_spool(read("photo.jpg").unwrap(), "photo.jpg", &config, spool::Channel::Web,
+///                              "code K7QM2XHP").unwrap();
+/// println!("{}", meta.describe());
+/// ```
+pub fn document_to_spool(data: Vec<u8>, name: &str, config: &Config, channel: spool::Channel,
+                         submitter: &str) -> Result<spool::FileMeta, String> {
+    let pdf = if data.starts_with(b"%PDF-") {
+        data
+    } else if is_image(&data) {
+        images_to_pdf(&vec![data], config.images.layout, config.images.max_megapixels)?
+    } else {
+        return Err("Only PDF files and images can be printed".to_string());
+    };
+    spool::store(&config.spool.dir, pdf, name, "application/pdf", channel, submitter)
+}
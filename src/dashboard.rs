@@ -0,0 +1,742 @@
+extern crate chrono;
+extern crate reqwest;
+extern crate toml;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{read, remove_file};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::chrono::Utc;
+
+use audit;
+use config::{self, Config, DashboardAccount, Role};
+use database;
+use hash;
+use health;
+use http::{self, escape_html as escape, Handler, Request, Response};
+use jobs;
+use preview;
+use printer;
+use spool::{self, Channel, FileMeta};
+use transform;
+
+const COOKIE: &str = "teleprint_session";
+const MAX_BODY: u64 = 1024 * 1024;
+const PAST_JOBS: usize = 50;
+// Delay of the failed login, which slows down guessing of the passwords
+const LOGIN_DELAY: u64 = 1;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:0 auto;max-width:1100px;padding:0 1em}\
+nav{padding:.8em 0;border-bottom:1px solid #ccc;margin-bottom:1em}nav a,nav form{margin-right:1em;display:inline}\
+table{border-collapse:collapse;width:100%}td,th{border-bottom:1px solid #ddd;padding:.3em;text-align:left;vertical-align:top}\
+.card{border:1px solid #ccc;border-radius:4px;padding:.8em;margin-bottom:1em;display:flex;gap:1em}\
+.card img{max-width:200px;border:1px solid #ddd}form{display:inline}pre{white-space:pre-wrap}\
+.message{background:#eef;padding:.5em;margin-bottom:1em}textarea{width:100%;height:40em;font-family:monospace}";
+
+// Session of the logged in account, the role is taken from the config on every request,
+// so the changes of the accounts apply immediately
+#[derive(Clone)]
+struct Session {
+    account: String,
+    csrf: String,
+    expires: i64,
+}
+
+// Account of the current request
+struct User {
+    name: String,
+    role: Role,
+    csrf: String,
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+
+fn field<'a>(form: &'a HashMap<String, String>, name: &str) -> &'a str {
+    form.get(name).map(|value| value.trim()).unwrap_or("")
+}
+
+fn audit_web(user: &User, action: audit::Action, target: &str) {
+    audit::record(&user.name, Channel::Web, action, target);
+}
+
+
+// Redirects back to the page with the result of the action
+fn redirect_with(path: &str, message: &str) -> Response {
+    match reqwest::Url::parse("http://localhost").and_then(|base| base.join(path)) {
+        Ok(mut url) => {
+            url.query_pairs_mut().append_pair("message", message);
+            Response::redirect(&format!("{}?{}", url.path(), url.query().unwrap_or("")))
+        }
+        Err(_) => Response::redirect(path),
+    }
+}
+
+fn html(status: u16, title: &str, body: &str) -> Response {
+    let page = format!("<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{} - Teleprint</title>\
+                        <style>{}</style></head><body>{}</body></html>", escape(title), STYLE, body);
+    Response::new(status, "text/html; charset=utf-8", Vec::from(page))
+        .with_header("Cache-Control", "no-store")
+        .with_header("X-Frame-Options", "DENY")
+        .with_header("Content-Security-Policy", "default-src 'self'; style-src 'unsafe-inline'")
+}
+
+fn page(user: &User, title: &str, request: &Request, body: &str) -> Response {
+    let admin_links = if user.role == Role::Admin {
+        "<a href=\"/users\">Users</a><a href=\"/config\">Config</a><a href=\"/audit\">Audit</a>"
+    } else {
+        ""
+    };
+    let message = match request.query.get("message") {
+        Some(text) => format!("<div class=\"message\">{}</div>", escape(text)),
+        None => "".to_string(),
+    };
+    html(200, title, &format!("<nav><a href=\"/jobs\">Jobs</a><a href=\"/spool\">Spool</a><a href=\"/printer\">Printer</a>{}\
+                               {} ({:?}) {}</nav>{}<h1>{}</h1>{}",
+                              admin_links, escape(&user.name), user.role,
+                              post_form(user, "/logout", "", "Log out"), message, escape(title), body))
+}
+
+// Form with the single button and the CSRF token
+fn post_form(user: &User, action: &str, fields: &str, button: &str) -> String {
+    format!("<form method=\"post\" action=\"{}\"><input type=\"hidden\" name=\"csrf\" value=\"{}\">{}\
+             <button>{}</button></form>", escape(action), user.csrf, fields, escape(button))
+}
+
+fn hidden(name: &str, value: &str) -> String {
+    format!("<input type=\"hidden\" name=\"{}\" value=\"{}\">", name, escape(value))
+}
+
+
+fn login_page(error: &str) -> Response {
+    let error = if error.is_empty() { "".to_string() } else { format!("<div class=\"message\">{}</div>", escape(error)) };
+    html(if error.is_empty() { 200 } else { 401 }, "Log in",
+         &format!("<h1>Teleprint</h1>{}<form method=\"post\" action=\"/login\">\
+                   <p><input name=\"name\" placeholder=\"Name\" autofocus></p>\
+                   <p><input name=\"password\" type=\"password\" placeholder=\"Password\"></p>\
+                   <button>Log in</button></form>", error))
+}
+
+fn login(request: &Request, config: &Config) -> Response {
+    let form = request.form();
+    let (name, password) = (field(&form, "name"), form.get("password").map(|value| value.as_str()).unwrap_or(""));
+    let valid = config.dashboard.accounts.iter()
+        .any(|account| account.name == name && hash::verify_password(password, &account.password));
+    if !valid {
+        audit::record(name, Channel::Web, audit::Action::Unauthorized, "login");
+        thread::sleep(Duration::from_secs(LOGIN_DELAY));
+        return login_page("Wrong name or password");
+    }
+
+    let now = Utc::now().timestamp();
+    let id = hash::random_hex();
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| session.expires > now);
+    sessions.insert(id.clone(), Session {
+        account: name.to_string(),
+        csrf: hash::random_hex(),
+        expires: now + config.dashboard.session_timeout as i64,
+    });
+    Response::redirect("/jobs")
+        .with_header("Set-Cookie", &format!("{}={}; HttpOnly; SameSite=Strict; Path=/", COOKIE, id))
+}
+
+// Returns the account of the valid session and prolongs it
+fn authenticate(request: &Request, config: &Config) -> Option<User> {
+    let id = request.cookie(COOKIE)?;
+    let now = Utc::now().timestamp();
+    let mut sessions = SESSIONS.lock().unwrap();
+    let account = match sessions.get_mut(&id) {
+        Some(ref session) if session.expires <= now => None,
+        Some(session) => {
+            session.expires = now + config.dashboard.session_timeout as i64;
+            config.dashboard.accounts.iter().find(|account| account.name == session.account)
+                .map(|account| User { name: account.name.clone(), role: account.role, csrf: session.csrf.clone() })
+        }
+        None => return None,
+    };
+    if account.is_none() {
+        sessions.remove(&id);
+    }
+    account
+}
+
+fn logout(request: &Request) -> Response {
+    if let Some(id) = request.cookie(COOKIE) {
+        SESSIONS.lock().unwrap().remove(&id);
+    }
+    Response::redirect("/login")
+        .with_header("Set-Cookie", &format!("{}=; HttpOnly; SameSite=Strict; Path=/; Max-Age=0", COOKIE))
+}
+
+
+fn file_row(meta: &FileMeta) -> String {
+    format!("<tr><td><a href=\"/files/{}\">{}</a></td><td>{}</td><td>{:?} {}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&meta.id), escape(&meta.id), escape(&meta.original_name), meta.channel, escape(&meta.submitter_label()),
+            meta.pages.map_or("?".to_string(), |pages| format!("{}", pages)), meta.size, escape(&meta.status()))
+}
+
+fn file_table(files: &[FileMeta]) -> String {
+    let rows: Vec<String> = files.iter().map(file_row).collect();
+    format!("<table><tr><th>ID</th><th>Name</th><th>From</th><th>Pages</th><th>Bytes</th><th>Status</th></tr>{}</table>",
+            rows.join(""))
+}
+
+fn has_preview(meta: &FileMeta, config: &Config) -> bool {
+    config.preview.enabled && meta.mime == "application/pdf"
+}
+
+// Buttons of the actions, which can be applied to the file in its current state
+fn file_actions(user: &User, meta: &FileMeta) -> String {
+    let path = format!("/files/{}", meta.id);
+    let mut actions = vec![
+        post_form(user, &format!("{}/print", path),
+                  &format!("<input name=\"options\" placeholder=\"pages=1-4 nup=2 fit\" value=\"{}\">",
+                           escape(meta.options.as_ref().map_or("", |options| options.as_str()))), "Print"),
+    ];
+    if meta.is_pending() {
+        actions.push(post_form(user, &format!("{}/reject", path), "", "Reject"));
+    }
+    if meta.job.is_some() {
+        actions.push(post_form(user, &format!("{}/cancel", path), "", "Cancel job"));
+    }
+    actions.push(if meta.pinned {
+        post_form(user, &format!("{}/unpin", path), "", "Unpin")
+    } else {
+        post_form(user, &format!("{}/pin", path), "", "Pin")
+    });
+    actions.push(post_form(user, &format!("{}/delete", path), "", "Delete"));
+    actions.push(format!("<a href=\"{}/download\">Download</a>", path));
+    actions.join(" ")
+}
+
+fn jobs_page(user: &User, request: &Request, config: &Config) -> Response {
+    let mut files = match spool::list(&config.spool.dir) {
+        Ok(data) => data,
+        Err(err) => return Response::text(500, &err),
+    };
+    files.sort_by_key(|meta| Reverse(meta.received));
+    let (pending, past): (Vec<FileMeta>, Vec<FileMeta>) = files.into_iter()
+        .filter(|meta| meta.derived_from.is_none())
+        .partition(|meta| meta.is_pending());
+
+    let mut body = format!("<h2>Waiting for the approval ({})</h2>", pending.len());
+    for meta in pending.iter() {
+        let thumbnail = if has_preview(meta, config) {
+            format!("<a href=\"/files/{0}\"><img src=\"/files/{0}/preview/1\" alt=\"preview\"></a>", escape(&meta.id))
+        } else {
+            "".to_string()
+        };
+        body.push_str(&format!("<div class=\"card\">{}<div><pre>{}</pre>{}</div></div>",
+                               thumbnail, escape(&meta.describe()), file_actions(user, meta)));
+    }
+
+    let past: Vec<FileMeta> = past.into_iter().take(PAST_JOBS).collect();
+    body.push_str(&format!("<h2>Past jobs</h2>{}", file_table(&past)));
+    page(user, "Jobs", request, &body)
+}
+
+fn spool_page(user: &User, request: &Request, config: &Config) -> Response {
+    let mut files = match spool::list(&config.spool.dir) {
+        Ok(data) => data,
+        Err(err) => return Response::text(500, &err),
+    };
+    files.sort_by_key(|meta| Reverse(meta.received));
+    let usage = match spool::usage(&config.spool.dir) {
+        Ok((count, size)) => format!("<p>{} files, {} bytes</p>", count, size),
+        Err(err) => format!("<p>{}</p>", escape(&err)),
+    };
+    page(user, "Spool", request, &format!("{}{}", usage, file_table(&files)))
+}
+
+fn file_page(user: &User, request: &Request, config: &Config, id: &str) -> Response {
+    let meta = match spool::read_meta(&config.spool.dir, id) {
+        Ok(data) => data,
+        Err(err) => return Response::text(404, &err),
+    };
+
+    let previews = if has_preview(&meta, config) {
+        let count = meta.pages.unwrap_or(1).min(config.preview.pages).max(1);
+        (1..count + 1).map(|page| format!("<img src=\"/files/{}/preview/{}\" alt=\"page {}\">", escape(&meta.id), page, page))
+            .collect::<Vec<String>>().join(" ")
+    } else {
+        "".to_string()
+    };
+    let summary = match read(spool::file_path(&config.spool.dir, &meta)) {
+        Ok(data) => preview::summary(&meta, &data),
+        Err(err) => format!("Error on reading the file: {}", err),
+    };
+    page(user, &meta.original_name, request,
+         &format!("<p>{}</p><pre>{}</pre><p>{}</p><div>{}</div>", escape(&summary), escape(&meta.describe()),
+                  file_actions(user, &meta), previews))
+}
+
+fn download(config: &Config, id: &str) -> Response {
+    let meta = match spool::read_meta(&config.spool.dir, id) {
+        Ok(data) => data,
+        Err(err) => return Response::text(404, &err),
+    };
+    match read(spool::file_path(&config.spool.dir, &meta)) {
+        Ok(data) => {
+            let name: String = meta.original_name.chars().filter(|c| !c.is_control() && *c != '"' && *c != '\\').collect();
+            Response::new(200, &meta.mime, data)
+                .with_header("Content-Disposition", &format!("attachment; filename=\"{}\"", name))
+        }
+        Err(err) => Response::text(500, &format!("Error on reading the file: {}", err)),
+    }
+}
+
+// Renders the page of the file to PNG, the thumbnails are removed after they are read
+fn preview_image(config: &Config, id: &str, page: &str) -> Response {
+    let meta = match spool::read_meta(&config.spool.dir, id) {
+        Ok(data) => data,
+        Err(err) => return Response::text(404, &err),
+    };
+    let page = match page.parse::<usize>() {
+        Ok(data) if data >= 1 && has_preview(&meta, config) => data,
+        _ => return Response::text(404, "No preview"),
+    };
+
+    let prefix = format!("{}/dashboard-{}-{}", spool::tmp_dir(&config.spool.dir), meta.id, &hash::random_hex()[..8]);
+    let thumbnails = match preview::render(&spool::file_path(&config.spool.dir, &meta), &config.preview, &prefix) {
+        Ok(data) => data,
+        Err(err) => return Response::text(500, &err),
+    };
+    let image = thumbnails.get(page - 1).map(read);
+    for path in thumbnails.iter() {
+        let _ = remove_file(path);
+    }
+
+    match image {
+        Some(Ok(data)) => Response::new(200, "image/png", data).with_header("Cache-Control", "private, max-age=300"),
+        Some(Err(err)) => Response::text(500, &format!("Error on reading the preview: {}", err)),
+        None => Response::text(404, "No such page"),
+    }
+}
+
+fn file_action(user: &User, a_config: &Arc<Mutex<Config>>, id: &str, action: &str,
+               form: &HashMap<String, String>) -> Response {
+    let config = { a_config.lock().unwrap().clone() };
+    let path = format!("/files/{}", id);
+    let result = match action {
+        "print" => {
+            let args: Vec<&str> = field(form, "options").split_whitespace().collect();
+            transform::Options::parse(&args)
+                .and_then(|options| jobs::print(id, &options, Arc::clone(a_config)))
+                .map(|printed| {
+                    audit_web(user, audit::Action::Print, &printed.target());
+                    match printed.job {
+                        Some(job) => format!("The file has been started printing, job {}", job),
+                        None => "The file has been started printing".to_string(),
+                    }
+                })
+        }
+        "reject" => jobs::reject(id, &config).map(|meta| {
+            audit_web(user, audit::Action::RejectFile, &meta.id);
+            if config.spool.delete_rejected && !meta.pinned {
+                return "The file has been rejected and deleted".to_string();
+            }
+            "The file has been rejected".to_string()
+        }),
+        "cancel" => spool::read_meta(&config.spool.dir, id)
+            .and_then(|meta| meta.job.ok_or("The file has no job".to_string()))
+            .and_then(|job| printer::cancel(&job).map(|_| job))
+            .map(|job| {
+                audit_web(user, audit::Action::Cancel, &job);
+                format!("The job {} was canceled", job)
+            }),
+        "delete" => spool::delete(&config.spool.dir, id).map(|_| {
+            audit_web(user, audit::Action::DeleteFile, id);
+            "The file has been deleted".to_string()
+        }),
+        "pin" | "unpin" => spool::read_meta(&config.spool.dir, id).and_then(|mut meta| {
+            meta.pinned = action == "pin";
+            spool::write_meta(&config.spool.dir, &meta)
+        }).map(|_| {
+            audit_web(user, if action == "pin" { audit::Action::PinFile } else { audit::Action::UnpinFile }, id);
+            "Ok".to_string()
+        }),
+        _ => return Response::text(404, "Unknown action"),
+    };
+
+    match result {
+        Ok(message) => match spool::read_meta(&config.spool.dir, id) {
+            Ok(_) => redirect_with(&path, &message),
+            Err(_) => redirect_with("/jobs", &message),
+        },
+        Err(err) => redirect_with(&path, &format!("Error: {}", err)),
+    }
+}
+
+fn printer_page(user: &User, request: &Request, config: &Config) -> Response {
+    let checks = health::checks(config);
+    page(user, "Printer", request,
+         &format!("<h2>{}</h2><pre>{}</pre><p>{}</p><h2>Health</h2><pre>{}</pre>",
+                  escape(&config.printer), escape(&printer::lpstat()),
+                  post_form(user, "/printer/cancel", "<input name=\"job\" placeholder=\"Job ID\">", "Cancel job"),
+                  escape(&health::report(&checks))))
+}
+
+fn cancel_job(user: &User, form: &HashMap<String, String>) -> Response {
+    let job = field(form, "job");
+    if job.is_empty() {
+        return redirect_with("/printer", "No job was specified");
+    }
+    match printer::cancel(job) {
+        Ok(_) => {
+            audit_web(user, audit::Action::Cancel, job);
+            redirect_with("/printer", &format!("The job {} was canceled", job))
+        }
+        Err(err) => redirect_with("/printer", &format!("Error on canceling the job: {}", err)),
+    }
+}
+
+
+// Table of the mail or API tokens with the forms to add, generate and delete them
+fn token_section(user: &User, title: &str, kind: &str, tokens: &[String]) -> String {
+    let mut tokens = tokens.to_vec();
+    tokens.sort();
+    let rows: Vec<String> = tokens.iter().map(|token| {
+        format!("<tr><td>{}</td><td>{}</td></tr>", escape(&hash::mask_token(token)),
+                post_form(user, "/users/deltoken", &format!("{}{}", hidden("kind", kind), hidden("fingerprint", &hash::fingerprint(token))),
+                          "Delete"))
+    }).collect();
+    format!("<h2>{}</h2><table>{}</table><p>{}</p><p>{}</p>", title, rows.join(""),
+            post_form(user, "/users/addtoken", &format!("{}<input name=\"token\" placeholder=\"Token\">", hidden("kind", kind)), "Add token"),
+            post_form(user, "/users/gentoken", &format!("{}<input name=\"name\" placeholder=\"Name\">", hidden("kind", kind)), "Generate token"))
+}
+
+// One-time codes of the upload page, they are shown in full to be handed out
+fn code_section(user: &User, codes: &[String]) -> String {
+    let mut codes = codes.to_vec();
+    codes.sort();
+    let rows: Vec<String> = codes.iter().map(|code| {
+        format!("<tr><td>{}</td><td>{}</td></tr>", escape(code), post_form(user, "/users/delcode", &hidden("code", code), "Delete"))
+    }).collect();
+    format!("<h2>Upload codes</h2><table>{}</table><p>{}</p>", rows.join(""), post_form(user, "/users/gencode", "", "Generate code"))
+}
+
+fn users_page(user: &User, request: &Request, config: &Config, users_table: &database::UsersTable) -> Response {
+    let users = users_table.vectorize();
+    let mut ids = users.users.clone();
+    ids.sort();
+
+    let user_rows: Vec<String> = ids.iter().map(|id| {
+        let id = format!("{}", id);
+        format!("<tr><td>{}{}</td><td>{} {}</td></tr>", escape(&id),
+                if users.admin.to_string() == id { " (admin)" } else { "" },
+                post_form(user, "/users/delete", &hidden("user", &id), "Delete"),
+                post_form(user, "/users/admin", &hidden("user", &id), "Make admin"))
+    }).collect();
+    let account_rows: Vec<String> = config.dashboard.accounts.iter().map(|account| {
+        let role = if account.role == Role::Admin { Role::Operator } else { Role::Admin };
+        format!("<tr><td>{}</td><td>{:?}</td><td>{} {}</td></tr>", escape(&account.name), account.role,
+                post_form(user, "/users/role", &format!("{}{}", hidden("account", &account.name), hidden("role", &format!("{:?}", role))),
+                          &format!("Make {:?}", role)),
+                post_form(user, "/users/delaccount", &hidden("account", &account.name), "Delete"))
+    }).collect();
+
+    let body = format!("<h2>Telegram users</h2><table>{}</table><p>{}</p>{}{}{}\
+                        <h2>Dashboard accounts</h2><table>{}</table><p>{}</p>",
+                       user_rows.join(""), post_form(user, "/users/add", "<input name=\"user\" placeholder=\"Telegram ID\">", "Add user"),
+                       token_section(user, "Mail tokens", "mail", &users.mail_tokens),
+                       token_section(user, "API tokens", "api", &users.api_tokens),
+                       code_section(user, &users.upload_codes),
+                       account_rows.join(""),
+                       post_form(user, "/users/addaccount",
+                                 "<input name=\"account\" placeholder=\"Name\"> <input name=\"password\" type=\"password\" placeholder=\"Password\"> \
+                                  <select name=\"role\"><option>Operator</option><option>Admin</option></select>", "Add account"));
+    page(user, "Users", request, &body)
+}
+
+// Changes the dashboard accounts in the config, at least one admin has to stay
+fn change_accounts<F: FnOnce(&mut Vec<DashboardAccount>) -> Result<(), String>>(a_config: &Arc<Mutex<Config>>, change: F) -> Result<(), String> {
+    let mut config = a_config.lock().unwrap();
+    let mut accounts = config.dashboard.accounts.clone();
+    change(&mut accounts)?;
+    if !accounts.iter().any(|account| account.role == Role::Admin) {
+        return Err("At least one admin account is required".to_string());
+    }
+    let mut updated = config.clone();
+    updated.dashboard.accounts = accounts;
+    config::write_config(&updated)?;
+    *config = updated;
+    Ok(())
+}
+
+fn users_action(user: &User, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>,
+                action: &str, form: &HashMap<String, String>) -> Response {
+    let telegram_user = field(form, "user").parse::<i64>();
+    let account = field(form, "account").to_string();
+
+    let result: Result<String, String> = match action {
+        "add" | "delete" | "admin" => match telegram_user {
+            Ok(id) => {
+                let mut users_table = a_users_table.lock().unwrap();
+                let audited = match action {
+                    "add" => {
+                        users_table.add_user(id);
+                        audit::Action::AddUser
+                    }
+                    "delete" if id == users_table.get_admin() => return redirect_with("/users", "The admin can't be deleted"),
+                    "delete" => {
+                        users_table.del_user(id);
+                        audit::Action::DelUser
+                    }
+                    _ => {
+                        users_table.add_user(id);
+                        users_table.set_admin(id);
+                        audit::Action::SetAdmin
+                    }
+                };
+                database::write_database(&users_table).map(|_| {
+                    audit_web(user, audited, &format!("{}", id));
+                    "Ok".to_string()
+                })
+            }
+            Err(_) => Err("Wrong Telegram ID".to_string()),
+        },
+        "addtoken" | "gentoken" | "deltoken" => {
+            let token = match action {
+                "gentoken" if field(form, "name").is_empty() => return redirect_with("/users", "No name was specified"),
+                "gentoken" => hash::generate_token(field(form, "name").to_string()),
+                // the page has only the fingerprints of the tokens, so the tokens aren't in its source
+                "deltoken" => {
+                    let users = a_users_table.lock().unwrap().vectorize();
+                    let tokens = if field(form, "kind") == "api" { users.api_tokens } else { users.mail_tokens };
+                    match tokens.into_iter().find(|token| hash::fingerprint(token) == field(form, "fingerprint")) {
+                        Some(data) => data,
+                        None => return redirect_with("/users", "Unknown token"),
+                    }
+                }
+                _ => field(form, "token").to_string(),
+            };
+            if token.is_empty() {
+                return redirect_with("/users", "No token was specified");
+            }
+            let mut users_table = a_users_table.lock().unwrap();
+            let audited = match (action, field(form, "kind") == "api") {
+                ("deltoken", true) => {
+                    users_table.del_api_token(token.clone());
+                    audit::Action::DelApiToken
+                }
+                ("deltoken", false) => {
+                    users_table.del_token(token.clone());
+                    audit::Action::DelToken
+                }
+                (_, true) => {
+                    users_table.add_api_token(token.clone());
+                    audit::Action::AddApiToken
+                }
+                (_, false) => {
+                    users_table.add_token(token.clone());
+                    audit::Action::AddToken
+                }
+            };
+            database::write_database(&users_table).map(|_| {
+                audit_web(user, audited, &hash::mask_token(&token));
+                match action {
+                    "gentoken" => format!("New token: {}", token),
+                    _ => "Ok".to_string(),
+                }
+            })
+        }
+        "gencode" | "delcode" => {
+            let mut users_table = a_users_table.lock().unwrap();
+            let (code, audited) = match action {
+                "gencode" => {
+                    let code = hash::generate_code();
+                    users_table.add_code(code.clone());
+                    (code, audit::Action::GenCode)
+                }
+                _ => {
+                    let code = field(form, "code").to_string();
+                    users_table.del_code(code.clone());
+                    (code, audit::Action::DelCode)
+                }
+            };
+            database::write_database(&users_table).map(|_| {
+                audit_web(user, audited, &hash::mask_code(&code));
+                match action {
+                    "gencode" => format!("New code: {}", code),
+                    _ => "Ok".to_string(),
+                }
+            })
+        }
+        "addaccount" => {
+            let password = form.get("password").map(|value| value.as_str()).unwrap_or("");
+            let role = if field(form, "role") == "Admin" { Role::Admin } else { Role::Operator };
+            if account.is_empty() || password.is_empty() {
+                return redirect_with("/users", "Name and password are required");
+            }
+            change_accounts(a_config, |accounts| {
+                if accounts.iter().any(|item| item.name == account) {
+                    return Err(format!("Account {} already exists", account));
+                }
+                accounts.push(DashboardAccount { name: account.clone(), password: hash::hash_password(password), role });
+                Ok(())
+            }).map(|_| {
+                audit_web(user, audit::Action::EditAccount, &format!("add {} {:?}", account, role));
+                "Ok".to_string()
+            })
+        }
+        "role" => {
+            let role = if field(form, "role") == "Admin" { Role::Admin } else { Role::Operator };
+            change_accounts(a_config, |accounts| {
+                match accounts.iter_mut().find(|item| item.name == account) {
+                    Some(item) => {
+                        item.role = role;
+                        Ok(())
+                    }
+                    None => Err(format!("No account {}", account)),
+                }
+            }).map(|_| {
+                audit_web(user, audit::Action::EditAccount, &format!("role {} {:?}", account, role));
+                "Ok".to_string()
+            })
+        }
+        "delaccount" => {
+            change_accounts(a_config, |accounts| {
+                accounts.retain(|item| item.name != account);
+                Ok(())
+            }).map(|_| {
+                audit_web(user, audit::Action::EditAccount, &format!("delete {}", account));
+                "Ok".to_string()
+            })
+        }
+        _ => return Response::text(404, "Unknown action"),
+    };
+
+    match result {
+        Ok(message) => redirect_with("/users", &message),
+        Err(err) => redirect_with("/users", &format!("Error: {}", err)),
+    }
+}
+
+
+fn config_page(user: &User, request: &Request, config: &Config) -> Response {
+    let text = match toml::to_string(config) {
+        Ok(data) => data,
+        Err(err) => return Response::text(500, &format!("Error on serializing the config: {}", err)),
+    };
+    page(user, "Config", request,
+         &format!("<p>Changes of the addresses, the token and the IMAP server are applied after the restart.</p>\
+                   <form method=\"post\" action=\"/config\">{}<textarea name=\"config\">{}</textarea>\
+                   <p><button>Save</button></p></form>", hidden("csrf", &user.csrf), escape(&text)))
+}
+
+fn save_config(user: &User, a_config: &Arc<Mutex<Config>>, form: &HashMap<String, String>) -> Response {
+    let updated: Config = match toml::from_str(field(form, "config")) {
+        Ok(data) => data,
+        Err(err) => return redirect_with("/config", &format!("Error in the config: {}", err)),
+    };
+    if !updated.dashboard.accounts.iter().any(|account| account.role == Role::Admin) {
+        return redirect_with("/config", "At least one admin account is required");
+    }
+
+    let mut config = a_config.lock().unwrap();
+    match config::write_config(&updated) {
+        Ok(_) => {
+            *config = updated;
+            audit_web(user, audit::Action::EditConfig, "config.toml");
+            redirect_with("/config", "The config has been saved")
+        }
+        Err(err) => redirect_with("/config", &format!("Error on writing the config: {}", err)),
+    }
+}
+
+fn audit_page(user: &User, request: &Request) -> Response {
+    let query = request.query.get("q").map(|value| value.as_str()).unwrap_or("100");
+    let entries = match audit::read_entries() {
+        Ok(data) => audit::filter(data, query),
+        Err(err) => return Response::text(500, &err),
+    };
+    let lines: Vec<String> = entries.iter().rev().map(|entry| escape(&entry.describe())).collect();
+    page(user, "Audit", request,
+         &format!("<form method=\"get\" action=\"/audit\"><input name=\"q\" value=\"{}\" placeholder=\"count, YYYY-MM-DD or user\">\
+                   <button>Filter</button></form><pre>{}</pre>", escape(query), lines.join("\n")))
+}
+
+
+fn handle(request: &Request, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    let config = { a_config.lock().unwrap().clone() };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/login") => return login_page(""),
+        ("POST", "/login") => return login(request, &config),
+        _ => (),
+    };
+
+    let user = match authenticate(request, &config) {
+        Some(data) => data,
+        None => return Response::redirect("/login"),
+    };
+    let form = request.form();
+    if request.method == "POST" && field(&form, "csrf") != user.csrf {
+        return Response::text(403, "The form has expired, reload the page");
+    }
+
+    let parts: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let admin_only = matches!(parts[0], "users" | "config" | "audit");
+    if admin_only && user.role != Role::Admin {
+        audit_web(&user, audit::Action::Unauthorized, &request.path);
+        return html(403, "Forbidden", "<h1>Only admins can open this page</h1><a href=\"/jobs\">Jobs</a>");
+    }
+
+    match (request.method.as_str(), parts.as_slice()) {
+        ("GET", [""]) => Response::redirect("/jobs"),
+        ("POST", ["logout"]) => logout(request),
+        ("GET", ["jobs"]) => jobs_page(&user, request, &config),
+        ("GET", ["spool"]) => spool_page(&user, request, &config),
+        ("GET", ["files", id]) => file_page(&user, request, &config, id),
+        ("GET", ["files", id, "download"]) => download(&config, id),
+        ("GET", ["files", id, "preview", page]) => preview_image(&config, id, page),
+        ("POST", ["files", id, action]) => file_action(&user, a_config, id, action, &form),
+        ("GET", ["printer"]) => printer_page(&user, request, &config),
+        ("POST", ["printer", "cancel"]) => cancel_job(&user, &form),
+        ("GET", ["users"]) => {
+            let users_table = { a_users_table.lock().unwrap().clone() };
+            users_page(&user, request, &config, &users_table)
+        }
+        ("POST", ["users", action]) => users_action(&user, a_config, a_users_table, action, &form),
+        ("GET", ["config"]) => config_page(&user, request, &config),
+        ("POST", ["config"]) => save_config(&user, a_config, &form),
+        ("GET", ["audit"]) => audit_page(&user, request),
+        _ => Response::text(404, "Not found"),
+    }
+}
+
+/// Runs the web dashboard, if it is enabled in the `[dashboard]` section
+///
+/// The dashboard shows the jobs with the previews, the spool and the printer status, admins also manage
+/// the users, tokens, dashboard accounts and the config. It changes the same spool, `users.toml`
+/// and `config.toml` as the bots.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let users_table = Arc::new(Mutex::new(read_users().unwrap()));
+/// let dashboard = thread::spawn(move || {
+///        run_server(Arc::clone(&config), Arc::clone(&users_table));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if !config.dashboard.enabled {
+        return;
+    }
+    if config.dashboard.accounts.is_empty() {
+        warn!("The dashboard has no accounts, add them with `teleprint --hash-password`");
+    }
+
+    let handler: Handler = Arc::new(move |request: &Request| handle(request, &a_config, &a_users_table));
+    match http::serve(&config.dashboard.address, MAX_BODY, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the dashboard: {}", err),
+    };
+}
@@ -5,23 +5,31 @@ use std::iter::FromIterator;
 
 use io_tools;
 
-/// Structure, that contains admin ID, vector of users and vector of mail tokens.
+/// Structure, that contains admin ID, vector of users and vectors of mail and API tokens
+/// and one-time upload codes.
 /// Usable with TOML
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Users {
     pub users: Vec<i64>,
     pub admin: i64,
     pub mail_tokens: Vec<String>,
+    #[serde(default)]
+    pub api_tokens: Vec<String>,
+    /// One-time codes of the upload page, each one can be used for a single file
+    #[serde(default)]
+    pub upload_codes: Vec<String>,
 }
 
 
-/// Structure, that contains admin ID and HashSets of users and mail tokens.
+/// Structure, that contains admin ID and HashSets of users, mail and API tokens and upload codes.
 /// Usable on working with users/tokens.
 #[derive(Clone, Debug)]
 pub struct UsersTable {
     users: HashSet<i64>,
     admin: i64,
     mail_tokens: HashSet<String>,
+    api_tokens: HashSet<String>,
+    upload_codes: HashSet<String>,
 }
 
 
@@ -74,6 +82,40 @@ impl UsersTable {
         self.mail_tokens.remove(&token);
     }
 
+    /// Adds token of the HTTP API
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let mut users = read_users().unwrap();
+    /// users.add_api_token("ci5f8e...".to_string());
+    /// ```
+    pub fn add_api_token(&mut self, token: String) {
+        self.api_tokens.insert(token);
+    }
+
+    /// Deletes token of the HTTP API
+    pub fn del_api_token(&mut self, token: String) {
+        self.api_tokens.remove(&token);
+    }
+
+    /// Adds one-time code of the upload page
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let mut users = read_users().unwrap();
+    /// users.add_code(generate_code());
+    /// ```
+    pub fn add_code(&mut self, code: String) {
+        self.upload_codes.insert(code);
+    }
+
+    /// Deletes one-time code of the upload page
+    pub fn del_code(&mut self, code: String) {
+        self.upload_codes.remove(&code);
+    }
+
     /// Checks whether the Telegram user is authorized
     ///
     /// # Examples
@@ -101,6 +143,25 @@ impl UsersTable {
         self.mail_tokens.contains(&token)
     }
 
+    /// Checks whether the token of the HTTP API is authorized
+    pub fn check_api_token(&self, token: &str) -> bool {
+        self.api_tokens.contains(token)
+    }
+
+    /// Checks the one-time code of the upload page and removes it, so it can't be used again
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let mut users = read_users().unwrap();
+    /// users.add_code("K7QM2XHP".to_string());
+    /// let accessed = users.use_code("K7QM2XHP"); // true
+    /// let accessed = users.use_code("K7QM2XHP"); // false
+    /// ```
+    pub fn use_code(&mut self, code: &str) -> bool {
+        self.upload_codes.remove(code)
+    }
+
     /// Sets admin ID (Telegram)
     pub fn set_admin(&mut self, admin_id: i64) {
         self.admin = admin_id;
@@ -116,7 +177,8 @@ impl UsersTable {
     /// println!("{:?}", users.vectorize()); // You will see vectors, usable for TOML
     /// ```
     pub fn vectorize(&self) -> Users {
-        let mut users = Users { users: vec![], admin: self.admin, mail_tokens: vec![] };
+        let mut users = Users { users: vec![], admin: self.admin, mail_tokens: vec![], api_tokens: vec![],
+                                upload_codes: vec![] };
         for user in &self.users {
             users.users.push(*user);
         }
@@ -125,6 +187,14 @@ impl UsersTable {
             users.mail_tokens.push(token.to_string());
         }
 
+        for token in &self.api_tokens {
+            users.api_tokens.push(token.to_string());
+        }
+
+        for code in &self.upload_codes {
+            users.upload_codes.push(code.to_string());
+        }
+
         users
     }
 
@@ -149,11 +219,13 @@ fn hashify<T>(data: Vec<T>) -> HashSet<T> where T: std::hash::Hash + std::clone:
 /// ```
 pub fn read_users() -> Result<UsersTable, String> {
     if !io_tools::exists("users.toml") {
-        println!("No `users.toml` file, creating it...");
+        info!("No `users.toml` file, creating it...");
         match write_database(&UsersTable {
             users: hashify(vec![]),
             admin: 0,
             mail_tokens: hashify(vec![]),
+            api_tokens: hashify(vec![]),
+            upload_codes: hashify(vec![]),
         }) {
             Ok(_) => (),
             Err(err) => return Err(format!("{:?}", err)),
@@ -163,7 +235,7 @@ pub fn read_users() -> Result<UsersTable, String> {
     let users: Users = match toml::from_str(&users_str) {
         Ok(value) => value,
         Err(err) => {
-            eprintln!("Something goes wrong while reading the users: {}", err);
+            error!("Something goes wrong while reading the users: {}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -172,6 +244,8 @@ pub fn read_users() -> Result<UsersTable, String> {
         users: hashify(users.users),
         admin: users.admin,
         mail_tokens: hashify(users.mail_tokens),
+        api_tokens: hashify(users.api_tokens),
+        upload_codes: hashify(users.upload_codes),
     };
     Ok(user_table)
 }
@@ -189,9 +263,11 @@ pub fn init_db(m_admin: i64) -> Result<(), String> {
         users: hashify(vec![m_admin]),
         admin: m_admin,
         mail_tokens: hashify(vec![]),
+        api_tokens: hashify(vec![]),
+        upload_codes: hashify(vec![]),
     }) {
         Ok(_) => Ok(()),
-        Err(err) => return Err(format!("{:?}", err)),
+        Err(err) => Err(format!("{:?}", err)),
     }
 }
 
@@ -211,7 +287,7 @@ pub fn write_database(users: &UsersTable) -> Result<(), String> {
     let users_str = match toml::to_string(&users.vectorize()) {
         Ok(value) => value,
         Err(err) => {
-            println!("Something went wrong while parsing the config: {}", err);
+            error!("Something went wrong while parsing the config: {}", err);
             panic!("{}", err);
         }
     };
@@ -219,10 +295,10 @@ pub fn write_database(users: &UsersTable) -> Result<(), String> {
 
     match io_tools::write_to_file("users.toml",
                                   users_str) {
-        Ok(_) => return Ok(()),
+        Ok(_) => Ok(()),
         Err(err) => {
-            println!("An error occured while writing to the config: {}", err);
-            return Err(format!("{:?}", err));
+            error!("An error occured while writing to the config: {}", err);
+            Err(format!("{:?}", err))
         }
-    };
+    }
 }
@@ -1,65 +1,461 @@
+extern crate futures;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
+extern crate rand;
 extern crate reqwest;
-extern crate thread_tryjoin;
+extern crate sha2;
+extern crate tokio_core;
 
-use std::io::Read;
-use std::sync::{Arc, Mutex};
+use std::fs::{File, remove_file};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::vec;
 
-use self::thread_tryjoin::TryJoinHandle;
+use self::futures::future::{self, Either, FutureResult};
+use self::futures::{Future, Stream};
+use self::hyper::client::HttpConnector;
+use self::hyper::client::connect::dns::{Name, Resolve};
+use self::hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use self::hyper::{Body, Client, Request, StatusCode};
+use self::hyper_tls::HttpsConnector;
+use self::sha2::{Digest, Sha256};
+use self::tokio_core::reactor::{Core, Timeout};
 
-use io_tools::write_bytes_to_file;
-use hash;
+use config::{Config, DownloadConfig};
+use metrics;
+use spool;
+use supervisor;
 
-/// Downloads (pdf) file from url, returns filename if Ok().
+const MAX_REDIRECTS: usize = 10;
+
+/// File, downloaded to the temporary location
+#[derive(Debug)]
+pub struct Download {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: Option<String>,
+}
+
+enum Failure {
+    Transient(String),
+    Permanent(String),
+}
+
+// Resolves the hosts of the downloads to the public addresses only. The connection is made to the addresses,
+// which were checked, so the host can't switch to the local network after `check_url` has passed it
+#[derive(Clone)]
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    type Addrs = vec::IntoIter<IpAddr>;
+    type Future = FutureResult<Self::Addrs, io::Error>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        future::result(public_addresses(name.as_str())
+            .map(|addresses| addresses.into_iter())
+            .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, err)))
+    }
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector<PublicResolver>>>;
+
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+// Runs the future on the loop, it fails if it doesn't finish before the deadline
+fn run_until<F: Future<Error = Failure>>(core: &mut Core, work: F, deadline: Instant) -> Result<F::Item, Failure> {
+    let now = Instant::now();
+    let left = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+    let timeout = match Timeout::new(left, &core.handle()) {
+        Ok(data) => data,
+        Err(err) => return Err(Failure::Permanent(format!("Error on setting the timeout: {}", err))),
+    };
+    match core.run(work.select2(timeout)) {
+        Ok(Either::A((item, _))) => Ok(item),
+        Ok(Either::B(_)) => Err(Failure::Transient("Error on downloading the file: timed out".to_string())),
+        Err(Either::A((err, _))) => Err(err),
+        Err(Either::B((err, _))) => Err(Failure::Permanent(format!("Error on waiting for the download: {}", err))),
+    }
+}
+
+// Requests the file and follows the redirects, which `follow` permits
+//
+// hyper's errors don't include the URL, so the bot token in the links of Telegram files isn't shown
+fn request(core: &mut Core, client: &HttpClient, url: &str, follow: &dyn Fn(&reqwest::Url) -> bool,
+           deadline: Instant) -> Result<hyper::Response<Body>, Failure> {
+    let mut url = match reqwest::Url::parse(url) {
+        Ok(data) => data,
+        Err(err) => return Err(Failure::Permanent(format!("Bad link: {}", err))),
+    };
+    let mut redirects = 0;
+    loop {
+        let request = match Request::get(url.as_str()).body(Body::empty()) {
+            Ok(data) => data,
+            Err(err) => return Err(Failure::Permanent(format!("Bad link: {}", err))),
+        };
+        let resp = run_until(core, client.request(request)
+            .map_err(|err| Failure::Transient(format!("Error on requesting the file: {}", err))), deadline)?;
+        if !resp.status().is_redirection() {
+            return Ok(resp);
+        }
+
+        let next = match resp.headers().get(LOCATION).and_then(|value| value.to_str().ok())
+            .and_then(|location| url.join(location).ok()) {
+            Some(data) => data,
+            None => return Ok(resp),
+        };
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(Failure::Permanent("Too many redirects".to_string()));
+        }
+        if !follow(&next) {
+            return Err(Failure::Permanent(format!("Redirect to {} is not allowed", next.host_str().unwrap_or(""))));
+        }
+        url = next;
+    }
+}
+
+fn attempt(core: &mut Core, client: &HttpClient, url: &str, follow: &dyn Fn(&reqwest::Url) -> bool,
+           config: &DownloadConfig, path: &str) -> Result<Download, Failure> {
+    let max_size = config.max_size * 1024 * 1024;
+    let deadline = Instant::now() + Duration::from_secs(config.read_timeout);
+
+    let resp = request(core, client, url, follow, deadline)?;
+    let status = resp.status();
+    if !status.is_success() {
+        let message = format!("Server responded with {}", status);
+        return Err(if is_transient(status) { Failure::Transient(message) } else { Failure::Permanent(message) });
+    }
+
+    let header = |name| resp.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+    let length = header(CONTENT_LENGTH).and_then(|value| value.trim().parse::<u64>().ok());
+    let content_type = header(CONTENT_TYPE);
+    if let Some(length) = length {
+        if max_size > 0 && length > max_size {
+            return Err(Failure::Permanent(format!("File is too big: {} bytes, limit is {} MB", length, config.max_size)));
+        }
+    }
+
+    let mut file = match File::create(path) {
+        Ok(data) => data,
+        Err(err) => return Err(Failure::Permanent(format!("Error on creating the temporary file: {}", err))),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    {
+        let saving = resp.into_body()
+            .map_err(|err| Failure::Transient(format!("Error on downloading the file: {}", err)))
+            .for_each(|chunk| {
+                size += chunk.len() as u64;
+                if max_size > 0 && size > max_size {
+                    return Err(Failure::Permanent(format!("File is too big: more than {} MB", config.max_size)));
+                }
+
+                hasher.input(&chunk);
+                file.write_all(&chunk).map_err(|err| Failure::Permanent(format!("Error on writing to file: {}", err)))
+            });
+        run_until(core, saving, deadline)?;
+    }
+
+    if let Some(length) = length {
+        if length != size {
+            return Err(Failure::Transient(format!("Got {} bytes instead of {}", size, length)));
+        }
+    }
+
+    Ok(Download {
+        path: path.to_string(),
+        size,
+        sha256: format!("{:x}", hasher.result()),
+        content_type,
+    })
+}
+
+
+/// Downloads file from url to the temporary file in `tmp_dir`, returns `Download` if Ok().
 ///
-/// File is saved to the <SHA1 of the file>.pdf, such as "4e24631bfb9aaa3fbf8b4dc9b549de1dec0c8b4a.123456"
+/// The file is streamed to the disk and hashed on the fly. Download is aborted if the server
+/// responds with an error status or the file is bigger than `max_size`. Connection errors, timeouts
+/// and server errors are retried `retries` times with exponential backoff.
 ///
 /// # Examples
 ///
 /// ```rust
-/// let result = download_from_url("http://edu.ifmo.ru/file/subspec/3143/up_09.03.04_nip.pdf", Some(123456));
+/// let result = download_from_url("http://edu.ifmo.ru/file/subspec/3143/up_09.03.04_nip.pdf",
+///                                &config.download, "spool/tmp");
 /// match result {
-///     Ok(filename) => println("{}", filename), // 28f158d186263820686c8341d510595ace3b27ce.123456
+///     Ok(download) => println("{} {}", download.path, download.size), // spool/tmp/9b7e31f2a4c0d615 1234567
 ///     Err(err) => eprintln("{}", err),
 /// };
 /// ```
-pub fn download_from_url(url: &str, user_id: Option<i64>) -> Result<String, String> {
-    let content = Arc::new(Mutex::new(Vec::new()));
+pub fn download_from_url(url: &str, config: &DownloadConfig, tmp_dir: &str) -> Result<Download, String> {
+    let client = build_client(config)?;
+    download(&client, url, &|next| check_address(next).is_ok(), config, tmp_dir)
+}
 
-    let extension = match user_id {
-        Some(v) => format!("{}", v),
-        None => "pdf".to_string(),
+fn build_client(config: &DownloadConfig) -> Result<HttpClient, String> {
+    let mut http = HttpConnector::new_with_resolver(PublicResolver);
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(Duration::from_secs(config.connect_timeout)));
+    match native_tls::TlsConnector::new() {
+        Ok(tls) => Ok(Client::builder().build(HttpsConnector::from((http, tls)))),
+        Err(err) => Err(format!("Error on creating the HTTP client: {}", err)),
+    }
+}
+
+fn download(client: &HttpClient, url: &str, follow: &dyn Fn(&reqwest::Url) -> bool, config: &DownloadConfig,
+            tmp_dir: &str) -> Result<Download, String> {
+    if supervisor::shutting_down() {
+        return Err("Teleprint is shutting down, please try again in a minute".to_string());
+    }
+    let _task = supervisor::task();
+    let mut core = match Core::new() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on creating the event loop: {}", err)),
     };
+    let path = format!("{}/{:016x}", tmp_dir, rand::random::<u64>());
+    let started = Instant::now();
+    let mut backoff = config.backoff;
+    let mut tries = 0;
+    loop {
+        let error = match attempt(&mut core, client, url, follow, config, &path) {
+            Ok(download) => {
+                metrics::inc(metrics::DOWNLOADS, &[("result", "ok")]);
+                metrics::add(metrics::DOWNLOAD_BYTES, &[], download.size as f64);
+                metrics::observe(metrics::DOWNLOAD_DURATION, &[], started.elapsed());
+                return Ok(download);
+            }
+            Err(Failure::Permanent(err)) => {
+                metrics::inc(metrics::DOWNLOADS, &[("result", "error")]);
+                let _ = remove_file(&path);
+                return Err(err);
+            }
+            Err(Failure::Transient(err)) => err,
+        };
+        let _ = remove_file(&path);
 
-    let url_copy = url.to_string();
-    let content_a = Arc::clone(&content);
+        tries += 1;
+        if tries > config.retries {
+            metrics::inc(metrics::DOWNLOADS, &[("result", "error")]);
+            return Err(format!("{} (after {} attempts)", error, tries));
+        }
 
-    let downloader = thread::spawn(move || {
-        let mut resp = match reqwest::get(&url_copy) {
-            Ok(data) => data,
-            Err(err) => return Err(format!("{:?}", err)),
-        };
+        warn!("{}, retrying in {} ms", error, backoff);
+        thread::sleep(Duration::from_millis(backoff));
+        backoff *= 2;
+    }
+}
 
-        match resp.read_to_end(&mut content_a.lock().unwrap()) {
-            Ok(_) => println!("Downloaded file"),
-            Err(err) => return Err(format!("Error on downloading to file: {}", err))
-        };
-        Ok("Ok downloaded")
-    });
 
-    if downloader.try_timed_join(Duration::from_secs(10)).is_err() {
-        return Err("Downloading is too slow, or there was an error".to_string());
+fn matches_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.').to_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn domain_allowed(host: &str, allowed: &[String], denied: &[String]) -> bool {
+    let host = host.to_lowercase();
+    if denied.iter().any(|domain| matches_domain(&host, domain)) {
+        return false;
+    }
+    allowed.is_empty() || allowed.iter().any(|domain| matches_domain(&host, domain))
+}
+
+fn internal_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_multicast() ||
+        // "this network" 0.0.0.0/8 and the reserved 240.0.0.0/4 with the broadcast address
+        octets[0] == 0 || octets[0] >= 240 ||
+        // shared address space of the carrier-grade NAT, 100.64.0.0/10
+        (octets[0] == 100 && octets[1] & 0xc0 == 64) ||
+        // benchmarking networks 198.18.0.0/15
+        (octets[0] == 198 && octets[1] & 0xfe == 18)
+}
+
+fn internal_address(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => internal_v4(ip),
+        IpAddr::V6(ref ip) => {
+            let segments = ip.segments();
+            // IPv4 addresses, translated by NAT64, 64:ff9b::/96
+            let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() ||
+                // unique local fc00::/7 and link-local fe80::/10
+                segments[0] & 0xfe00 == 0xfc00 || segments[0] & 0xffc0 == 0xfe80 ||
+                ip.to_ipv4().is_some_and(|ip| internal_v4(&ip)) ||
+                (nat64 && internal_v4(&Ipv4Addr::new((segments[6] >> 8) as u8, segments[6] as u8,
+                                                     (segments[7] >> 8) as u8, segments[7] as u8)))
+        }
+    }
+}
+
+// Resolves the host, the local network addresses are refused, so the users can't make the bot fetch
+// the dashboard, the API, the metrics or the cloud metadata
+fn public_addresses(host: &str) -> Result<Vec<IpAddr>, String> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<IpAddr> = match (host, 0).to_socket_addrs() {
+        Ok(data) => data.map(|address| address.ip()).collect(),
+        Err(err) => return Err(format!("Can't resolve {}: {}", host, err)),
+    };
+    if addresses.is_empty() {
+        return Err(format!("Can't resolve {}", host));
+    }
+    if addresses.iter().any(internal_address) {
+        return Err(format!("Downloading from the local network is not allowed: {}", host));
+    }
+    Ok(addresses)
+}
+
+// The addresses in the links are checked here, the names are checked once more on connecting by `PublicResolver`
+fn check_address(url: &reqwest::Url) -> Result<(), String> {
+    public_addresses(url.host_str().unwrap_or("")).map(|_| ())
+}
+
+/// Checks the link, sent by the user, returns parsed URL on Ok()
+///
+/// Only `http` and `https` links to the domains, allowed by `allowed_domains` and `denied_domains`, pass.
+/// Subdomains are matched too, so `example.com` covers `files.example.com`. Links to the loopback,
+/// private, link-local, multicast and reserved addresses are refused, whatever the domain. The download
+/// connects only to the checked addresses, so the domain can't be switched to the local network later.
+pub fn check_url(url: &str, config: &DownloadConfig) -> Result<reqwest::Url, String> {
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Bad link {}: {}", url, err)),
+    };
+
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(format!("Only http and https links are supported: {}", url));
+    }
+
+    let host = match parsed.host_str() {
+        Some(data) => data.to_string(),
+        None => return Err(format!("No domain in the link: {}", url)),
+    };
+
+    if !domain_allowed(&host, &config.allowed_domains, &config.denied_domains) {
+        return Err(format!("Downloading from {} is not allowed", host));
     }
+    check_address(&parsed)?;
 
-    let cont = content.lock().unwrap().clone();
-    let hashsum = hash::hash_data(&cont);
+    Ok(parsed)
+}
 
-    let filename = format!("{}.{}.pdf", hashsum, extension);
+/// Returns true if the text is the link, which should be downloaded
+pub fn is_link(text: &str) -> bool {
+    text.starts_with("http://") || text.starts_with("https://")
+}
 
-    match write_bytes_to_file(&filename, cont) {
-        Ok(_) => Ok(filename.clone()),
-        Err(err) => Err(format!("Error on writing to file: {}", err)),
+fn file_type(download: &Download) -> String {
+    let declared = match download.content_type {
+        Some(ref value) => value.split(';').next().unwrap_or("").trim().to_lowercase(),
+        None => "".to_string(),
+    };
+    if !declared.is_empty() && declared != "application/octet-stream" {
+        return declared;
+    }
+
+    let mut head = [0u8; 5];
+    match File::open(&download.path).and_then(|mut file| file.read_exact(&mut head)) {
+        Ok(_) if &head == b"%PDF-" => "application/pdf".to_string(),
+        _ => "application/octet-stream".to_string(),
     }
 }
 
+/// Downloads the file from the link, sent by the user, and stores it to the spool
+///
+/// The link is checked by `check_url`, redirects are followed only to the allowed public addresses,
+/// and the file is stored only if its type is in `allowed_types`. The link is recorded as the source of the file.
+///
+/// # Examples
+///
+/// ```rust
+/// let meta = fetch_to_spool("https://example.com/report.pdf", &config, spool::Channel::Telegram, "123456").unwrap();
+/// println!("{}", meta.describe());
+/// ```
+pub fn fetch_to_spool(url: &str, config: &Config, channel: spool::Channel, submitter: &str) -> Result<spool::FileMeta, String> {
+    let parsed = check_url(url, &config.download)?;
+
+    let (allowed, denied) = (&config.download.allowed_domains, &config.download.denied_domains);
+    let follow = |next: &reqwest::Url| match next.host_str() {
+        Some(host) => (next.scheme() == "http" || next.scheme() == "https") && domain_allowed(host, allowed, denied) &&
+            check_address(next).is_ok(),
+        None => false,
+    };
+    let client = build_client(&config.download)?;
+
+    spool::init(&config.spool.dir)?;
+    let download = download(&client, parsed.as_str(), &follow, &config.download, &spool::tmp_dir(&config.spool.dir))?;
+
+    let mime = file_type(&download);
+    if !config.download.allowed_types.contains(&mime) {
+        let _ = remove_file(&download.path);
+        return Err(format!("Files of type {} are not allowed", mime));
+    }
+
+    let name = match Path::new(parsed.path()).file_name() {
+        Some(data) => String::from(data.to_string_lossy()),
+        None => "download".to_string(),
+    };
+
+    spool::store_file(&config.spool.dir, &download.path, &download.sha256, download.size, &name, &mime,
+                      channel, submitter, Some(url))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(address: &str) -> bool {
+        internal_address(&address.parse().unwrap())
+    }
+
+    #[test]
+    fn blocks_local_ranges() {
+        for address in &["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+                         "0.1.2.3", "100.64.0.1", "100.127.255.255", "198.18.0.1", "198.19.255.255", "224.0.0.1",
+                         "240.0.0.1", "255.255.255.255", "::", "::1", "fc00::1", "fd12::1", "fe80::1", "ff02::1",
+                         "::ffff:127.0.0.1", "::ffff:10.0.0.1", "64:ff9b::a9fe:a9fe"] {
+            assert!(internal(address), "{} is not blocked", address);
+        }
+    }
+
+    #[test]
+    fn allows_public_ranges() {
+        for address in &["1.1.1.1", "8.8.8.8", "100.63.255.255", "100.128.0.1", "172.32.0.1", "198.17.0.1",
+                         "198.20.0.1", "223.255.255.255", "2606:4700::1111", "::ffff:1.1.1.1", "64:ff9b::808:808"] {
+            assert!(!internal(address), "{} is blocked", address);
+        }
+    }
+
+    #[test]
+    fn checks_links() {
+        let config = DownloadConfig {
+            denied_domains: vec!["example.com".to_string()],
+            ..DownloadConfig::default()
+        };
+        assert!(check_url("http://93.184.215.14/file.pdf", &config).is_ok());
+        for url in &["http://127.0.0.1:8080/metrics", "http://[::1]/", "https://169.254.169.254/latest/meta-data",
+                     "http://[::ffff:192.168.0.1]/", "http://localhost/", "ftp://93.184.215.14/file.pdf",
+                     "https://files.example.com/file.pdf", "file:///etc/passwd", "not a link"] {
+            assert!(check_url(url, &config).is_err(), "{} was accepted", url);
+        }
+    }
+
+    #[test]
+    fn matches_domains() {
+        let allowed = vec!["example.com".to_string()];
+        let denied = vec![".private.example.com".to_string()];
+        assert!(domain_allowed("Files.Example.com", &allowed, &denied));
+        assert!(!domain_allowed("notexample.com", &allowed, &denied));
+        assert!(!domain_allowed("a.private.example.com", &allowed, &denied));
+        assert!(domain_allowed("anything.org", &[], &[]));
+    }
+}
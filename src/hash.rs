@@ -1,17 +1,19 @@
 extern crate rand;
 extern crate sha1;
+extern crate sha2;
 
 use std::fs::File;
 use std::io::Read;
 
 use self::rand::prelude::*;
 use self::sha1::Sha1;
+use self::sha2::{Digest, Sha256};
 
 fn read_bytes(filename: &str) -> Result<Vec<u8>, String> {
     let mut f = match File::open(filename) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -19,8 +21,8 @@ fn read_bytes(filename: &str) -> Result<Vec<u8>, String> {
     match f.read_to_end(&mut buffer) {
         Ok(_) => Ok(buffer),
         Err(err) => {
-            eprintln!("{:?}", err);
-            return Err(format!("{:?}", err));
+            error!("{:?}", err);
+            Err(format!("{:?}", err))
         }
     }
 }
@@ -33,10 +35,10 @@ fn read_bytes(filename: &str) -> Result<Vec<u8>, String> {
 /// let digest = hash_file("filetoprint.pdf"); // <SHA1 hexadecimal digest>
 /// ```
 pub fn hash_file(filename: &str) -> Result<String, String> {
-    let mybytes = match read_bytes(&filename) {
+    let mybytes = match read_bytes(filename) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -53,12 +55,48 @@ pub fn hash_file(filename: &str) -> Result<String, String> {
 /// let data: Vec<u8> = vec![0, 5, 6, 58];
 /// println!("{}", hash_data(&data)); // <SHA1 hexadecimal digest>
 /// ```
-pub fn hash_data(data: &Vec<u8>) -> String {
+pub fn hash_data(data: &[u8]) -> String {
     let mut hasher = Sha1::new();
     hasher.update(data);
     hasher.digest().to_string()
 }
 
+/// Computes SHA-256 HEX digest of the bytes you provide
+///
+/// # Examples
+///
+/// ```rust
+/// let data: Vec<u8> = vec![0, 5, 6, 58];
+/// println!("{}", sha256_data(&data)); // <SHA-256 hexadecimal digest>
+/// ```
+pub fn sha256_data(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Block size of SHA-256, the HMAC key is padded to it
+const HMAC_BLOCK: usize = 64;
+
+/// Computes HMAC-SHA256 HEX digest of the data, used to sign the webhooks
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", hmac_sha256(b"key", b"The quick brown fox jumps over the lazy dog"));
+/// // f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8
+/// ```
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> String {
+    let mut key = if key.len() > HMAC_BLOCK { Sha256::digest(key).to_vec() } else { key.to_vec() };
+    key.resize(HMAC_BLOCK, 0);
+
+    let mut inner = Sha256::new();
+    inner.input(key.iter().map(|byte| byte ^ 0x36).collect::<Vec<u8>>());
+    inner.input(data);
+    let mut outer = Sha256::new();
+    outer.input(key.iter().map(|byte| byte ^ 0x5c).collect::<Vec<u8>>());
+    outer.input(inner.result());
+    format!("{:x}", outer.result())
+}
+
 
 /// Generates token from the name
 ///
@@ -77,4 +115,163 @@ pub fn generate_token(name: String) -> String {
         data.push(rng.gen::<u8>());
     }
     format!("{}{}", &name, hash_data(&data))
-}
\ No newline at end of file
+}
+
+// Letters and digits, which can't be confused with each other when typed from the paper
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+
+/// Generates the one-time code of the upload page, which is short enough to be dictated or typed
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", generate_code()); // K7QM2XHP
+/// ```
+pub fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH).map(|_| CODE_ALPHABET[rng.gen_range(0, CODE_ALPHABET.len())] as char).collect()
+}
+
+/// Returns the stable fingerprint of the secret: the first 8 hex characters of its SHA-256 digest
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", fingerprint("K7QM2XHP")); // 8 hex characters
+/// ```
+pub fn fingerprint(secret: &str) -> String {
+    sha256_data(&Vec::from(secret))[..8].to_string()
+}
+
+/// Returns the beginning of the token with its fingerprint, which is enough to tell the tokens
+/// of the same name apart, but not to use them
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", mask_token("IFMO1f3a9c...")); // IFMO...<fingerprint>
+/// ```
+pub fn mask_token(token: &str) -> String {
+    format!("{}...{}", token.chars().take(4).collect::<String>(), fingerprint(token))
+}
+
+/// Returns the fingerprint of the one-time code, which is recorded instead of the code itself,
+/// because the code is the credential of the upload page until it is used
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", mask_code("K7QM2XHP")); // code <fingerprint>
+/// ```
+pub fn mask_code(code: &str) -> String {
+    format!("code {}", fingerprint(&code.to_uppercase()))
+}
+
+/// Returns the fingerprint of the mail or API token, which is stored as the submitter of the files
+/// instead of the token itself
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", mask_submitter("IFMO1f3a9c...")); // token <fingerprint>
+/// ```
+pub fn mask_submitter(token: &str) -> String {
+    format!("token {}", fingerprint(token))
+}
+
+const PASSWORD_ROUNDS: u32 = 10000;
+
+fn stretch(password: &str, salt: &str, rounds: u32) -> String {
+    let mut digest = Sha256::digest(format!("{}{}", salt, password).as_bytes());
+    for _ in 1..rounds {
+        digest = Sha256::digest(&digest);
+    }
+    format!("{:x}", digest)
+}
+
+/// Hashes the password with the random salt, the result is stored in the config as
+/// `sha256$<rounds>$<salt>$<hex digest>`
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", hash_password("secret")); // sha256$10000$<salt>$<SHA-256 hexadecimal digest>
+/// ```
+pub fn hash_password(password: &str) -> String {
+    let mut rng = rand::thread_rng();
+    let salt: Vec<u8> = (0..16).map(|_| rng.gen::<u8>()).collect();
+    let salt = hash_data(&salt);
+    format!("sha256${}${}${}", PASSWORD_ROUNDS, salt, stretch(password, &salt, PASSWORD_ROUNDS))
+}
+
+/// Checks the password against the hash from `hash_password`
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let parts: Vec<&str> = hash.split('$').collect();
+    if parts.len() != 4 || parts[0] != "sha256" {
+        return false;
+    }
+    let rounds = match parts[1].parse::<u32>() {
+        Ok(data) if data > 0 => data,
+        _ => return false,
+    };
+
+    // compared in constant time, so the digest can't be guessed by the response time
+    let expected = stretch(password, parts[2], rounds);
+    expected.len() == parts[3].len() &&
+        expected.bytes().zip(parts[3].bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Returns the random hexadecimal string, used as the session ID
+pub fn random_hex() -> String {
+    let mut rng = rand::thread_rng();
+    let data: Vec<u8> = (0..32).map(|_| rng.gen::<u8>()).collect();
+    sha256_data(&data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test cases of RFC 4231, the 5th one checks the truncated output and is skipped
+    #[test]
+    fn hmac_sha256_matches_rfc4231() {
+        let long_key = [0xaa; 131];
+        let cases: Vec<(&[u8], &[u8], &str)> = vec![
+            (&[0x0b; 20], b"Hi There",
+             "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            (b"Jefe", b"what do ya want for nothing?",
+             "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            (&[0xaa; 20], &[0xdd; 50],
+             "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            (&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25], &[0xcd; 50],
+             "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+            (&long_key, b"Test Using Larger Than Block-Size Key - Hash Key First",
+             "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+            (&long_key, b"This is a test using a larger than block-size key and a larger than block-size data. \
+                          The key needs to be hashed before being used by the HMAC algorithm.",
+             "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2"),
+        ];
+        for (key, data, expected) in cases {
+            assert_eq!(hmac_sha256(key, data), expected);
+        }
+    }
+
+    #[test]
+    fn verifies_password() {
+        let hash = hash_password("secret");
+        assert!(hash.starts_with("sha256$10000$"));
+        assert!(verify_password("secret", &hash));
+        assert!(!verify_password("Secret", &hash));
+        assert!(!verify_password("secret", &hash[..hash.len() - 1]));
+        // the salt is random, so the same password gets another hash
+        assert_ne!(hash, hash_password("secret"));
+
+        let fast = format!("sha256$1$salt${}", sha256_data(&Vec::from("saltsecret")));
+        assert!(verify_password("secret", &fast));
+        for hash in &["", "secret", "sha1$1$salt$00", "sha256$0$salt$00", "sha256$x$salt$00", "sha256$1$salt"] {
+            assert!(!verify_password("secret", hash), "{} was accepted", hash);
+        }
+    }
+}
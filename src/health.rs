@@ -0,0 +1,299 @@
+extern crate chrono;
+extern crate reqwest;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use self::chrono::Utc;
+
+use config::Config;
+use http::{self, Handler, Request, Response};
+use logger;
+use spool;
+
+pub const TELEGRAM: &str = "telegram";
+pub const IMAP: &str = "imap";
+pub const MATRIX: &str = "matrix";
+
+
+/// State of the subsystem thread
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// The subsystem is turned off by the config or the command line
+    Disabled,
+    Running,
+    /// The thread exited, the subsystem doesn't work until restart
+    Stopped,
+}
+
+#[derive(Clone)]
+struct Status {
+    state: State,
+    last_ok: Option<i64>,
+    last_error: Option<(i64, String)>,
+}
+
+/// Result of the check of one subsystem
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub healthy: bool,
+    pub state: Option<State>,
+    /// Time of the last successful poll or request
+    pub last_ok: Option<i64>,
+    /// Shown only to the admins in `/diag` and `teleprint check`, `/healthz` needs no login
+    #[serde(skip_serializing)]
+    pub last_error: Option<String>,
+    pub last_error_time: Option<i64>,
+    pub detail: String,
+}
+
+impl Check {
+    /// Result of the one-time check, which has no history
+    pub fn new(name: &str, healthy: bool, detail: String) -> Check {
+        Check {
+            name: name.to_string(),
+            healthy,
+            state: None,
+            last_ok: None,
+            last_error: None,
+            last_error_time: None,
+            detail,
+        }
+    }
+}
+
+/// Keeps the subsystem in the `Running` state, it becomes `Stopped` when the guard is dropped
+pub struct Guard {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    status: &'a str,
+    checks: &'a Vec<Check>,
+}
+
+lazy_static! {
+    static ref STATUSES: Mutex<HashMap<String, Status>> = Mutex::new(HashMap::new());
+}
+
+
+fn update<F: FnOnce(&mut Status)>(name: &str, change: F) {
+    let mut statuses = STATUSES.lock().unwrap();
+    let status = statuses.entry(name.to_string()).or_insert(Status {
+        state: State::Running,
+        last_ok: None,
+        last_error: None,
+    });
+    change(status);
+}
+
+/// Sets the state of the subsystem thread
+///
+/// # Examples
+///
+/// ```rust
+/// set_state(IMAP, State::Disabled); // teleprint --noimap
+/// ```
+pub fn set_state(name: &str, state: State) {
+    update(name, |status| status.state = state);
+}
+
+/// Marks the subsystem running until the returned guard is dropped, so the exit of its thread
+/// is noticed even if it panics
+///
+/// # Examples
+///
+/// ```rust
+/// let _guard = running(IMAP);
+/// loop { /* polling */ }
+/// ```
+pub fn running(name: &str) -> Guard {
+    set_state(name, State::Running);
+    Guard { name: name.to_string() }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        update(&self.name, |status| if status.state == State::Running {
+            status.state = State::Stopped;
+        });
+    }
+}
+
+/// Records the successful poll or request of the subsystem
+pub fn record_ok(name: &str) {
+    update(name, |status| status.last_ok = Some(Utc::now().timestamp()));
+}
+
+/// Records the last error of the subsystem, the bot tokens in the Telegram URLs are hidden
+pub fn record_error(name: &str, error: &str) {
+    let error = logger::redact(error, &[]);
+    update(name, |status| status.last_error = Some((Utc::now().timestamp(), error)));
+}
+
+
+// The subsystem is healthy if its thread is running and, if `max_silence` is set,
+// it had successful polls within `max_silence` seconds
+fn thread_check(name: &str, max_silence: Option<i64>, now: i64) -> Check {
+    let status = STATUSES.lock().unwrap().get(name).cloned();
+    let status = match status {
+        Some(data) => data,
+        None => return Check::new(name, false, "not started".to_string()),
+    };
+
+    let (healthy, detail) = match (status.state, max_silence, status.last_ok) {
+        (State::Disabled, _, _) => (true, "disabled".to_string()),
+        (State::Stopped, _, _) => (false, "the thread has stopped".to_string()),
+        (State::Running, Some(limit), Some(last)) if now - last > limit =>
+            (false, format!("no successful polls for {} seconds", now - last)),
+        (State::Running, Some(_), None) => (false, "no successful polls yet".to_string()),
+        (State::Running, _, _) => (true, "running".to_string()),
+    };
+
+    Check {
+        name: name.to_string(),
+        healthy,
+        state: Some(status.state),
+        last_ok: status.last_ok,
+        last_error: status.last_error.as_ref().map(|(_, error)| error.clone()),
+        last_error_time: status.last_error.as_ref().map(|&(time, _)| time),
+        detail,
+    }
+}
+
+/// Checks, that the printer is known to CUPS and accepts jobs
+pub fn check_printer(printer: &str) -> Check {
+    let name = "printer";
+    let output = match Command::new("lpstat").args(["-p", printer]).output() {
+        Ok(data) => data,
+        Err(err) => return Check::new(name, false, format!("Error running lpstat: {}", err)),
+    };
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() {
+        return Check::new(name, false, format!("{} {}", text, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let disabled = text.contains("disabled");
+    Check::new(name, !disabled, text)
+}
+
+/// Checks, that the disk with the spool has at least `min_free` megabytes
+pub fn check_spool(spool_dir: &str, min_free: u64) -> Check {
+    let name = "spool";
+    if let Err(err) = spool::init(spool_dir) {
+        return Check::new(name, false, err);
+    }
+
+    let output = match Command::new("df").args(["-Pk", spool_dir]).output() {
+        Ok(data) => data,
+        Err(err) => return Check::new(name, false, format!("Error running df: {}", err)),
+    };
+    // POSIX output: the header and `<filesystem> <blocks> <used> <available> <capacity> <mounted on>`
+    let text = String::from_utf8_lossy(&output.stdout).to_string();
+    let available = text.lines().nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|value| value.parse::<u64>().ok());
+
+    match available {
+        Some(kilobytes) => {
+            let free = kilobytes / 1024;
+            Check::new(name, free >= min_free, format!("{} MB free, at least {} MB required", free, min_free))
+        }
+        None => Check::new(name, false, format!("Can't parse df output: {}", text.trim())),
+    }
+}
+
+/// Returns the checks of all subsystems by their current state, used by `/healthz` and `/diag`
+pub fn checks(config: &Config) -> Vec<Check> {
+    let now = Utc::now().timestamp();
+    vec![
+        thread_check(TELEGRAM, None, now),
+        thread_check(IMAP, Some(config.health.imap_timeout as i64), now),
+        thread_check(MATRIX, Some(config.matrix.sync_timeout as i64 * 3), now),
+        check_printer(&config.printer),
+        check_spool(&config.spool.dir, config.health.min_free_space),
+    ]
+}
+
+/// Checks the Telegram Bot API token by `getMe`
+pub fn probe_telegram(token: &str) -> Check {
+    let response = reqwest::get(&format!("https://api.telegram.org/bot{}/getMe", token));
+    match response {
+        Ok(ref resp) if resp.status().is_success() => Check::new(TELEGRAM, true, "getMe ok".to_string()),
+        Ok(resp) => Check::new(TELEGRAM, false, format!("Telegram API responded with {}", resp.status())),
+        Err(err) => Check::new(TELEGRAM, false, logger::redact(&format!("{:?}", err), &[token.to_string()])),
+    }
+}
+
+/// Returns true if all the checks passed
+pub fn is_healthy(checks: &[Check]) -> bool {
+    checks.iter().all(|check| check.healthy)
+}
+
+/// Returns the human readable report of the checks
+pub fn report(checks: &Vec<Check>) -> String {
+    let mut lines: Vec<String> = vec![format!("Status: {}", if is_healthy(checks) { "ok" } else { "FAIL" })];
+    for check in checks {
+        lines.push(format!("{} {}: {}", if check.healthy { "[ok]" } else { "[FAIL]" }, check.name, check.detail));
+        if let Some(time) = check.last_ok {
+            lines.push(format!("    last ok: {}", spool::format_time(time)));
+        }
+        if let (Some(time), Some(error)) = (check.last_error_time, &check.last_error) {
+            lines.push(format!("    last error: {} {}", spool::format_time(time), error));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Returns the checks as JSON for `/healthz` without the last errors
+pub fn to_json(checks: &Vec<Check>) -> String {
+    let report = Report {
+        status: if is_healthy(checks) { "ok" } else { "fail" },
+        checks,
+    };
+    serde_json::to_string(&report).unwrap_or("{\"status\":\"fail\"}".to_string())
+}
+
+/// Returns the answer of `/healthz`: the checks as JSON with the status `503`, if any of them fails
+pub fn healthz(config: &Config) -> Response {
+    let checks = checks(config);
+    let status = if is_healthy(&checks) { 200 } else { 503 };
+    Response::new(status, "application/json", Vec::from(to_json(&checks)))
+        .with_header("Cache-Control", "no-cache")
+}
+
+/// Runs the HTTP server with the health checks on `/healthz`, if it is enabled in the `[health]` section
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let health = thread::spawn(move || {
+///        run_server(Arc::clone(&config));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if !config.health.enabled {
+        return;
+    }
+
+    let handler: Handler = Arc::new(move |request: &Request| {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/healthz") => {
+                let config = { a_config.lock().unwrap().clone() };
+                healthz(&config)
+            }
+            (_, "/healthz") => Response::text(405, "Method not allowed"),
+            _ => Response::text(404, "Not found"),
+        }
+    });
+
+    match http::serve(&config.health.address, 0, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the health server: {}", err),
+    };
+}
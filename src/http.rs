@@ -0,0 +1,492 @@
+extern crate reqwest;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const MAX_HEADERS: usize = 100;
+// Limit of the request line, every header line and the chunk size line
+const MAX_LINE: u64 = 8 * 1024;
+// Connections, which are handled at once by one server, the others are answered with 503
+const MAX_CONNECTIONS: usize = 64;
+/// Hard limit of the request body, the bodies are kept in memory
+pub const MAX_BODY: u64 = 512 * 1024 * 1024;
+const TIMEOUT: u64 = 30;
+
+/// Parsed HTTP request
+pub struct Request {
+    pub method: String,
+    /// Path without the query, like `/metrics`
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Headers with the lowercase names
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// HTTP response, which is sent with `Connection: close`
+pub struct Response {
+    pub status: u16,
+    pub content_type: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Part of the `multipart/form-data` body, the file or the plain field
+pub struct Part {
+    pub name: String,
+    /// Name of the file, if the part is the uploaded file
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Function, which handles the requests of the server
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+impl Request {
+    /// Returns the value of the header by its name in any case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|value| value.as_str())
+    }
+
+    /// Returns the value of the cookie by its name
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.header("cookie").and_then(|cookies| {
+            cookies.split(';')
+                .filter_map(|pair| {
+                    let mut parts = pair.trim().splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(key), Some(value)) if key == name => Some(value.to_string()),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+    }
+
+    /// Returns the fields of the `application/x-www-form-urlencoded` body
+    pub fn form(&self) -> HashMap<String, String> {
+        let mut url = match reqwest::Url::parse("http://localhost/") {
+            Ok(data) => data,
+            Err(_) => return HashMap::new(),
+        };
+        url.set_query(Some(&String::from_utf8_lossy(&self.body)));
+        url.query_pairs().into_owned().collect()
+    }
+
+    /// Returns the parts of the `multipart/form-data` body, used by the forms with files
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let parts = request.multipart().unwrap();
+    /// let file = parts.iter().find(|part| part.name == "file").unwrap();
+    /// println!("{:?} {}", file.filename, file.data.len());
+    /// ```
+    pub fn multipart(&self) -> Result<Vec<Part>, String> {
+        let boundary = match self.header("content-type").and_then(|value| header_param(value, "boundary")) {
+            Some(data) => format!("--{}", data),
+            None => return Err("The body isn't multipart/form-data".to_string()),
+        };
+        let boundary = boundary.as_bytes();
+
+        let mut parts = vec![];
+        let mut rest = match find(&self.body, boundary) {
+            Some(index) => &self.body[index + boundary.len()..],
+            None => return Err("No parts in the body".to_string()),
+        };
+        // every part starts after the boundary line, the last boundary is followed by `--`
+        while !rest.starts_with(b"--") {
+            let end = match find(rest, boundary) {
+                Some(index) => index,
+                None => return Err("The body is incomplete".to_string()),
+            };
+            let part = rest[..end].strip_prefix(b"\r\n").unwrap_or(&rest[..end]);
+            let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+            rest = &rest[end + boundary.len()..];
+
+            let split = match find(part, b"\r\n\r\n") {
+                Some(index) => index,
+                None => return Err("Part without headers".to_string()),
+            };
+            let mut name = None;
+            let mut filename = None;
+            let mut content_type = None;
+            for line in String::from_utf8_lossy(&part[..split]).split("\r\n") {
+                let mut header = line.splitn(2, ':');
+                match (header.next().map(|key| key.trim().to_lowercase()), header.next()) {
+                    (Some(ref key), Some(value)) if key == "content-disposition" => {
+                        name = header_param(value, "name");
+                        filename = header_param(value, "filename");
+                    }
+                    (Some(ref key), Some(value)) if key == "content-type" => content_type = Some(value.trim().to_string()),
+                    _ => (),
+                };
+            }
+            match name {
+                Some(name) => parts.push(Part { name, filename, content_type, data: Vec::from(&part[split + 4..]) }),
+                None => return Err("Part without name".to_string()),
+            };
+        }
+        Ok(parts)
+    }
+}
+
+// Returns the parameter of the header like `form-data; name="file"`, the quotes are removed
+fn header_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1)
+        .filter_map(|param| {
+            let mut parts = param.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key.eq_ignore_ascii_case(name) => Some(value.trim_matches('"').to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Escapes the text to be shown in the HTML page
+///
+/// # Examples
+///
+/// ```rust
+/// println!("{}", escape_html("<b>")); // &lt;b&gt;
+/// ```
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            content_type: content_type.to_string(),
+            headers: vec![],
+            body,
+        }
+    }
+
+    /// Plain text response
+    pub fn text(status: u16, text: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", Vec::from(text))
+    }
+
+    /// Redirects the browser to the location with `303 See Other`, so the form isn't sent again
+    pub fn redirect(location: &str) -> Response {
+        Response::text(303, "").with_header("Location", location)
+    }
+
+    /// Adds the header to the response
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        303 => "See Other",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> Result<(), String> {
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                           response.status, reason(response.status), response.content_type, response.body.len());
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    match stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&response.body)) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error on writing the response: {}", err)),
+    }
+}
+
+// Reads the line up to `MAX_LINE` bytes, the longer lines are refused, so they can't take the memory
+fn read_line<R: BufRead>(reader: &mut R, error: &str) -> Result<String, Response> {
+    let mut line = String::new();
+    match reader.take(MAX_LINE).read_line(&mut line) {
+        Ok(_) if line.ends_with('\n') => Ok(line),
+        Ok(read) if read as u64 == MAX_LINE => Err(Response::text(431, "Line of the request is too long")),
+        _ => Err(Response::text(400, error)),
+    }
+}
+
+// Appends `length` bytes to the body, the buffer grows with the received data, not with the announced length
+fn read_body<R: Read>(reader: &mut R, length: u64, body: &mut Vec<u8>) -> Result<(), Response> {
+    match reader.take(length).read_to_end(body) {
+        Ok(read) if read as u64 == length => Ok(()),
+        _ => Err(Response::text(400, "Incomplete body")),
+    }
+}
+
+// Reads the body, sent in chunks with their hexadecimal sizes, the trailers are skipped
+fn read_chunked<R: BufRead>(reader: &mut R, max_body: u64) -> Result<Vec<u8>, Response> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(reader, "Incomplete body")?;
+        let size = match u64::from_str_radix(line.split(';').next().unwrap_or("").trim(), 16) {
+            Ok(data) => data,
+            Err(_) => return Err(Response::text(400, "Bad chunk size")),
+        };
+        if size == 0 {
+            break;
+        }
+        match (body.len() as u64).checked_add(size) {
+            Some(total) if total <= max_body => (),
+            _ => return Err(Response::text(413, "Request is too big")),
+        };
+
+        read_body(reader, size, &mut body)?;
+        let mut end = [0u8; 2];
+        match reader.read_exact(&mut end) {
+            Ok(_) => (),
+            Err(_) => return Err(Response::text(400, "Incomplete body")),
+        };
+    }
+
+    loop {
+        if read_line(reader, "Incomplete body")?.trim_end().is_empty() {
+            return Ok(body);
+        }
+    }
+}
+
+// Returns the request or the response with the error, which should be sent instead of handling it
+fn read_request(stream: &TcpStream, max_body: u64) -> Result<Request, Response> {
+    let mut reader = BufReader::new(stream);
+    let line = read_line(&mut reader, "Bad request")?;
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err(Response::text(400, "Bad request line"));
+    }
+    let (method, target) = (parts[0].to_string(), parts[1]);
+
+    // the target is parsed as the relative URL to get the decoded path and query
+    let url = match reqwest::Url::parse("http://localhost").and_then(|base| base.join(target)) {
+        Ok(data) => data,
+        Err(_) => return Err(Response::text(400, "Bad request target")),
+    };
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        let header = read_line(&mut reader, "Bad headers")?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err(Response::text(400, "Too many headers"));
+        }
+        if let Some(index) = header.find(':') {
+            headers.insert(header[..index].trim().to_lowercase(), header[index + 1..].trim().to_string());
+        }
+    }
+
+    let chunked = match headers.get("transfer-encoding") {
+        Some(value) if value.eq_ignore_ascii_case("chunked") => true,
+        Some(_) => return Err(Response::text(501, "Only chunked transfer encoding is supported")),
+        None => false,
+    };
+    let length = match headers.get("content-length").map(|value| value.parse::<u64>()) {
+        Some(Ok(data)) => data,
+        Some(Err(_)) => return Err(Response::text(400, "Bad Content-Length")),
+        None => 0,
+    };
+    if length > max_body {
+        return Err(Response::text(413, "Request is too big"));
+    }
+
+    // IPP clients wait for the confirmation before sending the document
+    if headers.get("expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue")) {
+        let _ = (&mut &*stream).write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    let body = if chunked {
+        read_chunked(&mut reader, max_body)?
+    } else {
+        let mut body = vec![];
+        read_body(&mut reader, length, &mut body)?;
+        body
+    };
+
+    Ok(Request {
+        method,
+        path: url.path().to_string(),
+        query: url.query_pairs().into_owned().collect(),
+        headers,
+        body,
+    })
+}
+
+// Counted connection of the server, it is released even if the handler panics
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, max_body: u64, handler: Handler) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)));
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT)));
+
+    let response = match read_request(&stream, max_body) {
+        Ok(request) => handler(&request),
+        Err(response) => response,
+    };
+    match write_response(&mut stream, &response) {
+        Ok(_) => (),
+        Err(err) => debug!("{}", err),
+    };
+}
+
+
+/// Runs the simple HTTP server on the address, every connection is handled in its own thread
+///
+/// Only requests with `Content-Length` or chunked bodies up to `max_body` bytes (and never more than `MAX_BODY`)
+/// are accepted, one request per connection. Up to 64 connections are handled at once, the others get `503`.
+/// Returns only if the address can't be bound.
+///
+/// # Examples
+///
+/// ```rust
+/// let handler: Handler = Arc::new(|request: &Request| Response::text(200, &request.path));
+/// serve("127.0.0.1:9898", 0, handler).unwrap();
+/// ```
+pub fn serve(address: &str, max_body: u64, handler: Handler) -> Result<(), String> {
+    let max_body = max_body.min(MAX_BODY);
+    let listener = match TcpListener::bind(address) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on binding {}: {}", address, err)),
+    };
+    info!("HTTP server is listening on {}", address);
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Error on accepting the connection: {}", err);
+                continue;
+            }
+        };
+        connections.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(Arc::clone(&connections));
+        if connections.load(Ordering::SeqCst) > MAX_CONNECTIONS {
+            let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+            let _ = write_response(&mut stream, &Response::text(503, "Too many connections"));
+            continue;
+        }
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            let _slot = slot;
+            handle_connection(stream, max_body, handler);
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(content_type: &str, body: &str) -> Request {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_string(), content_type.to_string());
+        Request {
+            method: "POST".to_string(),
+            path: "/upload".to_string(),
+            query: HashMap::new(),
+            headers,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_multipart() {
+        let body = "preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"comment\"\r\n\r\n\
+            two\r\nlines\r\n--XyZ\r\n\
+            content-disposition: form-data; name=\"file\"; filename=\"a b.pdf\"\r\n\
+            Content-Type: application/pdf\r\n\r\n\
+            %PDF--X\r\n\r\n--XyZ--\r\n";
+        let parts = request("multipart/form-data; boundary=\"XyZ\"", body).multipart().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "comment");
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].data, b"two\r\nlines".to_vec());
+        assert_eq!(parts[1].name, "file");
+        assert_eq!(parts[1].filename, Some("a b.pdf".to_string()));
+        assert_eq!(parts[1].content_type, Some("application/pdf".to_string()));
+        assert_eq!(parts[1].data, b"%PDF--X\r\n".to_vec());
+    }
+
+    #[test]
+    fn rejects_bad_multipart() {
+        let part = "--b\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\ndata\r\n";
+        assert!(request("application/x-www-form-urlencoded", part).multipart().is_err());
+        assert!(request("multipart/form-data; boundary=other", part).multipart().is_err());
+        // without the closing boundary
+        assert!(request("multipart/form-data; boundary=b", part).multipart().is_err());
+        assert!(request("multipart/form-data; boundary=b", "--b\r\ndata\r\n--b--").multipart().is_err());
+        let unnamed = "--b\r\nContent-Disposition: form-data\r\n\r\ndata\r\n--b--";
+        assert!(request("multipart/form-data; boundary=b", unnamed).multipart().is_err());
+        assert_eq!(request("multipart/form-data; boundary=b", "--b--").multipart().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let mut body: &[u8] = b"4;ext=1\r\nWiki\r\n8\r\n pedia\r\n\r\n0\r\nExpires: never\r\n\r\n";
+        assert_eq!(read_chunked(&mut body, 100).ok(), Some(b"Wiki pedia\r\n".to_vec()));
+
+        let mut body: &[u8] = b"4\r\nWiki\r\n10\r\n";
+        assert_eq!(read_chunked(&mut body, 10).err().map(|response| response.status), Some(413));
+        let mut body: &[u8] = b"ffffffffffffffff\r\n";
+        assert_eq!(read_chunked(&mut body, MAX_BODY).err().map(|response| response.status), Some(413));
+        let mut body: &[u8] = b"x\r\n";
+        assert_eq!(read_chunked(&mut body, 100).err().map(|response| response.status), Some(400));
+        let mut body: &[u8] = b"8\r\nWiki";
+        assert_eq!(read_chunked(&mut body, 100).err().map(|response| response.status), Some(400));
+    }
+
+    #[test]
+    fn limits_line_length() {
+        let mut data: &[u8] = b"GET / HTTP/1.1\r\nHost";
+        assert_eq!(read_line(&mut data, "Bad request").ok(), Some("GET / HTTP/1.1\r\n".to_string()));
+        assert_eq!(read_line(&mut data, "Bad request").err().map(|response| response.status), Some(400));
+
+        let long = vec![b'a'; MAX_LINE as usize + 1];
+        assert_eq!(read_line(&mut &long[..], "Bad request").err().map(|response| response.status), Some(431));
+    }
+}
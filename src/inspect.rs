@@ -0,0 +1,228 @@
+extern crate lopdf;
+
+use std::collections::BTreeSet;
+use std::panic;
+
+use self::lopdf::{Dictionary, Document, Object, ObjectId};
+
+const MM_PER_POINT: f64 = 25.4 / 72.0;
+// Pages bigger than A3 (in millimeters, with the tolerance) can't be printed on the usual office printer
+const MAX_PAGE_SHORT: f64 = 300.0;
+const MAX_PAGE_LONG: f64 = 423.0;
+// Fonts, which every PDF reader and printer must have
+const STANDARD_FONTS: [&str; 14] = [
+    "Times-Roman", "Times-Bold", "Times-Italic", "Times-BoldItalic",
+    "Helvetica", "Helvetica-Bold", "Helvetica-Oblique", "Helvetica-BoldOblique",
+    "Courier", "Courier-Bold", "Courier-Oblique", "Courier-BoldOblique",
+    "Symbol", "ZapfDingbats",
+];
+
+/// Result of the inspection of the PDF, attached to the submission record
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Report {
+    pub pages: Option<u32>,
+    pub encrypted: bool,
+    /// Parsing error, if the file is corrupted
+    pub error: Option<String>,
+    pub javascript: bool,
+    /// Numbers of the pages bigger than A3
+    pub oversized_pages: Vec<u32>,
+    /// Fonts, which are neither embedded nor standard, the printer will substitute them
+    pub missing_fonts: Vec<String>,
+}
+
+impl Report {
+    /// Returns the reason, why the file can't be printed at all
+    pub fn rejection(&self) -> Option<String> {
+        if self.encrypted {
+            return Some("the file is encrypted or protected by password, please send it without protection".to_string());
+        }
+        self.error.as_ref().map(|err| format!("the file is corrupted: {}", err))
+    }
+
+    /// Returns the problems, which the admin should know about before printing
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = vec![];
+        if self.javascript {
+            warnings.push("contains JavaScript".to_string());
+        }
+        if !self.oversized_pages.is_empty() {
+            let pages: Vec<String> = self.oversized_pages.iter().map(|page| format!("{}", page)).collect();
+            warnings.push(format!("pages bigger than A3: {}", pages.join(", ")));
+        }
+        if !self.missing_fonts.is_empty() {
+            warnings.push(format!("fonts are not embedded: {}", self.missing_fonts.join(", ")));
+        }
+        warnings
+    }
+}
+
+
+fn number(object: &Object) -> Option<f64> {
+    match *object {
+        Object::Integer(value) => Some(value as f64),
+        Object::Real(value) => Some(value),
+        _ => None,
+    }
+}
+
+/// Returns the MediaBox of the page `[x0, y0, x1, y1]` in points, it may be inherited from the page tree
+pub fn media_box(doc: &Document, page_id: ObjectId) -> Option<[f64; 4]> {
+    let mut node = Object::Reference(page_id);
+    for _ in 0..32 {
+        let dict = match doc.dereference(&node).map(|(_, object)| object.as_dict()) {
+            Ok(Ok(data)) => data,
+            _ => return None,
+        };
+
+        if let Ok(value) = dict.get(b"MediaBox") {
+            let rect: Vec<f64> = match doc.dereference(value).map(|(_, object)| object.as_array()) {
+                Ok(Ok(array)) => array.iter().filter_map(number).collect(),
+                _ => return None,
+            };
+            if rect.len() != 4 {
+                return None;
+            }
+            return Some([rect[0].min(rect[2]), rect[1].min(rect[3]), rect[0].max(rect[2]), rect[1].max(rect[3])]);
+        }
+
+        node = match dict.get(b"Parent") {
+            Ok(data) => data.clone(),
+            Err(_) => return None,
+        };
+    }
+    None
+}
+
+/// Returns width and height of the page in points
+pub fn page_size(doc: &Document, page_id: ObjectId) -> Option<(f64, f64)> {
+    media_box(doc, page_id).map(|rect| (rect[2] - rect[0], rect[3] - rect[1]))
+}
+
+fn has_javascript(dict: &Dictionary) -> bool {
+    dict.has(b"JS") || dict.has(b"JavaScript") ||
+        dict.get(b"S").and_then(|object| object.as_name()).is_ok_and(|name| name == b"JavaScript")
+}
+
+fn is_embedded(doc: &Document, font: &Dictionary) -> bool {
+    let descriptor = match font.get(b"FontDescriptor").and_then(|object| doc.dereference(object)) {
+        Ok((_, object)) => match object.as_dict() {
+            Ok(data) => data,
+            Err(_) => return false,
+        },
+        Err(_) => return false,
+    };
+    descriptor.has(b"FontFile") || descriptor.has(b"FontFile2") || descriptor.has(b"FontFile3")
+}
+
+fn missing_fonts(doc: &Document) -> Vec<String> {
+    let mut missing: BTreeSet<String> = BTreeSet::new();
+    for object in doc.objects.values() {
+        let font = match *object {
+            Object::Dictionary(ref dict) => dict,
+            _ => continue,
+        };
+        if font.get(b"Type").and_then(|object| object.as_name()).map_or(true, |name| name != b"Font") {
+            continue;
+        }
+
+        // composite fonts are checked by their descendants, Type3 glyphs are drawn by the file itself
+        let subtype = font.get(b"Subtype").and_then(|object| object.as_name()).unwrap_or(b"");
+        if subtype == b"Type0" || subtype == b"Type3" || is_embedded(doc, font) {
+            continue;
+        }
+
+        let name = match font.get(b"BaseFont").and_then(|object| object.as_name()) {
+            Ok(data) => String::from_utf8_lossy(data).to_string(),
+            Err(_) => continue,
+        };
+        // subset fonts are named like ABCDEF+Arial
+        let name = match name.find('+') {
+            Some(index) if index == 6 => name[index + 1..].to_string(),
+            _ => name,
+        };
+        if !STANDARD_FONTS.contains(&name.as_str()) {
+            missing.insert(name);
+        }
+    }
+    missing.into_iter().collect()
+}
+
+/// Parses the PDF, the panic of the parser on the malformed file is returned as the error
+///
+/// lopdf may panic on the broken cross-reference tables and streams instead of returning the error,
+/// and the uploaded files are arbitrary, so they are never parsed without this wrapper.
+///
+/// # Examples
+///
+/// ```rust
+/// let doc = load_pdf(&read("report.pdf").unwrap()).unwrap();
+/// println!("{} pages", doc.get_pages().len());
+/// ```
+pub fn load_pdf(data: &[u8]) -> Result<Document, String> {
+    match panic::catch_unwind(|| Document::load_mem(data)) {
+        Ok(Ok(doc)) => Ok(doc),
+        Ok(Err(err)) => Err(format!("{:?}", err)),
+        Err(_) => Err("the file is corrupted, the PDF parser failed on it".to_string()),
+    }
+}
+
+/// Parses the PDF and checks whether it can be printed, returns the report
+///
+/// Encrypted and corrupted files should be rejected, other problems are shown to the admin.
+///
+/// # Examples
+///
+/// ```rust
+/// let report = inspect(&read("report.pdf").unwrap());
+/// match report.rejection() {
+///     Some(reason) => println!("Rejected: {}", reason),
+///     None => println!("{} pages, warnings: {:?}", report.pages.unwrap(), report.warnings()),
+/// };
+/// ```
+pub fn inspect(data: &[u8]) -> Report {
+    let mut report = Report::default();
+
+    let doc = match load_pdf(data) {
+        Ok(data) => data,
+        Err(err) => {
+            // encrypted files often can't be parsed at all
+            if data.windows(8).any(|window| window == b"/Encrypt") {
+                report.encrypted = true;
+            } else {
+                report.error = Some(err);
+            }
+            return report;
+        }
+    };
+
+    if doc.trailer.has(b"Encrypt") {
+        report.encrypted = true;
+        return report;
+    }
+
+    let pages = doc.get_pages();
+    if pages.is_empty() {
+        report.error = Some("no pages".to_string());
+        return report;
+    }
+    report.pages = Some(pages.len() as u32);
+
+    for (number, page_id) in pages.iter() {
+        if let Some((width, height)) = page_size(&doc, *page_id) {
+            let (short, long) = if width < height { (width, height) } else { (height, width) };
+            if short * MM_PER_POINT > MAX_PAGE_SHORT || long * MM_PER_POINT > MAX_PAGE_LONG {
+                report.oversized_pages.push(*number);
+            }
+        }
+    }
+
+    report.javascript = doc.objects.values().any(|object| match *object {
+        Object::Dictionary(ref dict) => has_javascript(dict),
+        Object::Stream(ref stream) => has_javascript(&stream.dict),
+        _ => false,
+    });
+    report.missing_fonts = missing_fonts(&doc);
+
+    report
+}
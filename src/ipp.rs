@@ -0,0 +1,509 @@
+extern crate base64;
+extern crate chrono;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::chrono::Utc;
+
+use audit;
+use config::Config;
+use database;
+use hash;
+use http::{self, Handler, Request, Response};
+use pipeline::{self, Attachment, Identity, IngestChannel, Notifier, Silent};
+use spool::{self, Channel, FileMeta};
+use supervisor;
+use transform;
+
+const PATH: &str = "/ipp/print";
+// IPP headers and the attributes before the document
+const REQUEST_OVERHEAD: u64 = 64 * 1024;
+// Delay of the failed authentication, which slows down guessing of the tokens
+const AUTH_DELAY: u64 = 1;
+// Names and values have the 2 bytes length, the longer ones are cut
+const MAX_FIELD: usize = 0xffff;
+
+// Operations
+const PRINT_JOB: u16 = 0x0002;
+const VALIDATE_JOB: u16 = 0x0004;
+const GET_JOB_ATTRIBUTES: u16 = 0x0009;
+const GET_JOBS: u16 = 0x000A;
+const GET_PRINTER_ATTRIBUTES: u16 = 0x000B;
+
+// Status codes
+const OK: u16 = 0x0000;
+const BAD_REQUEST: u16 = 0x0400;
+const NOT_POSSIBLE: u16 = 0x0404;
+const NOT_FOUND: u16 = 0x0406;
+const FORMAT_NOT_SUPPORTED: u16 = 0x040A;
+const OPERATION_NOT_SUPPORTED: u16 = 0x0501;
+const SERVICE_UNAVAILABLE: u16 = 0x0502;
+const VERSION_NOT_SUPPORTED: u16 = 0x0503;
+
+// Delimiter tags of the attribute groups
+const OPERATION_GROUP: u8 = 0x01;
+const JOB_GROUP: u8 = 0x02;
+const END_OF_ATTRIBUTES: u8 = 0x03;
+const PRINTER_GROUP: u8 = 0x04;
+
+// Value tags
+const INTEGER: u8 = 0x21;
+const BOOLEAN: u8 = 0x22;
+const ENUM: u8 = 0x23;
+const TEXT: u8 = 0x41;
+const NAME: u8 = 0x42;
+const KEYWORD: u8 = 0x44;
+const URI: u8 = 0x45;
+const CHARSET: u8 = 0x47;
+const LANGUAGE: u8 = 0x48;
+const MIME_TYPE: u8 = 0x49;
+
+// Job states
+const JOB_PENDING: i32 = 3;
+const JOB_CANCELED: i32 = 7;
+const JOB_COMPLETED: i32 = 9;
+
+const FORMATS: [&str; 4] = ["application/pdf", "image/jpeg", "image/png", "application/octet-stream"];
+
+/// Parsed IPP request
+struct IppRequest {
+    version: (u8, u8),
+    operation: u16,
+    request_id: u32,
+    /// Group tag, name and value of every attribute, additional values repeat the name
+    attributes: Vec<(u8, String, Vec<u8>)>,
+    document: Vec<u8>,
+}
+
+/// Builder of the IPP response
+struct IppResponse {
+    data: Vec<u8>,
+}
+
+/// The IPP listener as the source of the print requests, the clients get the answers in the responses
+pub struct IppChannel;
+
+impl IngestChannel for IppChannel {
+    fn channel(&self) -> Channel {
+        Channel::Ipp
+    }
+
+    fn notifier(&self, _identity: &Identity) -> Box<dyn Notifier> {
+        Box::new(Silent)
+    }
+}
+
+impl IppRequest {
+    fn parse(data: &[u8]) -> Result<IppRequest, String> {
+        if data.len() < 8 {
+            return Err("The request is too short".to_string());
+        }
+        let mut request = IppRequest {
+            version: (data[0], data[1]),
+            operation: u16::from(data[2]) << 8 | u16::from(data[3]),
+            request_id: u32::from(data[4]) << 24 | u32::from(data[5]) << 16 | u32::from(data[6]) << 8 | u32::from(data[7]),
+            attributes: vec![],
+            document: vec![],
+        };
+
+        let mut position = 8;
+        let mut group = 0;
+        let mut name = String::new();
+        loop {
+            let tag = match data.get(position) {
+                Some(data) => *data,
+                None => return Err("No end of the attributes".to_string()),
+            };
+            position += 1;
+            if tag == END_OF_ATTRIBUTES {
+                break;
+            }
+            // delimiter tags are below 0x10, the value tags follow them
+            if tag < 0x10 {
+                group = tag;
+                continue;
+            }
+
+            let (attribute, next) = read_field(data, position)?;
+            let (value, next) = read_field(data, next)?;
+            position = next;
+            if !attribute.is_empty() {
+                name = String::from_utf8_lossy(attribute).to_string();
+            }
+            request.attributes.push((group, name.clone(), Vec::from(value)));
+        }
+
+        request.document = Vec::from(&data[position..]);
+        Ok(request)
+    }
+
+    /// Returns the first value of the operation attribute as the string
+    fn attribute(&self, name: &str) -> Option<String> {
+        self.attributes.iter()
+            .find(|&&(group, ref attribute, _)| group == OPERATION_GROUP && attribute == name)
+            .map(|(_, _, value)| String::from_utf8_lossy(value).to_string())
+    }
+
+    /// Returns the value of the integer operation attribute like `job-id`
+    fn integer(&self, name: &str) -> Option<i32> {
+        self.attributes.iter()
+            .find(|&&(group, ref attribute, ref value)| group == OPERATION_GROUP && attribute == name && value.len() == 4)
+            .map(|(_, _, value)| (u32::from(value[0]) << 24 | u32::from(value[1]) << 16 |
+                u32::from(value[2]) << 8 | u32::from(value[3])) as i32)
+    }
+}
+
+// Reads the field with the 2 bytes length, returns it with the position after it
+fn read_field(data: &[u8], position: usize) -> Result<(&[u8], usize), String> {
+    if position + 2 > data.len() {
+        return Err("The attribute is incomplete".to_string());
+    }
+    let length = (data[position] as usize) << 8 | data[position + 1] as usize;
+    let start = position + 2;
+    if start + length > data.len() {
+        return Err("The attribute is incomplete".to_string());
+    }
+    Ok((&data[start..start + length], start + length))
+}
+
+impl IppResponse {
+    /// Starts the response with the required operation attributes
+    fn new(version: (u8, u8), status: u16, request_id: u32, message: &str) -> IppResponse {
+        let mut response = IppResponse { data: vec![version.0, version.1] };
+        response.data.extend_from_slice(&status.to_be_bytes());
+        response.data.extend_from_slice(&request_id.to_be_bytes());
+        response.group(OPERATION_GROUP)
+            .string(CHARSET, "attributes-charset", "utf-8")
+            .string(LANGUAGE, "attributes-natural-language", "en");
+        if !message.is_empty() {
+            response.string(TEXT, "status-message", message);
+        }
+        response
+    }
+
+    fn group(&mut self, tag: u8) -> &mut IppResponse {
+        self.data.push(tag);
+        self
+    }
+
+    fn value(&mut self, tag: u8, name: &str, value: &[u8]) -> &mut IppResponse {
+        let name = &name.as_bytes()[..name.len().min(MAX_FIELD)];
+        let value = &value[..value.len().min(MAX_FIELD)];
+        self.data.push(tag);
+        self.data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        self.data.extend_from_slice(name);
+        self.data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    /// Adds the text attribute, the value is cut at the character boundary, if it is too long
+    fn string(&mut self, tag: u8, name: &str, value: &str) -> &mut IppResponse {
+        let mut end = value.len().min(MAX_FIELD);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.value(tag, name, &value.as_bytes()[..end])
+    }
+
+    /// Adds the attribute with several values, the additional ones are sent without the name
+    fn strings(&mut self, tag: u8, name: &str, values: &[&str]) -> &mut IppResponse {
+        for (index, value) in values.iter().enumerate() {
+            self.string(tag, if index == 0 { name } else { "" }, value);
+        }
+        self
+    }
+
+    fn integer(&mut self, tag: u8, name: &str, value: i32) -> &mut IppResponse {
+        self.value(tag, name, &value.to_be_bytes())
+    }
+
+    fn finish(&mut self) -> Response {
+        self.data.push(END_OF_ATTRIBUTES);
+        Response::new(200, "application/ipp", self.data.clone())
+    }
+}
+
+
+// Returns the mail token from `Authorization: Basic`, the password is the token and the user name is ignored
+fn basic_token(request: &Request) -> Option<String> {
+    request.header("authorization")
+        .and_then(|value| value.strip_prefix("Basic ").and_then(|credentials| base64::decode(credentials.trim()).ok()))
+        .and_then(|decoded| {
+            let credentials = String::from_utf8_lossy(&decoded).to_string();
+            credentials.split_once(':').map(|(_, password)| password.to_string())
+        })
+        .filter(|token| !token.is_empty())
+}
+
+// IPP has the integer job IDs, they are taken from the beginning of the hexadecimal file ID
+fn job_id(meta: &FileMeta) -> i32 {
+    (u32::from_str_radix(&meta.id[..8.min(meta.id.len())], 16).unwrap_or(0) & 0x7fff_ffff) as i32
+}
+
+fn own_files(config: &Config, token: &str) -> Vec<FileMeta> {
+    let submitter = hash::mask_submitter(token);
+    match spool::list(&config.spool.dir) {
+        Ok(files) => files.into_iter()
+            .filter(|meta| meta.channel == Channel::Ipp && meta.submitter == submitter && meta.derived_from.is_none())
+            .collect(),
+        Err(err) => {
+            error!("{}", err);
+            vec![]
+        }
+    }
+}
+
+fn printer_uri(request: &Request) -> String {
+    format!("ipp://{}{}", request.header("host").unwrap_or("localhost"), PATH)
+}
+
+fn job_attributes(response: &mut IppResponse, request: &Request, meta: &FileMeta) {
+    let (state, reason) = match (meta.printed, meta.rejected) {
+        (Some(_), _) => (JOB_COMPLETED, "job-completed-successfully"),
+        (None, Some(_)) => (JOB_CANCELED, "job-canceled-by-operator"),
+        (None, None) => (JOB_PENDING, "job-hold-until-specified"),
+    };
+    let id = job_id(meta);
+    response.string(URI, "job-uri", &format!("{}/{}", printer_uri(request), id))
+        .integer(INTEGER, "job-id", id)
+        .integer(ENUM, "job-state", state)
+        .string(KEYWORD, "job-state-reasons", reason)
+        .string(NAME, "job-name", &meta.original_name)
+        .string(URI, "job-printer-uri", &printer_uri(request));
+}
+
+fn printer_attributes(ipp: &IppRequest, request: &Request, config: &Config) -> Response {
+    let mut response = IppResponse::new(ipp.version, OK, ipp.request_id, "");
+    let operations: Vec<i32> = vec![PRINT_JOB, VALIDATE_JOB, GET_JOB_ATTRIBUTES, GET_JOBS, GET_PRINTER_ATTRIBUTES]
+        .into_iter().map(i32::from).collect();
+    response.group(PRINTER_GROUP)
+        .string(URI, "printer-uri-supported", &printer_uri(request))
+        .string(KEYWORD, "uri-security-supported", "none")
+        .string(KEYWORD, "uri-authentication-supported", "basic")
+        .string(NAME, "printer-name", &config.ipp.name)
+        .string(TEXT, "printer-info", &config.ipp.name)
+        .string(TEXT, "printer-make-and-model", "Teleprint virtual printer")
+        .integer(ENUM, "printer-state", 3)
+        .string(KEYWORD, "printer-state-reasons", "none")
+        .value(BOOLEAN, "printer-is-accepting-jobs", &[1])
+        .strings(KEYWORD, "ipp-versions-supported", &["1.1", "2.0"]);
+    for (index, operation) in operations.iter().enumerate() {
+        response.integer(ENUM, if index == 0 { "operations-supported" } else { "" }, *operation);
+    }
+    response.string(CHARSET, "charset-configured", "utf-8")
+        .string(CHARSET, "charset-supported", "utf-8")
+        .string(LANGUAGE, "natural-language-configured", "en")
+        .string(LANGUAGE, "generated-natural-language-supported", "en")
+        .string(MIME_TYPE, "document-format-default", "application/pdf")
+        .strings(MIME_TYPE, "document-format-supported", &FORMATS)
+        .string(KEYWORD, "compression-supported", "none")
+        .string(KEYWORD, "pdl-override-supported", "not-attempted")
+        .integer(INTEGER, "queued-job-count", 0)
+        .integer(INTEGER, "printer-up-time", Utc::now().timestamp() as i32);
+    response.finish()
+}
+
+// Sends the document to the admin for the approval like the files from the bots
+fn print_job(ipp: &IppRequest, request: &Request, token: &str, a_config: &Arc<Mutex<Config>>,
+             a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    let config = { a_config.lock().unwrap().clone() };
+    let error = |status: u16, message: &str| IppResponse::new(ipp.version, status, ipp.request_id, message).finish();
+
+    let format = ipp.attribute("document-format").unwrap_or("application/octet-stream".to_string());
+    if !FORMATS.contains(&format.as_str()) {
+        return error(FORMAT_NOT_SUPPORTED, &format!("Documents of type {} are not supported", format));
+    }
+    if let Err(err) = pipeline::check_quota(&config, Channel::Ipp, &hash::mask_submitter(token), None) {
+        return error(NOT_POSSIBLE, &err);
+    }
+    if ipp.operation == VALIDATE_JOB {
+        return IppResponse::new(ipp.version, OK, ipp.request_id, "").finish();
+    }
+    if ipp.document.is_empty() {
+        return error(BAD_REQUEST, "No document was sent");
+    }
+
+    let name = ipp.attribute("job-name").or_else(|| ipp.attribute("document-name")).unwrap_or("IPP job".to_string());
+    let attachment = Attachment::Document { name, data: ipp.document.clone() };
+    let meta = match pipeline::submit(IppChannel.request(Identity::MailToken(token.to_string()), attachment,
+                                                         transform::Options::default()), a_config, a_users_table) {
+        Ok(data) => data,
+        Err(err) => return error(NOT_POSSIBLE, &err),
+    };
+
+    let mut response = IppResponse::new(ipp.version, OK, ipp.request_id, "The document waits for the approval");
+    response.group(JOB_GROUP);
+    job_attributes(&mut response, request, &meta);
+    response.finish()
+}
+
+fn get_jobs(ipp: &IppRequest, request: &Request, token: &str, config: &Config) -> Response {
+    let files = own_files(config, token);
+    let files: Vec<&FileMeta> = match ipp.operation {
+        GET_JOB_ATTRIBUTES => {
+            let id = ipp.integer("job-id").or_else(|| {
+                ipp.attribute("job-uri").and_then(|uri| uri.rsplit('/').next().and_then(|id| id.parse::<i32>().ok()))
+            });
+            match files.iter().find(|meta| Some(job_id(meta)) == id) {
+                Some(meta) => vec![meta],
+                None => return IppResponse::new(ipp.version, NOT_FOUND, ipp.request_id, "No such job").finish(),
+            }
+        }
+        _ => files.iter().collect(),
+    };
+
+    let mut response = IppResponse::new(ipp.version, OK, ipp.request_id, "");
+    for meta in files {
+        response.group(JOB_GROUP);
+        job_attributes(&mut response, request, meta);
+    }
+    response.finish()
+}
+
+
+fn handle(request: &Request, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    if request.method != "POST" {
+        return Response::text(405, "IPP requests are sent with POST");
+    }
+    if request.path != "/" && request.path.trim_end_matches('/') != PATH {
+        return Response::text(404, "Not found");
+    }
+    if request.header("content-type").is_none_or(|value| !value.starts_with("application/ipp")) {
+        return Response::text(400, "Content-Type should be application/ipp");
+    }
+    let ipp = match IppRequest::parse(&request.body) {
+        Ok(data) => data,
+        Err(err) => return Response::text(400, &err),
+    };
+    if ipp.version.0 != 1 && ipp.version.0 != 2 {
+        return IppResponse::new((1, 1), VERSION_NOT_SUPPORTED, ipp.request_id, "Only IPP 1.1 and 2.0 are supported").finish();
+    }
+
+    let config = { a_config.lock().unwrap().clone() };
+    // the clients discover the printer before asking for the password
+    if ipp.operation == GET_PRINTER_ATTRIBUTES {
+        return printer_attributes(&ipp, request, &config);
+    }
+    match ipp.operation {
+        PRINT_JOB | VALIDATE_JOB | GET_JOB_ATTRIBUTES | GET_JOBS => (),
+        _ => return IppResponse::new(ipp.version, OPERATION_NOT_SUPPORTED, ipp.request_id, "").finish(),
+    };
+
+    let token = match basic_token(request) {
+        Some(ref data) if a_users_table.lock().unwrap().check_token(data.clone()) => data.clone(),
+        other => {
+            // the clients ask for the password only after the first request without it
+            if let Some(token) = other {
+                audit::record(&hash::mask_token(&token), Channel::Ipp, audit::Action::Unauthorized, &request.path);
+                thread::sleep(Duration::from_secs(AUTH_DELAY));
+            }
+            return Response::text(401, "Unknown token")
+                .with_header("WWW-Authenticate", &format!("Basic realm=\"{}\"", config.ipp.name));
+        }
+    };
+
+    match ipp.operation {
+        PRINT_JOB | VALIDATE_JOB if supervisor::shutting_down() =>
+            IppResponse::new(ipp.version, SERVICE_UNAVAILABLE, ipp.request_id, "Teleprint is restarting").finish(),
+        PRINT_JOB | VALIDATE_JOB => print_job(&ipp, request, &token, a_config, a_users_table),
+        _ => get_jobs(&ipp, request, &token, &config),
+    }
+}
+
+/// Runs the IPP listener, if it is enabled in the `[ipp]` section
+///
+/// Laptops and phones add it as the IPP printer and print to it natively. The clients authenticate
+/// with the HTTP basic auth, the password is the mail token. The listener speaks plain HTTP, so the token
+/// is protected only by the TLS proxy in front of it. The documents go to the admin
+/// for the approval like the files from Telegram and mail, and are printed on the real printer after it.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let users_table = Arc::new(Mutex::new(read_users().unwrap()));
+/// let ipp = thread::spawn(move || {
+///        run_server(Arc::clone(&config), Arc::clone(&users_table));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if !config.ipp.enabled {
+        return;
+    }
+
+    let max_body = if config.ipp.max_size == 0 {
+        http::MAX_BODY
+    } else {
+        config.ipp.max_size.saturating_mul(1024 * 1024).saturating_add(REQUEST_OVERHEAD)
+    };
+    let handler: Handler = Arc::new(move |request: &Request| handle(request, &a_config, &a_users_table));
+    match http::serve(&config.ipp.address, max_body, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the IPP listener: {}", err),
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds the request with the response builder, both have the same layout
+    fn request(document: &[u8]) -> Vec<u8> {
+        let mut builder = IppResponse::new((2, 0), PRINT_JOB, 7, "");
+        builder.integer(INTEGER, "job-id", 42)
+            .strings(KEYWORD, "sides", &["one-sided", "two-sided-long-edge"])
+            .group(JOB_GROUP)
+            .string(NAME, "job-name", "report.pdf");
+        let mut data = builder.finish().body;
+        data.extend_from_slice(document);
+        data
+    }
+
+    #[test]
+    fn parses_request() {
+        let ipp = IppRequest::parse(&request(b"%PDF-1.4")).unwrap();
+        assert_eq!(ipp.version, (2, 0));
+        assert_eq!(ipp.operation, PRINT_JOB);
+        assert_eq!(ipp.request_id, 7);
+        assert_eq!(ipp.attribute("attributes-charset"), Some("utf-8".to_string()));
+        assert_eq!(ipp.integer("job-id"), Some(42));
+        assert_eq!(ipp.document, b"%PDF-1.4".to_vec());
+
+        // the additional value repeats the name, the job attributes are not the operation ones
+        let sides: Vec<&[u8]> = ipp.attributes.iter()
+            .filter(|&(_, name, _)| name == "sides")
+            .map(|(_, _, value)| &value[..])
+            .collect();
+        assert_eq!(sides, vec![&b"one-sided"[..], &b"two-sided-long-edge"[..]]);
+        assert_eq!(ipp.attribute("job-name"), None);
+        assert!(ipp.attributes.contains(&(JOB_GROUP, "job-name".to_string(), b"report.pdf".to_vec())));
+    }
+
+    #[test]
+    fn rejects_incomplete_request() {
+        let data = request(b"");
+        // cut in the header, in the middle of the field or before the end of the attributes
+        for end in 0..data.len() {
+            assert!(IppRequest::parse(&data[..end]).is_err(), "{} bytes were accepted", end);
+        }
+        // the length of the name points past the end
+        let mut data = data;
+        data[10] = 0xff;
+        assert!(IppRequest::parse(&data).is_err());
+    }
+
+    #[test]
+    fn cuts_long_values() {
+        let long = "ü".repeat(40000);
+        let mut builder = IppResponse::new((1, 1), OK, 1, &long);
+        let ipp = IppRequest::parse(&builder.finish().body).unwrap();
+        let message = ipp.attribute("status-message").unwrap();
+        assert_eq!(message.len(), MAX_FIELD - 1);
+        assert!(message.chars().all(|character| character == 'ü'));
+    }
+}
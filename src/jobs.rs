@@ -0,0 +1,130 @@
+extern crate chrono;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use self::chrono::Utc;
+
+use alerts::{self, Alert, Event};
+use config::Config;
+use pipeline;
+use printer;
+use spool::{self, FileMeta};
+use transform;
+
+/// Spooled file, which was sent to the printer
+pub struct Printed {
+    pub meta: FileMeta,
+    /// Transformed copy, which was printed instead of the file, if the options were given
+    pub derived: Option<FileMeta>,
+    /// CUPS job ID, which can be canceled
+    pub job: Option<String>,
+}
+
+impl Printed {
+    /// Returns the file ID with the ID of the transformed copy, used in the audit log
+    pub fn target(&self) -> String {
+        match self.derived {
+            Some(ref data) => format!("{} ({})", self.meta.id, data.id),
+            None => self.meta.id.clone(),
+        }
+    }
+}
+
+
+// Transforms the spooled file and stores the result as the new file, derived from it
+fn derive_file(meta: &FileMeta, options: &transform::Options, spool_dir: &str) -> Result<FileMeta, String> {
+    let data = match fs::read(spool::file_path(spool_dir, meta)) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on reading the file: {}", err)),
+    };
+
+    let transformed = transform::transform(&data, options)?;
+    let mut derived = spool::store(spool_dir, transformed, &format!("{} ({})", meta.original_name, options.describe()),
+                                   "application/pdf", meta.channel, &meta.submitter)?;
+    derived.derived_from = Some(meta.id.clone());
+    spool::write_meta(spool_dir, &derived)?;
+    Ok(derived)
+}
+
+// Notifies the submitter in the background through its channel
+fn notify_submitter(meta: &FileMeta, config: &Config, text: String) {
+    let notifier = pipeline::notifier(meta, config);
+    thread::spawn(move || notifier.notify(&text));
+}
+
+
+/// Prints the spooled file with the options of `/print`, marks it printed and notifies the submitter
+///
+/// If the options change the file, its transformed copy is stored and printed instead.
+///
+/// # Examples
+///
+/// ```rust
+/// let options = transform::Options::parse(&["pages=1-4", "nup=2"]).unwrap();
+/// let printed = print("4f2a9c01d3e5b768", &options, Arc::clone(&config)).unwrap();
+/// println!("{:?}", printed.job); // Some("Office-42")
+/// ```
+pub fn print(file_id: &str, options: &transform::Options, a_config: Arc<Mutex<Config>>) -> Result<Printed, String> {
+    let config = { a_config.lock().unwrap().clone() };
+    let meta = spool::read_meta(&config.spool.dir, file_id)?;
+
+    let derived = if options.is_identity() {
+        None
+    } else {
+        match derive_file(&meta, options, &config.spool.dir) {
+            Ok(data) => Some(data),
+            Err(err) => return Err(format!("Error on transforming the file: {}", err)),
+        }
+    };
+
+    let job = {
+        let printed = derived.as_ref().unwrap_or(&meta);
+        match printer::print_from_file(&spool::file_path(&config.spool.dir, printed), printed, a_config) {
+            Ok(data) => data,
+            Err(err) => {
+                alerts::notify(&config, Alert::new(Event::PrinterError, &format!("Error on printing {}: {}", printed.id, err))
+                    .with_file(&printed.id));
+                return Err(err);
+            }
+        }
+    };
+    let job = if job.is_empty() { None } else { Some(job) };
+
+    let now = Utc::now().timestamp();
+    let mut result = Printed { meta, derived, job };
+    for printed in vec![Some(&mut result.meta), result.derived.as_mut()].into_iter().flatten() {
+        printed.printed = Some(now);
+        printed.job = result.job.clone();
+        match spool::write_meta(&config.spool.dir, printed) {
+            Ok(_) => (),
+            Err(err) => error!("{}", err),
+        };
+    }
+
+    notify_submitter(&result.meta, &config, "Your file has been started printing".to_string());
+    let text = match result.job {
+        Some(ref job) => format!("File {} has been sent to {}, job {}", result.target(), config.printer, job),
+        None => format!("File {} has been sent to {}", result.target(), config.printer),
+    };
+    alerts::notify(&config, Alert::new(Event::JobDone, &text).with_file(&result.meta.id));
+    Ok(result)
+}
+
+/// Rejects the spooled file and notifies the submitter
+///
+/// The file is deleted if `delete_rejected` is set and it isn't pinned, otherwise it is marked rejected.
+pub fn reject(file_id: &str, config: &Config) -> Result<FileMeta, String> {
+    let mut meta = spool::read_meta(&config.spool.dir, file_id)?;
+
+    if config.spool.delete_rejected && !meta.pinned {
+        spool::delete(&config.spool.dir, &meta.id)?;
+    } else {
+        meta.rejected = Some(Utc::now().timestamp());
+        spool::write_meta(&config.spool.dir, &meta)?;
+    }
+
+    notify_submitter(&meta, config, format!("Your file {} has been rejected", meta.original_name));
+    Ok(meta)
+}
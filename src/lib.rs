@@ -1,4 +1,8 @@
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+#[macro_use]
 extern crate serde_derive;
 
 pub mod io_tools;
@@ -8,4 +12,26 @@ pub mod config;
 pub mod printer;
 pub mod hash;
 pub mod downloader;
-pub mod mailbot;
\ No newline at end of file
+pub mod mailbot;
+pub mod spool;
+pub mod cleaner;
+pub mod convert;
+pub mod preview;
+pub mod inspect;
+pub mod transform;
+pub mod stamp;
+pub mod logger;
+pub mod audit;
+pub mod http;
+pub mod metrics;
+pub mod health;
+pub mod supervisor;
+pub mod jobs;
+pub mod pipeline;
+pub mod dashboard;
+pub mod api;
+pub mod upload;
+pub mod ipp;
+pub mod matrix;
+pub mod alerts;
+pub mod webhook;
\ No newline at end of file
@@ -0,0 +1,193 @@
+extern crate chrono;
+extern crate log;
+
+use std::fs::{File, OpenOptions, metadata, rename};
+use std::io::{Write, stderr};
+use std::sync::Mutex;
+
+use self::chrono::Local;
+use self::log::{LevelFilter, Log, Metadata, Record};
+
+use config::{Config, LogConfig, LogFormat};
+
+const REDACTED: &str = "<redacted>";
+
+
+struct Output {
+    file: Option<File>,
+    size: u64,
+}
+
+struct Logger {
+    config: LogConfig,
+    level: LevelFilter,
+    secrets: Vec<String>,
+    output: Mutex<Output>,
+}
+
+/// Hides the secrets and the bot tokens in the Telegram URLs (`/bot<id>:<token>/`) in the text
+///
+/// # Examples
+///
+/// ```rust
+/// let text = redact("https://api.telegram.org/bot123456:ABC-def/getFile", &[]);
+/// println!("{}", text); // https://api.telegram.org/bot<redacted>/getFile
+/// ```
+pub fn redact(text: &str, secrets: &[String]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find("/bot") {
+        result.push_str(&rest[..index + 4]);
+        rest = &rest[index + 4..];
+
+        let id_length = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if id_length > 0 && rest[id_length..].starts_with(':') {
+            let token = &rest[id_length + 1..];
+            let token_length = token.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(token.len());
+            result.push_str(REDACTED);
+            rest = &token[token_length..];
+        }
+    }
+    result.push_str(rest);
+
+    for secret in secrets.iter().filter(|secret| !secret.is_empty()) {
+        result = result.replace(secret.as_str(), REDACTED);
+    }
+    result
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn open_file(path: &str) -> Option<File> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(err) => {
+            let _ = writeln!(stderr(), "Error on opening the log file {}: {}", path, err);
+            None
+        }
+    }
+}
+
+impl Logger {
+    fn format(&self, record: &Record) -> String {
+        let time = Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z");
+        let message = redact(&format!("{}", record.args()), &self.secrets);
+        match self.config.format {
+            LogFormat::Plain => format!("{} {:<5} [{}] {}\n", time, record.level(), record.target(), message),
+            LogFormat::Json => format!("{{\"time\":\"{}\",\"level\":\"{}\",\"target\":\"{}\",\"message\":\"{}\"}}\n",
+                                       time, record.level(), escape_json(record.target()), escape_json(&message)),
+        }
+    }
+
+    // Renames `<file>` to `<file>.1`, `<file>.1` to `<file>.2` and so on, the oldest one is overwritten
+    fn rotate(&self, output: &mut Output) {
+        output.file = None;
+        for number in (1..self.config.keep).rev() {
+            let _ = rename(format!("{}.{}", self.config.file, number), format!("{}.{}", self.config.file, number + 1));
+        }
+        if self.config.keep > 0 {
+            let _ = rename(&self.config.file, format!("{}.1", self.config.file));
+        } else {
+            let _ = File::create(&self.config.file);
+        }
+        output.file = open_file(&self.config.file);
+        output.size = 0;
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+
+        let mut output = self.output.lock().unwrap();
+        if self.config.file.is_empty() {
+            let _ = stderr().write_all(line.as_bytes());
+            return;
+        }
+
+        let max_size = self.config.max_size * 1024 * 1024;
+        if max_size > 0 && output.size + line.len() as u64 > max_size {
+            self.rotate(&mut output);
+        }
+        let written = match output.file {
+            Some(ref mut file) => file.write_all(line.as_bytes()).is_ok(),
+            None => false,
+        };
+        if written {
+            output.size += line.len() as u64;
+        } else {
+            let _ = stderr().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(ref mut file) = self.output.lock().unwrap().file {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Sets up logging by the `[log]` section of the config
+///
+/// Messages are written to the `file` (to stderr if it is empty) in the plain or JSON format,
+/// the file is rotated after `max_size` megabytes and `keep` old files are kept.
+/// The bot token, the IMAP password, the Matrix access token, the XMPP password and the webhook secret
+/// are replaced with `<redacted>` in every message.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = read_config().unwrap();
+/// logger::init(&config).unwrap();
+/// info!("Bot started");
+/// ```
+pub fn init(config: &Config) -> Result<(), String> {
+    let level = match config.log.level.parse::<LevelFilter>() {
+        Ok(data) => data,
+        Err(_) => return Err(format!("Unknown log level: {}", config.log.level)),
+    };
+
+    let (file, size) = if config.log.file.is_empty() {
+        (None, 0)
+    } else {
+        (open_file(&config.log.file), metadata(&config.log.file).map(|data| data.len()).unwrap_or(0))
+    };
+
+    let logger = Logger {
+        config: config.log.clone(),
+        level,
+        secrets: vec![config.token.clone(), config.imap.password.clone(), config.matrix.access_token.clone(),
+                      config.alerts.xmpp.password.clone(), config.telegram.secret_token.clone()],
+        output: Mutex::new(Output { file, size }),
+    };
+
+    match log::set_boxed_logger(Box::new(logger)) {
+        Ok(_) => {
+            log::set_max_level(level);
+            Ok(())
+        }
+        Err(err) => Err(format!("Error on setting up the logger: {}", err)),
+    }
+}
@@ -2,31 +2,48 @@ extern crate imap;
 extern crate mailparse;
 extern crate native_tls;
 
+use std::fmt;
 use std::net::TcpStream;
 use std::option::Option;
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time;
 
-use bot;
 use config::Config;
 use database;
-use hash;
-use io_tools;
-
+use downloader;
+use health;
+use metrics;
+use pipeline::{self, Identity, IngestChannel, Notifier, Silent};
+use spool;
+use supervisor;
+use transform;
+
+use self::mailparse::MailHeaderMap;
 use self::native_tls::{TlsConnector, TlsStream};
 
 #[derive(Debug)]
 struct Message {
     body: Option<Vec<u8>>,
-    header: Option<Vec<u8>>,
-    text: Option<Vec<u8>>,
+}
+
+struct Attachment {
+    name: String,
+    mime: String,
+    data: Vec<u8>,
+}
+
+impl fmt::Debug for Attachment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Attachment {{ name: {:?}, mime: {:?}, size: {} }}", self.name, self.mime, self.data.len())
+    }
 }
 
 #[derive(Debug)]
 struct ProccessedMessage {
+    /// Address of the sender from the `From` header
+    from: Option<String>,
     text: Option<String>,
-    filename: Option<String>,
+    attachment: Option<Attachment>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -44,10 +61,7 @@ enum GetLatestError {
 }
 
 fn vectorize(data: Option<&[u8]>) -> Option<Vec<u8>> {
-    match data {
-        Some(d) => Some(Vec::from(d)),
-        None => None
-    }
+    data.map(Vec::from)
 }
 
 fn get_latest(session: &mut imap::Session<TlsStream<TcpStream>>) -> Result<Vec<Message>, GetLatestError> {
@@ -55,14 +69,14 @@ fn get_latest(session: &mut imap::Session<TlsStream<TcpStream>>) -> Result<Vec<M
     match session.select("INBOX") {
         Ok(_) => (),
         Err(err) => {
-            eprintln!("Error on selecting INBOX: {:?}", err);
+            error!("Error on selecting INBOX: {:?}", err);
             return Err(GetLatestError::Select(SelectError::BrokenPipe));
         }
     };
     let news = match session.search("UNSEEN") {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("Error on getting latest messages: {:?}", err);
+            error!("Error on getting latest messages: {:?}", err);
             return Err(GetLatestError::Search);
         }
     };
@@ -71,21 +85,19 @@ fn get_latest(session: &mut imap::Session<TlsStream<TcpStream>>) -> Result<Vec<M
         let buff = match session.fetch(format!("{}", x), "RFC822") {
             Ok(data) => data,
             Err(err) => {
-                eprintln!("Error getting {} message: {:?}", x, err);
+                error!("Error getting {} message: {:?}", x, err);
                 continue;
             }
         };
         //println!("{:?}", buff);
-        if buff.len() > 0 {
+        if !buff.is_empty() {
             messages.push(Message {
                 body: vectorize(buff[0].body()),
-                header: vectorize(buff[0].header()),
-                text: vectorize(buff[0].text()),
             });
             match session.store(format!("{}", x), "+FLAGS.SILENT (\\Seen)") {
-                Ok(_) => print!(""),
+                Ok(_) => (),
                 Err(err) => {
-                    eprintln!("Error on marking as seen: {}", err);
+                    error!("Error on marking as seen: {}", err);
                     messages.pop();
                 }
             };
@@ -104,7 +116,7 @@ fn init(a_config: &Arc<Mutex<Config>>) -> Result<imap::Session<TlsStream<TcpStre
     let tls = match TlsConnector::builder().build() {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("Tls error: {:?}", err);
+            error!("Tls error: {:?}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -113,7 +125,7 @@ fn init(a_config: &Arc<Mutex<Config>>) -> Result<imap::Session<TlsStream<TcpStre
                                      server.as_str(), &tls) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("IMAP connection error: {:?}", err);
+            error!("IMAP connection error: {:?}", err);
             return Err(format!("{:?}", err));
         }
     };
@@ -121,10 +133,40 @@ fn init(a_config: &Arc<Mutex<Config>>) -> Result<imap::Session<TlsStream<TcpStre
     match client.login(&user, &password) {
         Ok(data) => Ok(data),
         Err(err) => {
-            eprintln!("IMAP session error: {:?}", err);
-            return Err(format!("{:?}", err));
+            error!("IMAP session error: {:?}", err);
+            Err(format!("{:?}", err))
+        }
+    }
+}
+
+
+/// Connects and logs in to the IMAP server once, returns error on Err()
+pub fn check_connection(a_config: &Arc<Mutex<Config>>) -> Result<(), String> {
+    let mut session = init(a_config)?;
+    match session.logout() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("{:?}", err)),
+    }
+}
+
+
+fn attachment_name(part: &mailparse::ParsedMail) -> Option<String> {
+    for header in &["Content-Disposition", "Content-Type"] {
+        let value = match part.headers.get_first_value(header) {
+            Ok(Some(data)) => data,
+            _ => continue,
+        };
+        for param in value.split(';') {
+            let mut pair = param.trim().splitn(2, '=');
+            let key = pair.next().unwrap_or("").trim().to_lowercase();
+            if key == "filename" || key == "name" {
+                if let Some(name) = pair.next() {
+                    return Some(name.trim().trim_matches('"').to_string());
+                }
+            }
         }
     }
+    None
 }
 
 
@@ -132,74 +174,67 @@ fn process(message_body: Vec<u8>) -> Option<ProccessedMessage> {
     let parsed = match mailparse::parse_mail(message_body.as_slice()) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("{:?}", err);
+            error!("{:?}", err);
             return None;
         }
     };
-    let mut proccessed = ProccessedMessage { text: None, filename: None };
+    let from = parsed.headers.get_first_value("From").unwrap_or_default();
+    let mut proccessed = ProccessedMessage { from, text: None, attachment: None };
     for x in parsed.subparts {
         if x.ctype.mimetype.as_str() == "application/pdf" {
+            let name = match attachment_name(&x) {
+                Some(name) => name,
+                None => "attachment.pdf".to_string(),
+            };
             match x.get_body_raw() {
-                Ok(data) => {
-                    let filename = format!("{}.pdf", hash::hash_data(&data));
-                    match io_tools::write_bytes_to_file(&filename, data) {
-                        Ok(_) => proccessed.filename = Some(filename),
-                        Err(err) => eprintln!("Error on writing file: {:?}", err),
-                    }
-                }
-                Err(err) => eprintln!("Error on getting the body: {:?}", err),
+                Ok(data) => proccessed.attachment = Some(Attachment {
+                    name,
+                    mime: x.ctype.mimetype.clone(),
+                    data,
+                }),
+                Err(err) => error!("Error on getting the body: {:?}", err),
             }
         } else {
             for y in x.subparts {
                 if y.ctype.mimetype.as_str() == "text/plain" {
                     match y.get_body() {
                         Ok(data) => proccessed.text = Some(data.trim().to_string()),
-                        Err(err) => eprintln!("Error on getting the body: {:?}", err),
+                        Err(err) => error!("Error on getting the body: {:?}", err),
                     }
                 }
             }
         }
     }
-    return Some(proccessed);
+    Some(proccessed)
 }
 
 
-fn send_file(filename: String, user_token: String, a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) -> Result<(), String> {
-    let (admin, bot_token, access) = {
-        let users_table = a_users_table.lock().unwrap();
-        (users_table.get_admin().clone(),
-         a_config.lock().unwrap().token.clone(),
-         users_table.check_token(user_token.clone()))
-    };
+/// Mail as the source of the print requests, the letters are not answered
+pub struct MailChannel;
 
-    if !access {
-        return Err(format!("No access: {}", &user_token));
+impl IngestChannel for MailChannel {
+    fn channel(&self) -> spool::Channel {
+        spool::Channel::Mail
     }
 
-    println!("We are at file send2;");
-    let _child = thread::spawn(move || {
-        println!("We are at thread;");
-        match bot::send_message(&bot_token, admin, &format!("Mail user {} wants to print:", user_token)) {
-            Ok(_) => print!(""),
-            Err(err) => eprintln!("Error on sending message: {:?}", err),
-        };
-
-        match bot::send_message(&bot_token, admin, &format!("{}", filename)) {
-            Ok(_) => print!(""),
-            Err(err) => eprintln!("Error on sending message: {:?}", err),
-        };
+    fn notifier(&self, _identity: &Identity) -> Box<dyn Notifier> {
+        Box::new(Silent)
+    }
+}
 
-        match bot::send_document(&bot_token, admin, &format!("{}", filename)) {
-            Ok(_) => print!(""),
-            Err(err) => eprintln!("Error on sending document: {:?}", err),
-        };
-    });
 
-    Ok(())
+fn send(attachment: pipeline::Attachment, user_token: &str, a_config: &Arc<Mutex<Config>>,
+        a_users_table: &Arc<Mutex<database::UsersTable>>) -> Result<(), String> {
+    let request = MailChannel.request(Identity::MailToken(user_token.to_string()), attachment, transform::Options::default());
+    pipeline::submit(request, a_config, a_users_table).map(|_| ())
 }
 
+
 fn react(message: ProccessedMessage, a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
-    println!("{:?}", message);
+    // the text starts with the mail token, so it isn't logged
+    debug!("Letter from {}, {} characters of text, attachment: {:?}",
+           message.from.as_ref().map_or("unknown sender", |from| from.as_str()),
+           message.text.as_ref().map_or(0, |text| text.len()), message.attachment);
     let text = match message.text {
         Some(data) => data,
         None => return,
@@ -209,15 +244,37 @@ fn react(message: ProccessedMessage, a_config: Arc<Mutex<Config>>, a_users_table
         return;
     }
 
-
-    let result = match message.filename {
-        Some(filename) => send_file(filename, text, a_config, a_users_table),
+    let mut words = text.split_whitespace();
+    let token = match words.next() {
+        Some(data) => data.to_string(),
         None => return,
     };
 
-    match result {
-        Ok(_) => return,
-        Err(err) => eprintln!("{}", err),
+    // `<token> printtext [options]` on the first line, the rest of the letter is printed as text
+    let rest = text.trim_start()[token.len()..].trim_start_matches([' ', '\t']);
+    if rest.split_whitespace().next() == Some("printtext") && message.attachment.is_none() {
+        let author = message.from.unwrap_or("unknown sender".to_string());
+        let attachment = pipeline::Attachment::Text { text: rest["printtext".len()..].to_string(), author };
+        match send(attachment, &token, &a_config, &a_users_table) {
+            Ok(_) => (),
+            Err(err) => error!("{}", err),
+        };
+        return;
+    }
+
+    if let Some(attachment) = message.attachment {
+        let attachment = pipeline::Attachment::File { name: attachment.name, mime: attachment.mime, data: attachment.data };
+        match send(attachment, &token, &a_config, &a_users_table) {
+            Ok(_) => (),
+            Err(err) => error!("{}", err),
+        };
+    }
+
+    for url in words.filter(|word| downloader::is_link(word)) {
+        match send(pipeline::Attachment::Link(url.to_string()), &token, &a_config, &a_users_table) {
+            Ok(_) => (),
+            Err(err) => error!("{}", err),
+        };
     }
 }
 
@@ -236,33 +293,51 @@ fn react(message: ProccessedMessage, a_config: Arc<Mutex<Config>>, a_users_table
 ///  });
 /// ```
 pub fn run_bot(config: Arc<Mutex<Config>>, users_table: Arc<Mutex<database::UsersTable>>) {
-    if {config.lock().unwrap()}.imap.server == "NONE".to_string() {
+    if {config.lock().unwrap()}.imap.server == "NONE" {
+        health::set_state(health::IMAP, health::State::Disabled);
         return;
     }
+    let _guard = health::running(health::IMAP);
 
     let mut session = match init(&config) {
         Ok(data) => data,
         Err(err) => {
-            eprintln!("IMAP init error: {:?}", err);
+            error!("IMAP init error: {:?}", err);
+            health::record_error(health::IMAP, &err);
             return;
         }
     };
-    println!("Session ok");
+    info!("IMAP session ok");
 
     'main_loop: loop {
-        let buff = match get_latest(&mut session) {
-            Ok(data) => data,
+        if supervisor::shutting_down() {
+            let _ = session.logout();
+            return;
+        }
+        // the letters, fetched before the shutdown, are processed till the end
+        let _task = supervisor::task();
+        let started = time::Instant::now();
+        let latest = get_latest(&mut session);
+        metrics::observe(metrics::IMAP_POLL_DURATION, &[], started.elapsed());
+        let buff = match latest {
+            Ok(data) => {
+                health::record_ok(health::IMAP);
+                data
+            }
             Err(err) => {
-                eprintln!("Get latest error: {:?}", err);
+                error!("Get latest error: {:?}", err);
+                health::record_error(health::IMAP, &format!("{:?}", err));
                 if err == GetLatestError::Select(SelectError::BrokenPipe) {
+                    metrics::inc(metrics::IMAP_RECONNECTS, &[]);
                     session = match init(&config) {
                         Ok(data) => data,
                         Err(err) => {
-                            eprintln!("IMAP init error: {:?}", err);
+                            error!("IMAP init error: {:?}", err);
+                            health::record_error(health::IMAP, &err);
                             continue 'main_loop;
                         }
                     };
-                    println!("Session ok");
+                    info!("IMAP session ok");
                 }
                 continue 'main_loop;
             }
@@ -279,6 +354,6 @@ pub fn run_bot(config: Arc<Mutex<Config>>, users_table: Arc<Mutex<database::User
                 None => continue,
             };
         }
-        thread::sleep(time::Duration::from_secs(1));
+        supervisor::sleep(time::Duration::from_secs(1));
     }
 }
\ No newline at end of file
@@ -1,11 +1,41 @@
+#[macro_use]
+extern crate log;
 extern crate teleprint;
 
 use std::env;
-use std::sync::{Arc, Mutex};
+use std::process;
 use std::thread;
+use std::sync::{Arc, Mutex};
 
-use teleprint::config::read_config;
+use teleprint::config::{read_config, Transport};
 use teleprint::database::read_users;
+use teleprint::health;
+use teleprint::supervisor::{self, Worker};
+
+// Runs the health checks once with the real connections to Telegram and IMAP, returns true if all passed
+fn check() -> bool {
+    let config = match read_config() {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Error on reading the config: {}", err);
+            return false;
+        }
+    };
+
+    let mut checks = vec![health::probe_telegram(&config.token)];
+    if config.imap.server != "NONE" {
+        let a_config = Arc::new(Mutex::new(config.clone()));
+        checks.push(match teleprint::mailbot::check_connection(&a_config) {
+            Ok(_) => health::Check::new(health::IMAP, true, "login ok".to_string()),
+            Err(err) => health::Check::new(health::IMAP, false, err),
+        });
+    }
+    checks.push(health::check_printer(&config.printer));
+    checks.push(health::check_spool(&config.spool.dir, config.health.min_free_space));
+
+    println!("{}", health::report(&checks));
+    health::is_healthy(&checks)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -17,6 +47,22 @@ fn main() {
                 return;
             }
             "--noimap" => run_imap = false,
+            "check" => process::exit(if check() { 0 } else { 1 }),
+            "--hash-password" => {
+                let password = teleprint::io_tools::read_std_line("Enter password: ");
+                println!("{}", teleprint::hash::hash_password(&password));
+                return;
+            }
+            "--audit-export" => {
+                match teleprint::audit::export(args.get(2).map_or("csv", |format| format.as_str())) {
+                    Ok(_) => (),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        process::exit(1);
+                    }
+                };
+                return;
+            }
             _ => {
                 println!("Unknown argument, exiting");
                 return;
@@ -24,21 +70,127 @@ fn main() {
         }
     }
     let users_table = Arc::new(Mutex::new(read_users().unwrap()));
-    let config = Arc::new(Mutex::new(read_config().unwrap()));
+    let config = read_config().unwrap();
+    match teleprint::logger::init(&config) {
+        Ok(_) => info!("Starting teleprint"),
+        Err(err) => eprintln!("{}", err),
+    };
+    let config = Arc::new(Mutex::new(config));
+
+    supervisor::install_signal_handlers();
+    {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        thread::spawn(move || teleprint::bot::restore_pending(a_config, a_users));
+    }
+
+    let mut workers: Vec<Worker> = vec![];
+
+    {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("Telegram bot", move || {
+            teleprint::bot::run_bot(Arc::clone(&a_config),
+                                    Arc::clone(&a_users));
+        }));
+    }
+
+    let webhook_enabled = { config.lock().unwrap().telegram.transport == Transport::Webhook };
+    if webhook_enabled {
+        let a_config = Arc::clone(&config);
+        workers.push(Worker::new("Telegram webhook", move || {
+            teleprint::webhook::run_server(Arc::clone(&a_config));
+        }));
+    }
 
-    if run_imap {
+    let imap_server = { config.lock().unwrap().imap.server.clone() };
+    if run_imap && imap_server != "NONE" {
         let a_config = Arc::clone(&config);
         let a_users = Arc::clone(&users_table);
-        let _imap_bot = thread::spawn(move || {
+        workers.push(Worker::new("IMAP bot", move || {
             teleprint::mailbot::run_bot(Arc::clone(&a_config),
                                         Arc::clone(&a_users));
-        });
+        }));
+    } else {
+        health::set_state(health::IMAP, health::State::Disabled);
     }
 
-    let tele_bot = thread::spawn(move || {
-        teleprint::bot::run_bot(Arc::clone(&config),
-                                Arc::clone(&users_table));
-    });
+    {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("cleaner", move || {
+            teleprint::cleaner::run_cleaner(Arc::clone(&a_config),
+                                            Arc::clone(&a_users));
+        }));
+    }
+
+    let health_enabled = { config.lock().unwrap().health.enabled };
+    if health_enabled {
+        let a_config = Arc::clone(&config);
+        workers.push(Worker::new("health server", move || {
+            health::run_server(Arc::clone(&a_config));
+        }));
+    }
+
+    let metrics_enabled = { config.lock().unwrap().metrics.enabled };
+    if metrics_enabled {
+        let a_config = Arc::clone(&config);
+        workers.push(Worker::new("metrics server", move || {
+            teleprint::metrics::run_server(Arc::clone(&a_config));
+        }));
+    }
+
+    let dashboard_enabled = { config.lock().unwrap().dashboard.enabled };
+    if dashboard_enabled {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("dashboard", move || {
+            teleprint::dashboard::run_server(Arc::clone(&a_config),
+                                             Arc::clone(&a_users));
+        }));
+    }
+
+    let api_enabled = { config.lock().unwrap().api.enabled };
+    if api_enabled {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("API server", move || {
+            teleprint::api::run_server(Arc::clone(&a_config),
+                                       Arc::clone(&a_users));
+        }));
+    }
+
+    let upload_enabled = { config.lock().unwrap().upload.enabled };
+    if upload_enabled {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("upload page", move || {
+            teleprint::upload::run_server(Arc::clone(&a_config),
+                                          Arc::clone(&a_users));
+        }));
+    }
+
+    let ipp_enabled = { config.lock().unwrap().ipp.enabled };
+    if ipp_enabled {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("IPP listener", move || {
+            teleprint::ipp::run_server(Arc::clone(&a_config),
+                                       Arc::clone(&a_users));
+        }));
+    }
+
+    let matrix_enabled = { config.lock().unwrap().matrix.enabled };
+    if matrix_enabled {
+        let a_config = Arc::clone(&config);
+        let a_users = Arc::clone(&users_table);
+        workers.push(Worker::new("Matrix bot", move || {
+            teleprint::matrix::run_bot(Arc::clone(&a_config),
+                                       Arc::clone(&a_users));
+        }));
+    } else {
+        health::set_state(health::MATRIX, health::State::Disabled);
+    }
 
-    println!("{:?}", tele_bot.join());
+    supervisor::supervise(workers, config, users_table);
 }
@@ -0,0 +1,786 @@
+extern crate chrono;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use self::chrono::Utc;
+use self::serde::Serialize;
+
+use audit;
+use bot;
+use config::{Config, MatrixConfig};
+use database;
+use downloader;
+use health;
+use io_tools;
+use jobs;
+use pipeline::{self, Attachment, Identity, IngestChannel, Notifier, Silent};
+use printer;
+use spool::{self, Channel, FileMeta};
+use supervisor;
+use transform;
+
+// Seconds of the requests besides `/sync`, which waits for `sync_timeout` in addition
+const REQUEST_TIMEOUT: u64 = 30;
+// Seconds before the next sync after the error
+const RETRY_DELAY: u64 = 5;
+// The history before the first start isn't handled, only the invites
+const FIRST_SYNC_FILTER: &str = "{\"room\":{\"timeline\":{\"limit\":0}}}";
+// Matrix events are limited by 64 KiB, the long answers are cut
+const MESSAGE_LIMIT: usize = 30000;
+
+// Position of the sync and the rooms, which are remembered between the restarts
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct State {
+    since: Option<String>,
+    /// Direct chats, which the bot joined by the invite of the known user
+    direct_rooms: Vec<String>,
+    /// Room of the last message of every known user, where the answers are sent
+    rooms: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+}
+
+#[derive(Deserialize)]
+struct Uploaded {
+    content_uri: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ApiError {
+    errcode: String,
+    error: String,
+}
+
+#[derive(Deserialize)]
+struct SyncResponse {
+    next_batch: String,
+    #[serde(default)]
+    rooms: Rooms,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Rooms {
+    join: HashMap<String, JoinedRoom>,
+    invite: HashMap<String, InvitedRoom>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct JoinedRoom {
+    timeline: Events,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct InvitedRoom {
+    invite_state: Events,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Events {
+    events: Vec<Event>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    state_key: Option<String>,
+    content: serde_json::Value,
+}
+
+// Content of `m.room.message`, `body` is the caption of the file if `filename` is set
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MessageContent {
+    msgtype: String,
+    body: String,
+    filename: Option<String>,
+    url: Option<String>,
+    info: FileInfo,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct FileInfo {
+    mimetype: Option<String>,
+    size: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct TextMessage<'a> {
+    msgtype: &'a str,
+    body: &'a str,
+}
+
+#[derive(Serialize)]
+struct FileMessage<'a> {
+    msgtype: &'a str,
+    body: &'a str,
+    filename: &'a str,
+    url: &'a str,
+    info: FileInfo,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+// Transaction IDs of the sent events must be unique for the access token
+static TRANSACTION: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Matrix as the source of the print requests, the users get the answers in the room of their last message
+pub struct MatrixChannel {
+    pub config: MatrixConfig,
+}
+
+// Answers the Matrix user in the room of its last message
+struct Reply {
+    config: MatrixConfig,
+    user: String,
+}
+
+impl Notifier for Reply {
+    fn notify(&self, text: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let room = { STATE.lock().unwrap().rooms.get(&self.user).cloned() };
+        match room {
+            Some(room) => match send_message(&self.config, &room, text) {
+                Ok(_) => (),
+                Err(err) => error!("Error on sending message: {}", err),
+            },
+            None => warn!("No Matrix room of {} is known, the message is dropped", self.user),
+        };
+    }
+}
+
+impl IngestChannel for MatrixChannel {
+    fn channel(&self) -> Channel {
+        Channel::Matrix
+    }
+
+    fn notifier(&self, identity: &Identity) -> Box<dyn Notifier> {
+        match *identity {
+            Identity::MatrixUser { ref user, .. } => Box::new(Reply { config: self.config.clone(), user: user.clone() }),
+            _ => Box::new(Silent),
+        }
+    }
+}
+
+
+fn audit_matrix(user: &str, action: audit::Action, target: &str) {
+    audit::record(user, Channel::Matrix, action, target);
+}
+
+fn read_state(path: &str) -> State {
+    if !io_tools::exists(path) {
+        return State::default();
+    }
+    match toml::from_str(&io_tools::read_str(path)) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on reading the Matrix state, the sync is started over: {}", err);
+            State::default()
+        }
+    }
+}
+
+fn write_state(path: &str, state: &State) {
+    let text = match toml::to_string(state) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on writing the Matrix state: {}", err);
+            return;
+        }
+    };
+    match io_tools::write_to_file(path, text) {
+        Ok(_) => (),
+        Err(err) => error!("Error on writing the Matrix state: {}", err),
+    };
+}
+
+fn client(timeout: u64) -> Result<reqwest::Client, String> {
+    match reqwest::Client::builder().timeout(Duration::from_secs(timeout)).build() {
+        Ok(data) => Ok(data),
+        Err(err) => Err(format!("{:?}", err)),
+    }
+}
+
+fn authorization(config: &MatrixConfig) -> String {
+    format!("Bearer {}", config.access_token)
+}
+
+// Builds the URL of the homeserver API from the path segments, the room IDs are escaped
+fn api_url(config: &MatrixConfig, segments: &[&str]) -> Result<reqwest::Url, String> {
+    let mut url = match reqwest::Url::parse(&config.homeserver) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Wrong homeserver {}: {}", config.homeserver, err)),
+    };
+    match url.path_segments_mut() {
+        Ok(mut path) => {
+            path.pop_if_empty().extend(segments);
+        }
+        Err(_) => return Err(format!("Wrong homeserver {}", config.homeserver)),
+    };
+    Ok(url)
+}
+
+// Returns the response, if it succeeded, the errors of the homeserver are returned with their codes
+fn api_result(method: &str, response: reqwest::Result<reqwest::Response>) -> Result<reqwest::Response, String> {
+    debug!("{:?}", response);
+    let result = match response {
+        Ok(mut resp) => {
+            if resp.status().is_success() {
+                Ok(resp)
+            } else {
+                let status = resp.status();
+                let error = match resp.json::<ApiError>() {
+                    Ok(data) => format!("{} {}", data.errcode, data.error),
+                    Err(_) => "".to_string(),
+                };
+                Err(format!("Matrix {} responded with {} {}", method, status, error).trim().to_string())
+            }
+        }
+        // the error of reqwest contains the URL with the access token
+        Err(err) => Err(format!("Matrix {} failed: {}", method,
+                                err.get_ref().map_or("request failed".to_string(), |err| format!("{}", err)))),
+    };
+    if let Err(ref err) = result {
+        health::record_error(health::MATRIX, err);
+    }
+    result
+}
+
+fn post<T: Serialize>(config: &MatrixConfig, method: &str, segments: &[&str], body: &T) -> Result<reqwest::Response, String> {
+    let url = api_url(config, segments)?;
+    api_result(method, client(REQUEST_TIMEOUT)?.post(url).header("Authorization", authorization(config)).json(body).send())
+}
+
+fn whoami(config: &MatrixConfig) -> Result<String, String> {
+    let url = api_url(config, &["_matrix", "client", "v3", "account", "whoami"])?;
+    let mut response = api_result("whoami", client(REQUEST_TIMEOUT)?.get(url)
+        .header("Authorization", authorization(config)).send())?;
+    match response.json::<WhoAmI>() {
+        Ok(data) => Ok(data.user_id),
+        Err(err) => Err(format!("Wrong whoami response: {:?}", err)),
+    }
+}
+
+fn join(config: &MatrixConfig, room: &str) -> Result<(), String> {
+    post(config, "join", &["_matrix", "client", "v3", "join", room], &Empty {}).map(|_| ())
+}
+
+fn leave(config: &MatrixConfig, room: &str) -> Result<(), String> {
+    post(config, "leave", &["_matrix", "client", "v3", "rooms", room, "leave"], &Empty {}).map(|_| ())
+}
+
+fn send_event<T: Serialize>(config: &MatrixConfig, room: &str, content: &T) -> Result<(), String> {
+    let transaction = format!("teleprint{}.{}", Utc::now().timestamp_millis(), TRANSACTION.fetch_add(1, Ordering::SeqCst));
+    let url = api_url(config, &["_matrix", "client", "v3", "rooms", room, "send", "m.room.message", &transaction])?;
+    api_result("send", client(REQUEST_TIMEOUT)?.put(url).header("Authorization", authorization(config))
+        .json(content).send()).map(|_| ())
+}
+
+fn sync(client: &reqwest::Client, config: &MatrixConfig, since: &Option<String>) -> Result<SyncResponse, String> {
+    let mut url = api_url(config, &["_matrix", "client", "v3", "sync"])?;
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("timeout", &format!("{}", config.sync_timeout * 1000));
+        match *since {
+            Some(ref since) => query.append_pair("since", since),
+            None => query.append_pair("filter", FIRST_SYNC_FILTER),
+        };
+    }
+    let mut response = api_result("sync", client.get(url).header("Authorization", authorization(config)).send())?;
+    match response.json::<SyncResponse>() {
+        Ok(data) => Ok(data),
+        Err(err) => Err(format!("Wrong sync response: {:?}", err)),
+    }
+}
+
+
+/// Sends the text message to the room
+///
+/// # Examples
+///
+/// ```rust
+/// send_message(&config.matrix, &config.matrix.admin_room, "Teleprint started").unwrap();
+/// ```
+pub fn send_message(config: &MatrixConfig, room: &str, text: &str) -> Result<(), String> {
+    send_event(config, room, &TextMessage { msgtype: "m.text", body: text })
+}
+
+/// Uploads the file to the homeserver and sends it to the room
+///
+/// # Examples
+///
+/// ```rust
+/// send_file(&config.matrix, room, &spool::file_path(&config.spool.dir, &meta), &meta.original_name, &meta.mime).unwrap();
+/// ```
+pub fn send_file(config: &MatrixConfig, room: &str, path: &str, name: &str, mime: &str) -> Result<(), String> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on reading the file: {}", err)),
+    };
+    let size = data.len() as u64;
+
+    let mut url = api_url(config, &["_matrix", "media", "v3", "upload"])?;
+    url.query_pairs_mut().append_pair("filename", name);
+    let mut response = api_result("upload", client(REQUEST_TIMEOUT)?.post(url).header("Authorization", authorization(config))
+        .header("Content-Type", mime).body(data).send())?;
+    let uploaded = match response.json::<Uploaded>() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Wrong upload response: {:?}", err)),
+    };
+
+    send_event(config, room, &FileMessage {
+        msgtype: "m.file",
+        body: name,
+        filename: name,
+        url: &uploaded.content_uri,
+        info: FileInfo { mimetype: Some(mime.to_string()), size: Some(size) },
+    })
+}
+
+// Downloads the file by its `mxc://` URI, the authenticated media API is tried first,
+// the homeservers without it serve the files by the older one
+fn download(config: &Config, uri: &str) -> Result<Vec<u8>, String> {
+    let parts: Vec<&str> = uri.trim_start_matches("mxc://").splitn(2, '/').collect();
+    if !uri.starts_with("mxc://") || parts.len() != 2 {
+        return Err(format!("Wrong content URI {}", uri));
+    }
+    let client = client(config.download.connect_timeout + config.download.read_timeout)?;
+
+    let url = api_url(&config.matrix, &["_matrix", "client", "v1", "media", "download", parts[0], parts[1]])?;
+    let response = match client.get(url).header("Authorization", authorization(&config.matrix)).send() {
+        Ok(ref resp) if resp.status().is_client_error() && resp.status() != reqwest::StatusCode::PAYLOAD_TOO_LARGE => {
+            let url = api_url(&config.matrix, &["_matrix", "media", "v3", "download", parts[0], parts[1]])?;
+            api_result("download", client.get(url).send())?
+        }
+        response => api_result("download", response)?,
+    };
+
+    let limit = config.download.max_size * 1024 * 1024;
+    let mut data: Vec<u8> = vec![];
+    match response.take(limit + 1).read_to_end(&mut data) {
+        Ok(_) => (),
+        Err(err) => return Err(format!("Error on downloading the file: {}", err)),
+    };
+    if data.len() as u64 > limit {
+        return Err(format!("The file is bigger than {} MB", config.download.max_size));
+    }
+    Ok(data)
+}
+
+
+/// Sends the file to the admin room for the approval, if it is set: the description,
+/// the warning about the duplicates and the commands of the approval
+///
+/// The file is announced in Telegram too, this one is for the admin, who uses Matrix.
+pub fn announce_file(meta: &FileMeta, config: &Config) {
+    if !config.matrix.enabled || config.matrix.admin_room.is_empty() {
+        return;
+    }
+
+    let mut lines = vec![bot::announce_header(meta), meta.describe()];
+    if let Some(warning) = spool::duplicate_warning(&config.spool.dir, meta, config.spool.duplicate_window) {
+        lines.push(warning);
+    }
+    lines.push("Send one of:".to_string());
+    for command in bot::approval_buttons(meta).into_iter().flat_map(|row| row.into_iter()) {
+        lines.push(command);
+    }
+
+    match send_message(&config.matrix, &config.matrix.admin_room, &lines.join("\n")) {
+        Ok(_) => (),
+        Err(err) => error!("Error on sending message: {}", err),
+    };
+}
+
+
+fn help(is_admin: bool, known: bool, sender: &str) -> String {
+    if is_admin {
+        "Use the commands below to manage users, files and printer:
+* `/adduser <user_id>` - add user to the access list
+* `/deluser <user_id>` - delete user from the access list
+* `/users` - get users list
+* `/print <file ID> [pages=3-7,1] [nup=2|4|6|9] [rotate=90] [fit] [booklet]` - print the file, transformed by the options
+* `/reject <file ID>` - reject the file
+* `/files` - get files list
+* `/getfile <file ID>` - get file
+* `/delfile <file ID>` - delete file
+* `/pin <file ID>` - keep the file from the automatic cleanup
+* `/unpin <file ID>` - allow the automatic cleanup of the file
+* `/lpstat` - see lpstat output
+* `/cancel <job ID or name>` - cancel the job
+* `/diag` - check the Telegram poller, IMAP, Matrix, printer and spool disk space
+* `/audit [count|user ID|token|YYYY-MM-DD]` - see the last actions, actions of the user or since the date
+* `/help` - print the list of commands above
+Tokens, codes and domains are managed in Telegram or on the dashboard.".to_string()
+    } else if known {
+        "Just send PDF file, image or the link to the file (`/url <link>`). \
+Add the print options like `nup=2 fit` to the caption of the file. \
+Use `/printtext` followed by the text to print the text itself.".to_string()
+    } else {
+        format!("Ask the admin to give {} access to the printer.", sender)
+    }
+}
+
+// Runs the admin command and returns the answer
+fn admin_command(command: &str, args: &[&str], sender: &str, room: &str, a_config: &Arc<Mutex<Config>>,
+                 a_users_table: &Arc<Mutex<database::UsersTable>>) -> String {
+    let config = { a_config.lock().unwrap().clone() };
+    let spool_dir = &config.spool.dir;
+    let argument = args.first().cloned().unwrap_or("");
+
+    match command {
+        "/adduser" | "/deluser" => {
+            let user = match argument.parse::<i64>() {
+                Ok(data) => data,
+                Err(_) => return "No user ID was specified. Error".to_string(),
+            };
+            let mut users_table = a_users_table.lock().unwrap();
+            let action = if command == "/adduser" {
+                users_table.add_user(user);
+                audit::Action::AddUser
+            } else {
+                users_table.del_user(user);
+                audit::Action::DelUser
+            };
+            match database::write_database(&users_table) {
+                Ok(_) => {
+                    audit_matrix(sender, action, &format!("{}", user));
+                    "Ok".to_string()
+                }
+                Err(err) => format!("Error on writing config: {}", err),
+            }
+        }
+        "/users" => format!("{:?}", a_users_table.lock().unwrap().vectorize().users),
+        _ if argument.is_empty() && ["/print", "/reject", "/getfile", "/delfile", "/pin", "/unpin"].contains(&command) =>
+            "No file ID was specified. Error".to_string(),
+        "/print" => {
+            let options = match transform::Options::parse(&args[1..]) {
+                Ok(data) => data,
+                Err(err) => return format!("Error in the print options: {}", err),
+            };
+            match jobs::print(argument, &options, Arc::clone(a_config)) {
+                Ok(printed) => {
+                    audit_matrix(sender, audit::Action::Print, &printed.target());
+                    match printed.job {
+                        Some(job) => format!("The file has been started printing, job {}", job),
+                        None => "The file has been started printing...".to_string(),
+                    }
+                }
+                Err(err) => format!("Error on printing the file: {}", err),
+            }
+        }
+        "/reject" => match jobs::reject(argument, &config) {
+            Ok(meta) => {
+                audit_matrix(sender, audit::Action::RejectFile, &meta.id);
+                "The file has been rejected".to_string()
+            }
+            Err(err) => format!("Error on rejecting the file: {}", err),
+        },
+        "/files" => match spool::describe_files(spool_dir) {
+            Ok(data) => data,
+            Err(err) => format!("Error on getting files: {}", err),
+        },
+        "/getfile" => {
+            let meta = match spool::read_meta(spool_dir, argument) {
+                Ok(data) => data,
+                Err(err) => return format!("Error on getting file: {}", err),
+            };
+            match send_file(&config.matrix, room, &spool::file_path(spool_dir, &meta), &meta.original_name, &meta.mime) {
+                Ok(_) => meta.describe(),
+                Err(err) => format!("Error on sending file: {}", err),
+            }
+        }
+        "/delfile" => match spool::delete(spool_dir, argument) {
+            Ok(_) => {
+                audit_matrix(sender, audit::Action::DeleteFile, argument);
+                "Ok".to_string()
+            }
+            Err(err) => format!("Error on deleting file: {}", err),
+        },
+        "/pin" | "/unpin" => {
+            let mut meta = match spool::read_meta(spool_dir, argument) {
+                Ok(data) => data,
+                Err(err) => return format!("Error on pinning the file: {}", err),
+            };
+            meta.pinned = command == "/pin";
+            match spool::write_meta(spool_dir, &meta) {
+                Ok(_) => {
+                    audit_matrix(sender, if meta.pinned { audit::Action::PinFile } else { audit::Action::UnpinFile }, &meta.id);
+                    "Ok".to_string()
+                }
+                Err(err) => format!("Error on pinning the file: {}", err),
+            }
+        }
+        "/lpstat" => printer::lpstat(),
+        "/cancel" if argument.is_empty() => "No job was specified. Error".to_string(),
+        "/cancel" => match printer::cancel(argument) {
+            Ok(_) => {
+                audit_matrix(sender, audit::Action::Cancel, argument);
+                "The job was canceled successfully.".to_string()
+            }
+            Err(err) => format!("Error on canceling the job:\n{}", err),
+        },
+        "/diag" => health::report(&health::checks(&config)),
+        "/audit" => {
+            let entries = match audit::read_entries() {
+                Ok(data) => audit::filter(data, &args.join(" ")),
+                Err(err) => return format!("Error on reading audit log: {}", err),
+            };
+            // the latest entries are kept, if all of them don't fit into one message
+            let mut lines: Vec<String> = vec![];
+            let mut length = 0;
+            for entry in entries.iter().rev() {
+                let line = entry.describe();
+                length += line.len() + 1;
+                if length > MESSAGE_LIMIT {
+                    break;
+                }
+                lines.push(line);
+            }
+            lines.reverse();
+            if lines.is_empty() { "No entries".to_string() } else { lines.join("\n") }
+        }
+        _ => format!("Unknown command {}, see /help", command),
+    }
+}
+
+fn reply(config: &MatrixConfig, room: &str, text: &str) {
+    match send_message(config, room, text) {
+        Ok(_) => (),
+        Err(err) => error!("Error on sending message: {}", err),
+    };
+}
+
+// Submits the attachment and answers in the room, the later notifications go to the room of the last message
+fn submit(identity: Identity, attachment: Attachment, options: transform::Options, room: &str,
+          a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().matrix.clone() };
+    let kind = attachment.kind();
+    let mut request = MatrixChannel { config: config.clone() }.request(identity, attachment, options);
+    request.reply = Box::new(Silent);
+    let result = pipeline::submit(request, a_config, a_users_table);
+    reply(&config, room, &pipeline::receipt(kind, &result));
+}
+
+fn handle_message(room: &str, sender: &str, content: MessageContent, a_config: &Arc<Mutex<Config>>,
+                  a_users_table: &Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    let id = config.matrix.users.get(sender).cloned().unwrap_or(0);
+    let (admin, known) = {
+        let users_table = a_users_table.lock().unwrap();
+        (users_table.get_admin(), users_table.check_user(id))
+    };
+    let is_admin = id != 0 && id == admin;
+    if known {
+        STATE.lock().unwrap().rooms.insert(sender.to_string(), room.to_string());
+    }
+    let identity = Identity::MatrixUser { user: sender.to_string(), id };
+
+    match content.msgtype.as_str() {
+        "m.file" | "m.image" => {
+            // the file isn't downloaded for the unknown users
+            if !known {
+                audit_matrix(sender, audit::Action::Unauthorized, "print request");
+                return reply(&config.matrix, room, &help(false, false, sender));
+            }
+            let name = content.filename.clone().unwrap_or(content.body.clone());
+            let caption = if content.filename.is_some() && content.body != name { content.body.clone() } else { "".to_string() };
+            let options = match transform::Options::parse(&caption.split_whitespace().collect::<Vec<&str>>()) {
+                Ok(data) => data,
+                Err(err) => return reply(&config.matrix, room, &format!("Error in the print options: {}", err)),
+            };
+            let uri = content.url.clone().unwrap_or("".to_string());
+            match download(&config, &uri) {
+                Ok(data) => submit(identity, Attachment::Document { name, data }, options, room,
+                                   a_config, a_users_table),
+                Err(err) => reply(&config.matrix, room, &format!("Error in receiving file: {}", err)),
+            };
+        }
+        "m.text" => {
+            let text = content.body.trim();
+            let command = text.split_whitespace().next().unwrap_or("");
+            let args: Vec<&str> = text.split_whitespace().skip(1).collect();
+            match command {
+                "/help" => reply(&config.matrix, room, &help(is_admin, known, sender)),
+                "/url" => match args.first() {
+                    Some(url) => submit(identity, Attachment::Link(url.to_string()), transform::Options::default(), room,
+                                        a_config, a_users_table),
+                    None => reply(&config.matrix, room, "No link was specified. Error"),
+                },
+                "/printtext" => {
+                    let attachment = Attachment::Text { text: text[command.len()..].trim().to_string(), author: sender.to_string() };
+                    submit(identity, attachment, transform::Options::default(), room, a_config, a_users_table);
+                }
+                _ if command.starts_with('/') && is_admin => {
+                    let answer = admin_command(command, &args, sender, room, a_config, a_users_table);
+                    reply(&config.matrix, room, &answer);
+                }
+                _ if command.starts_with('/') => {
+                    audit_matrix(sender, audit::Action::Unauthorized, command);
+                    reply(&config.matrix, room, "You don't have access to the command, see /help");
+                }
+                _ if downloader::is_link(text) => submit(identity, Attachment::Link(text.to_string()),
+                                                         transform::Options::default(), room, a_config, a_users_table),
+                // the rest of the chat isn't for the bot
+                _ => (),
+            };
+        }
+        _ => (),
+    };
+}
+
+// Joins the allowed rooms and the direct chats of the known users, other invites are declined
+fn handle_invites(config: &MatrixConfig, me: &str, invites: &HashMap<String, InvitedRoom>) {
+    for (room, invite) in invites {
+        let inviter = invite.invite_state.events.iter()
+            .find(|event| event.kind == "m.room.member" && event.state_key.as_ref().is_some_and(|key| key == me))
+            .map_or("".to_string(), |event| event.sender.clone());
+        let allowed = config.rooms.contains(room);
+        let direct = !allowed && config.direct && config.users.contains_key(&inviter);
+
+        if !allowed && !direct {
+            info!("Declining the invite of {} to {}", inviter, room);
+            match leave(config, room) {
+                Ok(_) => (),
+                Err(err) => error!("Error on declining the invite: {}", err),
+            };
+            continue;
+        }
+
+        match join(config, room) {
+            Ok(_) => {
+                info!("Joined {} by the invite of {}", room, inviter);
+                if direct {
+                    STATE.lock().unwrap().direct_rooms.push(room.clone());
+                }
+            }
+            Err(err) => error!("Error on joining {}: {}", room, err),
+        };
+    }
+}
+
+
+/// Runs the Matrix bot, if it is enabled in the `[matrix]` section
+///
+/// The bot syncs with the homeserver by the long polling. The known users send the files, links and texts
+/// in the allowed rooms and their direct chats, they go to the admin for the approval like the files
+/// from Telegram. The admin, mapped in `users`, approves them and manages the printer by the same commands
+/// as in Telegram. End-to-end encrypted rooms aren't supported.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let users_table = Arc::new(Mutex::new(read_users().unwrap()));
+/// let matrix = thread::spawn(move || {
+///        run_bot(Arc::clone(&config), Arc::clone(&users_table));
+///  });
+/// ```
+pub fn run_bot(a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().matrix.clone() };
+    if !config.enabled {
+        health::set_state(health::MATRIX, health::State::Disabled);
+        return;
+    }
+    let _guard = health::running(health::MATRIX);
+
+    let me = match whoami(&config) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on logging in to Matrix: {}", err);
+            return;
+        }
+    };
+    info!("Logged in to Matrix as {}", me);
+    {
+        *STATE.lock().unwrap() = read_state(&config.state_file);
+    }
+    for room in &config.rooms {
+        match join(&config, room) {
+            Ok(_) => (),
+            Err(err) => error!("Error on joining {}: {}", room, err),
+        };
+    }
+
+    let client = match client(config.sync_timeout + REQUEST_TIMEOUT) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on creating the Matrix client: {}", err);
+            return;
+        }
+    };
+
+    while !supervisor::shutting_down() {
+        let config = { a_config.lock().unwrap().matrix.clone() };
+        let since = { STATE.lock().unwrap().since.clone() };
+        let response = match sync(&client, &config, &since) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Error on syncing with Matrix: {}", err);
+                supervisor::sleep(Duration::from_secs(RETRY_DELAY));
+                continue;
+            }
+        };
+        health::record_ok(health::MATRIX);
+
+        let _task = supervisor::task();
+        handle_invites(&config, &me, &response.rooms.invite);
+        // the position is saved before the messages are handled, so a failing message isn't handled again
+        let direct_rooms = {
+            let mut state = STATE.lock().unwrap();
+            state.since = Some(response.next_batch.clone());
+            write_state(&config.state_file, &state);
+            state.direct_rooms.clone()
+        };
+        if since.is_none() {
+            continue;
+        }
+
+        for (room, joined) in response.rooms.join {
+            if !config.rooms.contains(&room) && !direct_rooms.contains(&room) {
+                continue;
+            }
+            for event in joined.timeline.events {
+                if event.kind != "m.room.message" || event.sender == me {
+                    continue;
+                }
+                match serde_json::from_value::<MessageContent>(event.content) {
+                    Ok(content) => handle_message(&room, &event.sender, content, &a_config, &a_users_table),
+                    Err(err) => debug!("Skipping the message of {}: {}", event.sender, err),
+                };
+            }
+        }
+        // the rooms of the users are saved too
+        let state = STATE.lock().unwrap();
+        write_state(&config.state_file, &state);
+    }
+}
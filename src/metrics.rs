@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use config::Config;
+use health;
+use http::{self, Handler, Request, Response};
+use spool;
+
+pub const JOBS_SUBMITTED: &str = "teleprint_jobs_submitted_total";
+pub const JOBS_PRINTED: &str = "teleprint_jobs_printed_total";
+pub const JOBS_FAILED: &str = "teleprint_jobs_failed_total";
+pub const PAGES_PRINTED: &str = "teleprint_pages_printed_total";
+pub const DOWNLOADS: &str = "teleprint_downloads_total";
+pub const DOWNLOAD_BYTES: &str = "teleprint_download_bytes_total";
+pub const DOWNLOAD_DURATION: &str = "teleprint_download_duration_seconds";
+pub const IMAP_POLL_DURATION: &str = "teleprint_imap_poll_duration_seconds";
+pub const IMAP_RECONNECTS: &str = "teleprint_imap_reconnects_total";
+pub const TELEGRAM_ERRORS: &str = "teleprint_telegram_api_errors_total";
+pub const QUEUE_DEPTH: &str = "teleprint_queue_depth";
+pub const SPOOL_FILES: &str = "teleprint_spool_files";
+pub const SPOOL_BYTES: &str = "teleprint_spool_bytes";
+
+// Name, type and help of every exported metric
+const DESCRIPTIONS: [(&str, &str, &str); 13] = [
+    (JOBS_SUBMITTED, "counter", "Files sent to the admin for approval"),
+    (JOBS_PRINTED, "counter", "Files sent to the printer"),
+    (JOBS_FAILED, "counter", "Files, which failed to print"),
+    (PAGES_PRINTED, "counter", "Pages of the files sent to the printer"),
+    (DOWNLOADS, "counter", "Downloads of the links and Telegram files"),
+    (DOWNLOAD_BYTES, "counter", "Bytes downloaded"),
+    (DOWNLOAD_DURATION, "summary", "Time of the successful downloads"),
+    (IMAP_POLL_DURATION, "summary", "Time of checking the mailbox for the new letters"),
+    (IMAP_RECONNECTS, "counter", "Reconnections to the IMAP server"),
+    (TELEGRAM_ERRORS, "counter", "Failed requests to the Telegram Bot API"),
+    (QUEUE_DEPTH, "gauge", "Files waiting for the approval"),
+    (SPOOL_FILES, "gauge", "Files in the spool"),
+    (SPOOL_BYTES, "gauge", "Size of the spool"),
+];
+
+lazy_static! {
+    // Values by the metric name and the rendered labels
+    static ref VALUES: Mutex<BTreeMap<(String, String), f64>> = Mutex::new(BTreeMap::new());
+}
+
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return "".to_string();
+    }
+    let pairs: Vec<String> = labels.iter().map(|&(name, value)| format!("{}=\"{}\"", name, escape(value))).collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Adds the value to the counter
+///
+/// # Examples
+///
+/// ```rust
+/// add(DOWNLOAD_BYTES, &[], 1024.0);
+/// ```
+pub fn add(name: &str, labels: &[(&str, &str)], value: f64) {
+    let mut values = VALUES.lock().unwrap();
+    *values.entry((name.to_string(), render_labels(labels))).or_insert(0.0) += value;
+}
+
+/// Increments the counter
+///
+/// # Examples
+///
+/// ```rust
+/// inc(JOBS_SUBMITTED, &[("channel", "Mail")]);
+/// ```
+pub fn inc(name: &str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+/// Sets the value of the gauge
+pub fn set(name: &str, labels: &[(&str, &str)], value: f64) {
+    VALUES.lock().unwrap().insert((name.to_string(), render_labels(labels)), value);
+}
+
+/// Records the duration to the summary, which is exported as `<name>_sum` and `<name>_count`
+pub fn observe(name: &str, labels: &[(&str, &str)], duration: Duration) {
+    let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+    add(&format!("{}_sum", name), labels, seconds);
+    add(&format!("{}_count", name), labels, 1.0);
+}
+
+/// Returns the label value of the channel
+pub fn channel_label(channel: spool::Channel) -> String {
+    format!("{:?}", channel)
+}
+
+// Gauges of the spool are computed when the metrics are requested
+fn update_spool(spool_dir: &str) {
+    match spool::list(spool_dir) {
+        Ok(files) => {
+            let waiting = files.iter().filter(|meta| meta.is_pending()).count();
+            set(QUEUE_DEPTH, &[], waiting as f64);
+        }
+        Err(err) => error!("Error on listing the spool: {}", err),
+    };
+    match spool::usage(spool_dir) {
+        Ok((count, size)) => {
+            set(SPOOL_FILES, &[], count as f64);
+            set(SPOOL_BYTES, &[], size as f64);
+        }
+        Err(err) => error!("Error on getting the spool usage: {}", err),
+    };
+}
+
+/// Returns all the metrics in the Prometheus text format
+pub fn render() -> String {
+    let values = VALUES.lock().unwrap();
+    let mut text = String::new();
+    for &(name, kind, help) in DESCRIPTIONS.iter() {
+        text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+        let suffixes: &[&str] = if kind == "summary" { &["_sum", "_count"] } else { &[""] };
+        for suffix in suffixes {
+            let full_name = format!("{}{}", name, suffix);
+            for ((_, labels), value) in values.range((full_name.clone(), "".to_string())..)
+                .take_while(|&((key, _), _)| *key == full_name) {
+                text.push_str(&format!("{}{} {}\n", full_name, labels, value));
+            }
+        }
+    }
+    text
+}
+
+/// Runs the HTTP server with the metrics on `/metrics` and the health checks on `/healthz`,
+/// if it is enabled in the `[metrics]` section, the health checks have their own server too
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let metrics = thread::spawn(move || {
+///        run_server(Arc::clone(&config));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if !config.metrics.enabled {
+        return;
+    }
+
+    let handler: Handler = Arc::new(move |request: &Request| {
+        if request.method != "GET" {
+            return Response::text(405, "Method not allowed");
+        }
+        match request.path.as_str() {
+            "/metrics" => {
+                let spool_dir = { a_config.lock().unwrap().spool.dir.clone() };
+                update_spool(&spool_dir);
+                Response::new(200, "text/plain; version=0.0.4; charset=utf-8", Vec::from(render()))
+                    .with_header("Cache-Control", "no-cache")
+            }
+            // also served here for the scrapers, which already probe it on the metrics address
+            "/healthz" => {
+                let config = { a_config.lock().unwrap().clone() };
+                health::healthz(&config)
+            }
+            _ => Response::text(404, "Not found"),
+        }
+    });
+
+    match http::serve(&config.metrics.address, 0, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the metrics server: {}", err),
+    };
+}
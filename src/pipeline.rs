@@ -0,0 +1,363 @@
+extern crate chrono;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use self::chrono::Utc;
+
+use alerts::{self, Alert, Event};
+use audit;
+use bot;
+use config::Config;
+use convert;
+use database;
+use downloader;
+use hash;
+use matrix;
+use spool::{self, Channel, FileMeta};
+use supervisor;
+use transform;
+
+/// Error of `submit`, if the submitter has no access, the channels answer it with their own status
+pub const UNAUTHORIZED: &str = "You don't have access to the printer";
+const DAY: i64 = 24 * 60 * 60;
+
+/// Who sent the request, checked against `users.toml` by the pipeline
+pub enum Identity {
+    TelegramUser(i64),
+    MailToken(String),
+    ApiToken(String),
+    /// Mail token or one-time code from the upload page, the code is used up by the request
+    TokenOrCode(String),
+    /// Matrix user and the user ID, which it is mapped to in the `[matrix]` section
+    MatrixUser { user: String, id: i64 },
+}
+
+/// What should be printed
+pub enum Attachment {
+    /// File, which is stored as is
+    File { name: String, mime: String, data: Vec<u8> },
+    /// PDF file or image, images are converted to PDF, other files are refused
+    Document { name: String, data: Vec<u8> },
+    /// File, which is already downloaded to the `tmp_dir` of the spool
+    Downloaded { name: String, mime: String, download: downloader::Download },
+    /// Images, printed one per page
+    Images { name: String, paths: Vec<String>, layout: Option<convert::Layout> },
+    /// Link to the file, which is downloaded with the limits of the `[download]` section
+    Link(String),
+    /// Text with the options on its first line, `author` is printed in the header
+    Text { text: String, author: String },
+}
+
+/// Sends the answers back to the submitter
+pub trait Notifier: Send {
+    fn notify(&self, text: &str);
+}
+
+/// Notifier of the channels, which answer the submitter themselves, like the HTTP ones
+pub struct Silent;
+
+impl Notifier for Silent {
+    fn notify(&self, _text: &str) {}
+}
+
+/// Source of the print requests: Telegram, mail, the upload page, the API or IPP
+///
+/// The channel only receives the attachments, everything after it is done by `submit`.
+pub trait IngestChannel {
+    /// Channel, recorded in the spool and the audit log
+    fn channel(&self) -> Channel;
+
+    /// Returns the notifier, which replies to the submitter
+    fn notifier(&self, identity: &Identity) -> Box<dyn Notifier>;
+
+    /// Builds the request of the submitter
+    fn request(&self, identity: Identity, attachment: Attachment, options: transform::Options) -> PrintRequest {
+        PrintRequest {
+            channel: self.channel(),
+            reply: self.notifier(&identity),
+            identity,
+            attachment,
+            options,
+        }
+    }
+}
+
+/// Request of printing, common for all channels
+pub struct PrintRequest {
+    pub channel: Channel,
+    pub identity: Identity,
+    pub attachment: Attachment,
+    /// Options asked by the submitter, they are offered to the admin as the first approval button
+    pub options: transform::Options,
+    pub reply: Box<dyn Notifier>,
+}
+
+impl Attachment {
+    /// Returns the word for the messages to the submitter, like `Your album ... has been sent`
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Attachment::Images { ref paths, .. } if paths.len() > 1 => "album",
+            Attachment::Images { .. } => "image",
+            Attachment::Text { .. } => "text",
+            _ => "file",
+        }
+    }
+
+    // Removes the downloaded files of the attachment, which wasn't accepted
+    fn discard(self) {
+        let paths = match self {
+            Attachment::Downloaded { download, .. } => vec![download.path],
+            Attachment::Images { paths, .. } => paths,
+            _ => vec![],
+        };
+        for path in paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    // Converts the attachment and stores it to the spool
+    fn store(self, config: &Config, channel: Channel, submitter: &str) -> Result<FileMeta, String> {
+        let spool_dir = &config.spool.dir;
+        match self {
+            Attachment::File { name, mime, data } => spool::store(spool_dir, data, &name, &mime, channel, submitter),
+            Attachment::Document { name, data } => convert::document_to_spool(data, &name, config, channel, submitter),
+            Attachment::Downloaded { name, mime, download } =>
+                spool::store_file(spool_dir, &download.path, &download.sha256, download.size, &name, &mime,
+                                  channel, submitter, None),
+            Attachment::Images { name, paths, layout } => {
+                let mut images: Vec<Vec<u8>> = vec![];
+                let mut error: Option<String> = None;
+                for path in &paths {
+                    match fs::read(path) {
+                        Ok(data) => images.push(data),
+                        Err(err) => error = Some(format!("Error on reading the image: {}", err)),
+                    };
+                    let _ = fs::remove_file(path);
+                }
+                if let Some(err) = error {
+                    return Err(err);
+                }
+
+                let pdf = convert::images_to_pdf(&images, layout.unwrap_or(config.images.layout),
+                                                config.images.max_megapixels)?;
+                let name = if images.len() > 1 { format!("album of {} images", images.len()) } else { name };
+                spool::store(spool_dir, pdf, &name, "application/pdf", channel, submitter)
+            }
+            Attachment::Link(url) => downloader::fetch_to_spool(&url, config, channel, submitter),
+            Attachment::Text { text, author } => convert::text_to_spool(&text, config, channel, submitter, &author),
+        }
+    }
+}
+
+
+// Returns the submitter of the spooled file and the used one-time code, if the identity has access.
+// The tokens and the codes are stored by their fingerprints, which are enough to count the quota
+// and to find the own files, so the spool doesn't keep the credentials
+fn authorize(channel: Channel, identity: &Identity, users_table: &mut database::UsersTable) -> Option<(String, Option<String>)> {
+    match *identity {
+        Identity::TelegramUser(id) if users_table.check_user(id) => Some((format!("{}", id), None)),
+        Identity::MailToken(ref token) if users_table.check_token(token.clone()) => Some((hash::mask_submitter(token), None)),
+        Identity::ApiToken(ref token) if users_table.check_api_token(token) => Some((hash::mask_submitter(token), None)),
+        Identity::MatrixUser { ref user, id } if users_table.check_user(id) => Some((user.clone(), None)),
+        Identity::TokenOrCode(ref access) if !access.is_empty() && users_table.check_token(access.clone()) =>
+            Some((hash::mask_submitter(access), None)),
+        Identity::TokenOrCode(ref access) if channel == Channel::Web && users_table.use_code(&access.to_uppercase()) => {
+            let code = access.to_uppercase();
+            match database::write_database(users_table) {
+                Ok(_) => Some((hash::mask_code(&code), Some(code))),
+                Err(err) => {
+                    error!("Error on writing users: {}", err);
+                    users_table.add_code(code);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+fn audit_rejected(channel: Channel, identity: &Identity) {
+    let actor = match *identity {
+        Identity::TelegramUser(id) => format!("{}", id),
+        Identity::MailToken(ref token) | Identity::ApiToken(ref token) | Identity::TokenOrCode(ref token) =>
+            if token.is_empty() { "anonymous".to_string() } else { hash::mask_token(token) },
+        Identity::MatrixUser { ref user, .. } => user.clone(),
+    };
+    match channel {
+        Channel::Mail => audit::record(&actor, channel, audit::Action::MailRejected, &actor),
+        _ => audit::record(&actor, channel, audit::Action::Unauthorized, "print request"),
+    };
+}
+
+// Returns the one-time code back, if the file wasn't accepted after all
+fn restore_code(code: Option<String>, a_users_table: &Arc<Mutex<database::UsersTable>>) {
+    if let Some(code) = code {
+        let mut users_table = a_users_table.lock().unwrap();
+        users_table.add_code(code);
+        match database::write_database(&users_table) {
+            Ok(_) => (),
+            Err(err) => error!("Error on writing users: {}", err),
+        };
+    }
+}
+
+// Daily limits of the documents and pages, the IPP listener has its own ones
+fn limits(channel: Channel, config: &Config) -> (u32, u32) {
+    match channel {
+        Channel::Ipp => (config.ipp.daily_jobs, config.ipp.daily_pages),
+        _ => (config.quota.daily_jobs, config.quota.daily_pages),
+    }
+}
+
+/// Checks the daily quota of the submitter, the stored `document` is counted in addition to the previous ones
+///
+/// # Examples
+///
+/// ```rust
+/// check_quota(&config, Channel::Ipp, &hash::mask_submitter("IFMO1f3a9c..."), None).unwrap();
+/// ```
+pub fn check_quota(config: &Config, channel: Channel, submitter: &str, document: Option<&FileMeta>) -> Result<(), String> {
+    let (daily_jobs, daily_pages) = limits(channel, config);
+    if daily_jobs == 0 && daily_pages == 0 {
+        return Ok(());
+    }
+
+    let since = Utc::now().timestamp() - DAY;
+    let new_id = document.map_or("", |meta| meta.id.as_str());
+    let recent: Vec<FileMeta> = spool::list(&config.spool.dir)?.into_iter()
+        .filter(|meta| meta.channel == channel && meta.submitter == submitter && meta.derived_from.is_none())
+        .filter(|meta| meta.received >= since && meta.id != new_id)
+        .collect();
+    let used_pages: u32 = recent.iter().map(|meta| meta.pages.unwrap_or(1)).sum();
+    let pages = document.map_or(0, |meta| meta.pages.unwrap_or(1));
+
+    if daily_jobs > 0 && recent.len() as u32 >= daily_jobs {
+        return Err(format!("The daily quota of {} documents is used up", daily_jobs));
+    }
+    if daily_pages > 0 && used_pages + pages > daily_pages {
+        return Err(format!("The daily quota of {} pages would be exceeded, {} pages are used", daily_pages, used_pages));
+    }
+    Ok(())
+}
+
+// Authorizes the request, stores the attachment within the quota and sends it to the admin for the approval
+fn accept(channel: Channel, identity: &Identity, attachment: Attachment, options: &transform::Options,
+          a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Result<FileMeta, String> {
+    if supervisor::shutting_down() {
+        attachment.discard();
+        return Err("Teleprint is restarting, send the file again in a minute".to_string());
+    }
+
+    let (admin, authorized) = {
+        let mut users_table = a_users_table.lock().unwrap();
+        (users_table.get_admin(), authorize(channel, identity, &mut users_table))
+    };
+    let (submitter, code) = match authorized {
+        Some(data) => data,
+        None => {
+            audit_rejected(channel, identity);
+            attachment.discard();
+            return Err(UNAUTHORIZED.to_string());
+        }
+    };
+
+    let config = { a_config.lock().unwrap().clone() };
+    let exempt = match *identity {
+        Identity::TelegramUser(id) | Identity::MatrixUser { id, .. } => id == admin,
+        _ => false,
+    };
+    if !exempt {
+        if let Err(err) = check_quota(&config, channel, &submitter, None) {
+            restore_code(code, a_users_table);
+            attachment.discard();
+            return Err(err);
+        }
+    }
+
+    // the admin is notified before the shutdown
+    let task = supervisor::task();
+    let mut meta = match attachment.store(&config, channel, &submitter) {
+        Ok(data) => data,
+        Err(err) => {
+            restore_code(code, a_users_table);
+            return Err(err);
+        }
+    };
+    // the pages are known only after storing, the file is dropped if it doesn't fit into the quota
+    if !exempt {
+        if let Err(err) = check_quota(&config, channel, &submitter, Some(&meta)) {
+            let _ = spool::delete(&config.spool.dir, &meta.id);
+            restore_code(code, a_users_table);
+            return Err(err);
+        }
+    }
+    if !options.is_identity() {
+        meta.options = Some(options.describe());
+        match spool::write_meta(&config.spool.dir, &meta) {
+            Ok(_) => (),
+            Err(err) => error!("{}", err),
+        };
+    }
+
+    let announced = meta.clone();
+    thread::spawn(move || {
+        bot::announce_file(admin, &announced, &config);
+        matrix::announce_file(&announced, &config);
+        alerts::notify(&config, Alert::new(Event::NewRequest, &format!("{}\n{}", bot::announce_header(&announced),
+                                                                        announced.describe()))
+            .with_file(&announced.id));
+        drop(task);
+    });
+    Ok(meta)
+}
+
+
+/// Returns the notifier, which replies to the submitter of the spooled file, used when the file is printed or rejected
+pub fn notifier(meta: &FileMeta, config: &Config) -> Box<dyn Notifier> {
+    match (meta.channel, meta.telegram_user()) {
+        (_, Some(user)) => bot::TelegramChannel { token: config.token.clone() }.notifier(&Identity::TelegramUser(user)),
+        (Channel::Matrix, _) => matrix::MatrixChannel { config: config.matrix.clone() }
+            .notifier(&Identity::MatrixUser { user: meta.submitter.clone(), id: 0 }),
+        _ => Box::new(Silent),
+    }
+}
+
+
+/// Passes the request through the pipeline, which is shared by all channels:
+/// authorization, the quota, conversion, storing to the spool, the approval request to the admin
+/// and the answer to the submitter through its notifier
+///
+/// Returns the spooled file, the channels without the notifier answer with it themselves.
+/// `UNAUTHORIZED` is returned, if the submitter has no access.
+///
+/// # Examples
+///
+/// ```rust
+/// let request = MailChannel.request(Identity::MailToken(token), Attachment::Link(url), transform::Options::default());
+/// let meta = submit(request, &config, &users_table).unwrap();
+/// ```
+pub fn submit(request: PrintRequest, a_config: &Arc<Mutex<Config>>,
+              a_users_table: &Arc<Mutex<database::UsersTable>>) -> Result<FileMeta, String> {
+    let PrintRequest { channel, identity, attachment, options, reply } = request;
+    let kind = attachment.kind();
+    let result = accept(channel, &identity, attachment, &options, a_config, a_users_table);
+    reply.notify(&receipt(kind, &result));
+    result
+}
+
+/// Returns the answer to the submitter about the result of `submit`, `kind` is from `Attachment::kind`
+///
+/// # Examples
+///
+/// ```rust
+/// let result = submit(request, &config, &users_table);
+/// println!("{}", receipt("file", &result)); // Your file 4f2a9c01d3e5b768 has been sent to the admin
+/// ```
+pub fn receipt(kind: &str, result: &Result<FileMeta, String>) -> String {
+    match *result {
+        Ok(ref meta) => format!("Your {} {} has been sent to the admin", kind, meta.id),
+        Err(ref err) => format!("Error in receiving {}: {}", kind, err),
+    }
+}
@@ -0,0 +1,149 @@
+use std::fs::{read, read_dir, remove_file};
+use std::path::Path;
+use std::process::Command;
+
+use bot;
+use config::{Config, PreviewConfig};
+use inspect;
+use spool;
+
+const MM_PER_POINT: f64 = 25.4 / 72.0;
+// Difference in millimeters, which is still treated as the same paper size
+const PAPER_TOLERANCE: f64 = 3.0;
+const PAPERS: [(&str, f64, f64); 5] = [
+    ("A3", 297.0, 420.0),
+    ("A4", 210.0, 297.0),
+    ("A5", 148.0, 210.0),
+    ("Letter", 215.9, 279.4),
+    ("Legal", 215.9, 355.6),
+];
+
+
+/// Returns the paper size of the first page, like `A4` or `Letter landscape`, or the size in millimeters
+///
+/// Returns None if the file can't be parsed as PDF.
+pub fn paper_size(data: &[u8]) -> Option<String> {
+    let doc = match inspect::load_pdf(data) {
+        Ok(data) => data,
+        Err(_) => return None,
+    };
+    let first = match doc.get_pages().values().next() {
+        Some(id) => *id,
+        None => return None,
+    };
+    let (width, height) = match inspect::page_size(&doc, first) {
+        Some((width, height)) => (width * MM_PER_POINT, height * MM_PER_POINT),
+        None => return None,
+    };
+
+    let (short, long) = if width < height { (width, height) } else { (height, width) };
+    let orientation = if width > height { " landscape" } else { "" };
+    for &(name, paper_short, paper_long) in PAPERS.iter() {
+        if (short - paper_short).abs() <= PAPER_TOLERANCE && (long - paper_long).abs() <= PAPER_TOLERANCE {
+            return Some(format!("{}{}", name, orientation));
+        }
+    }
+    Some(format!("{:.0}x{:.0} mm", width, height))
+}
+
+/// Returns the short summary of the file for the approval message: pages, paper size and file size
+pub fn summary(meta: &spool::FileMeta, data: &[u8]) -> String {
+    let pages = match meta.pages {
+        Some(count) => format!("{} pages", count),
+        None => "unknown number of pages".to_string(),
+    };
+    let paper = paper_size(data).unwrap_or("unknown paper size".to_string());
+    format!("{}: {}, {}, {} bytes", meta.original_name, pages, paper, meta.size)
+}
+
+/// Renders the first `pages` pages of the PDF to the PNG files `<prefix>-<page>.png`, returns their paths on Ok()
+///
+/// Uses the external renderer (`pdftoppm` from poppler-utils by default), the longest side
+/// of the thumbnail is scaled to `size` pixels.
+///
+/// # Examples
+///
+/// ```rust
+/// let thumbnails = render("spool/blobs/9b7e...31f2.pdf", &config.preview, "spool/tmp/preview-4f2a9c01d3e5b768").unwrap();
+/// println!("{:?}", thumbnails); // ["spool/tmp/preview-4f2a9c01d3e5b768-1.png"]
+/// ```
+pub fn render(pdf_path: &str, config: &PreviewConfig, prefix: &str) -> Result<Vec<String>, String> {
+    let output = match Command::new(&config.renderer)
+        .args(["-png", "-f", "1", "-l", &format!("{}", config.pages), "-scale-to", &format!("{}", config.size)])
+        .arg(pdf_path)
+        .arg(prefix)
+        .output() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error running the renderer ({}): {}", config.renderer, err)),
+    };
+    if !output.status.success() {
+        return Err(format!("Renderer failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    // pdftoppm pads the page numbers with zeros depending on the page count
+    let path = Path::new(prefix);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, format!("{}-", name.to_string_lossy())),
+        _ => return Err(format!("Bad thumbnail prefix: {}", prefix)),
+    };
+    let entries = match read_dir(dir) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on reading the thumbnails directory: {}", err)),
+    };
+
+    let mut thumbnails: Vec<String> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file| file.starts_with(&name) && file.ends_with(".png"))
+        .map(|file| format!("{}", dir.join(file).display()))
+        .collect();
+    thumbnails.sort();
+
+    if thumbnails.is_empty() {
+        return Err("Renderer produced no thumbnails".to_string());
+    }
+    Ok(thumbnails)
+}
+
+/// Sends the thumbnails of the spooled file with its summary to the chat
+///
+/// If the preview is disabled or can't be rendered, only the summary is sent.
+pub fn send_preview(config: &Config, chat_id: i64, meta: &spool::FileMeta) {
+    let path = spool::file_path(&config.spool.dir, meta);
+    let text = match read(&path) {
+        Ok(data) => summary(meta, &data),
+        Err(err) => {
+            error!("Error on reading {}: {}", path, err);
+            return;
+        }
+    };
+
+    let thumbnails = if config.preview.enabled {
+        let prefix = format!("{}/preview-{}", spool::tmp_dir(&config.spool.dir), meta.id);
+        match render(&path, &config.preview, &prefix) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Error on rendering the preview of {}: {}", meta.id, err);
+                vec![]
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    if thumbnails.is_empty() {
+        match bot::send_message(&config.token, chat_id, &text) {
+            Ok(_) => (),
+            Err(err) => error!("Error on sending message: {:?}", err),
+        };
+        return;
+    }
+
+    for (number, thumbnail) in thumbnails.iter().enumerate() {
+        let caption = if number == 0 { text.clone() } else { "".to_string() };
+        match bot::send_photo(&config.token, chat_id, thumbnail, &caption) {
+            Ok(_) => (),
+            Err(err) => error!("Error on sending photo: {:?}", err),
+        };
+        let _ = remove_file(thumbnail);
+    }
+}
@@ -1,29 +1,81 @@
-use std::fs::{read_dir, remove_file};
+extern crate chrono;
+
+use std::fs::{read, remove_file};
 use std::process::Command;
 use std::sync::{Arc, Mutex};
 
+use self::chrono::Utc;
+
 use config;
+use io_tools;
+use metrics;
+use spool;
+use stamp;
 
 /// Deletes file by filename
 pub fn delete_file(filename: &str) -> Result<String, String> {
     match remove_file(filename) {
-        Ok(_) => return Ok("Ok".to_string()),
-        Err(err) => return Err(format!("Error: {:?}", err)),
+        Ok(_) => Ok("Ok".to_string()),
+        Err(err) => Err(format!("Error: {:?}", err)),
     }
 }
 
 
 /// Prints file by filename via lp (on *nix only)
-pub fn print_from_file(filename: &str, a_config: Arc<Mutex<config::Config>>) -> Result<String, String> {
+///
+/// If stamping is enabled for the printer, the job information of `meta` is stamped
+/// on the temporary copy of the file, which is removed after it is queued.
+/// Returns the CUPS job ID on Ok(), it is empty if lp didn't report it.
+pub fn print_from_file(filename: &str, meta: &spool::FileMeta, a_config: Arc<Mutex<config::Config>>) -> Result<String, String> {
     let config = { a_config.lock().unwrap().clone() };
+    let result = print(filename, meta, &config);
+
+    let channel = metrics::channel_label(meta.channel);
+    let labels = [("channel", channel.as_str()), ("printer", config.printer.as_str())];
+    match result {
+        Ok(_) => {
+            metrics::inc(metrics::JOBS_PRINTED, &labels);
+            metrics::add(metrics::PAGES_PRINTED, &labels, meta.pages.unwrap_or(0) as f64);
+        }
+        Err(_) => metrics::inc(metrics::JOBS_FAILED, &labels),
+    };
+    result
+}
+
+fn print(filename: &str, meta: &spool::FileMeta, config: &config::Config) -> Result<String, String> {
+    let stamp_config = config.stamp_config(&config.printer);
+
+    if !stamp_config.footer && !stamp_config.watermark && !stamp_config.cover {
+        return lp(&config.printer, filename);
+    }
 
-    let _printing_process = match Command::new("lp")
-        .args(&["-d", &config.printer, filename]).spawn() {
-        Ok(child) => child,
-        Err(err) => return Err(format!("Error running the printing process (lp): {}", err)),
+    let data = match read(filename) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on reading the file: {}", err)),
+    };
+    let stamped = stamp::stamp(&data, meta, stamp_config, Utc::now().timestamp())?;
+    let stamped_name = format!("{}/print-{}.pdf", spool::tmp_dir(&config.spool.dir), meta.id);
+    match io_tools::write_bytes_to_file(&stamped_name, stamped) {
+        Ok(_) => (),
+        Err(err) => return Err(format!("Error on writing the stamped file: {}", err)),
     };
 
-    Ok("Ok".to_string())
+    // lp copies the file to the print queue, so the copy can be removed after it exits
+    let result = lp(&config.printer, &stamped_name);
+    let _ = remove_file(&stamped_name);
+    result
+}
+
+// Queues the file and returns the job ID from the output of lp: `request id is Office-42 (1 file(s))`
+fn lp(printer: &str, filename: &str) -> Result<String, String> {
+    match Command::new("lp").args(["-d", printer, filename]).output() {
+        Ok(ref outp) if outp.status.success() => {
+            let stdout = String::from_utf8_lossy(&outp.stdout).to_string();
+            Ok(stdout.split_whitespace().skip_while(|word| *word != "is").nth(1).unwrap_or("").to_string())
+        }
+        Ok(outp) => Err(format!("lp error: {}", String::from_utf8_lossy(&outp.stderr))),
+        Err(err) => Err(format!("Error running the printing process (lp): {}", err)),
+    }
 }
 
 /// Returns output of the `$ lpstat` command
@@ -45,6 +97,38 @@ pub fn get_printers() -> String {
     }
 }
 
+/// Printer known to CUPS with its state: `idle`, `printing` or `disabled`
+#[derive(Serialize, Clone, Debug)]
+pub struct PrinterStatus {
+    pub name: String,
+    pub state: String,
+}
+
+/// Returns the printers from the output of `$ lpstat -p`
+pub fn printers() -> Result<Vec<PrinterStatus>, String> {
+    let output = match Command::new("lpstat").arg("-p").output() {
+        Ok(outp) => outp,
+        Err(err) => return Err(format!("lpstat error: {}", err)),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+
+    // `printer Office is idle.  enabled since ...`, `printer Lab disabled since ...`
+    Ok(stdout.lines().filter_map(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 2 || words[0] != "printer" {
+            return None;
+        }
+        let state = if line.contains("disabled") {
+            "disabled"
+        } else if line.contains("printing") {
+            "printing"
+        } else {
+            "idle"
+        };
+        Some(PrinterStatus { name: words[1].to_string(), state: state.to_string() })
+    }).collect())
+}
+
 /// Cancels the job by its name or number
 pub fn cancel(job: &str) -> Result<String, String> {
     let output = match Command::new("cancel")
@@ -57,32 +141,5 @@ pub fn cancel(job: &str) -> Result<String, String> {
     if output.len() < 3 {
         return Ok("Ok".to_string());
     }
-    return Err(format!("Error on cancel: {}", output));
+    Err(format!("Error on cancel: {}", output))
 }
-
-/// Returns list of pdf files, ready for sending to the Telegram or email
-pub fn get_files() -> Result<String, String> {
-    let entries = match read_dir(".") {
-        Ok(data) => data,
-        Err(err) => return Err(format!("Error on getting the list of the files: {}", err)),
-    };
-
-    let mut v_entries: Vec<String> = vec![];
-    v_entries.push("Files:".to_string());
-    for entry in entries {
-        match entry {
-            Ok(data) => {
-                let extension = match data.path().extension() {
-                    Some(path) => String::from(path.to_string_lossy()),
-                    None => "".to_string()
-                };
-                if extension == "pdf".to_string() || extension.trim().parse::<i64>().is_ok() {
-                    v_entries.push(String::from(data.file_name().to_string_lossy()));
-                }
-            }
-            Err(_) => continue,
-        };
-    }
-
-    Ok(v_entries.join("\n").to_string())
-}
\ No newline at end of file
@@ -0,0 +1,599 @@
+extern crate chrono;
+extern crate rand;
+extern crate toml;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, read, read_dir, remove_file, rename};
+use std::path::Path;
+
+use self::chrono::{Local, TimeZone, Utc};
+
+use config::SpoolConfig;
+use hash;
+use inspect;
+use io_tools;
+
+/// Channel, through which the file was received
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Telegram,
+    /// The mail bot, the submitter is the fingerprint of the mail token
+    Mail,
+    /// The web dashboard and the upload page, the submitter is the fingerprint of the token or the code
+    Web,
+    /// The HTTP API, the submitter is the fingerprint of the API token
+    Api,
+    /// The IPP listener, the submitter is the fingerprint of the mail token from the HTTP basic auth
+    Ipp,
+    /// The Matrix bot, the submitter is the Matrix user ID
+    Matrix,
+}
+
+/// Submission record of the spooled file, stored as `<id>.toml`
+///
+/// Contents of the file are stored once in the `blobs` directory by its SHA-256,
+/// so several submissions of the same document share one blob.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileMeta {
+    pub id: String,
+    pub original_name: String,
+    pub mime: String,
+    pub size: u64,
+    pub pages: Option<u32>,
+    pub sha256: String,
+    pub channel: Channel,
+    pub submitter: String,
+    pub source_url: Option<String>,
+    /// ID of the file, which was transformed into this one
+    #[serde(default)]
+    pub derived_from: Option<String>,
+    /// Print options, which the submitter asked for, like `nup=2 fit`
+    #[serde(default)]
+    pub options: Option<String>,
+    pub received: i64,
+    pub printed: Option<i64>,
+    /// CUPS job ID of the printed file
+    #[serde(default)]
+    pub job: Option<String>,
+    pub rejected: Option<i64>,
+    #[serde(default)]
+    pub pinned: bool,
+    /// Whether the admin was notified about the file, so it can be announced after the restart
+    #[serde(default = "announced_by_default")]
+    pub announced: bool,
+    /// Result of the PDF inspection, None for other types and older records
+    #[serde(default)]
+    pub inspection: Option<inspect::Report>,
+}
+
+/// Reason, why the file was removed from the spool by the cleanup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Removal {
+    Printed,
+    Rejected,
+    Expired,
+    Evicted,
+}
+
+
+// Records, written before the flag was added, were announced right after they were stored
+fn announced_by_default() -> bool {
+    true
+}
+
+impl FileMeta {
+    /// Returns true if the file is waiting for the approval of the admin
+    pub fn is_pending(&self) -> bool {
+        self.printed.is_none() && self.rejected.is_none() && self.derived_from.is_none()
+    }
+
+    /// Returns Telegram ID of the submitter, if the file came from Telegram
+    pub fn telegram_user(&self) -> Option<i64> {
+        match self.channel {
+            Channel::Telegram => self.submitter.parse::<i64>().ok(),
+            Channel::Mail | Channel::Web | Channel::Api | Channel::Ipp | Channel::Matrix => None,
+        }
+    }
+
+    /// Returns the submitter to show: the files, received before the tokens were stored by their fingerprints,
+    /// have the full tokens, so they are masked
+    pub fn submitter_label(&self) -> String {
+        match self.channel {
+            Channel::Mail | Channel::Web | Channel::Api | Channel::Ipp
+                if !self.submitter.starts_with("token ") && !self.submitter.starts_with("code ") =>
+                hash::mask_token(&self.submitter),
+            _ => self.submitter.clone(),
+        }
+    }
+
+    /// Returns status of the file: pending, printed or rejected, and whether it is pinned
+    pub fn status(&self) -> String {
+        let status = match (self.printed, self.rejected) {
+            (Some(printed), _) => match self.job {
+                Some(ref job) => format!("printed at {}, job {}", format_time(printed), job),
+                None => format!("printed at {}", format_time(printed)),
+            },
+            (None, Some(rejected)) => format!("rejected at {}", format_time(rejected)),
+            (None, None) => "pending".to_string(),
+        };
+
+        if self.pinned { format!("{}, pinned", status) } else { status }
+    }
+
+    /// Returns human-readable description of the file, used in the messages to the admin and the alerts,
+    /// so the submitter is shown by `submitter_label`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let meta = read_meta("spool", "28f158d186263820686c8341d510595ace3b27ce").unwrap();
+    /// println!("{}", meta.describe());
+    /// ```
+    pub fn describe(&self) -> String {
+        format!("{}\n  name: {}\n  type: {}, {} bytes, {} pages\n  from: {:?} {}{}{}{}\n  received: {}\n  status: {}\n  sha256: {}{}",
+                self.id, self.original_name, self.mime, self.size,
+                match self.pages {
+                    Some(pages) => format!("{}", pages),
+                    None => "?".to_string(),
+                },
+                self.channel, self.submitter_label(),
+                match self.source_url {
+                    Some(ref url) => format!("\n  source: {}", url),
+                    None => "".to_string(),
+                },
+                match self.derived_from {
+                    Some(ref id) => format!("\n  derived from: {}", id),
+                    None => "".to_string(),
+                },
+                match self.options {
+                    Some(ref options) => format!("\n  options: {}", options),
+                    None => "".to_string(),
+                },
+                format_time(self.received),
+                self.status(),
+                self.sha256,
+                match self.inspection {
+                    Some(ref report) if !report.warnings().is_empty() =>
+                        format!("\n  warnings: {}", report.warnings().join("; ")),
+                    Some(_) => "\n  check: ok".to_string(),
+                    None => "".to_string(),
+                })
+    }
+}
+
+
+/// Formats unix timestamp as local time
+pub fn format_time(timestamp: i64) -> String {
+    match Local.timestamp_opt(timestamp, 0).single() {
+        Some(time) => format!("{}", time.format("%Y-%m-%d %H:%M:%S")),
+        None => format!("{}", timestamp),
+    }
+}
+
+fn blob_path(spool_dir: &str, sha256: &str) -> String {
+    format!("{}/blobs/{}.pdf", spool_dir, sha256)
+}
+
+fn legacy_path(spool_dir: &str, id: &str) -> String {
+    format!("{}/{}.pdf", spool_dir, id)
+}
+
+fn meta_path(spool_dir: &str, id: &str) -> String {
+    format!("{}/{}.toml", spool_dir, id)
+}
+
+/// Counts pages of the PDF by looking for the `/Type /Page` objects
+///
+/// Returns None if no page objects were found (most likely the file is not PDF)
+pub fn count_pages(data: &[u8]) -> Option<u32> {
+    let mut pages = 0;
+    let mut i = 0;
+    while i + 5 <= data.len() {
+        if &data[i..i + 5] == b"/Type" {
+            let mut j = i + 5;
+            while j < data.len() && (data[j] == b' ' || data[j] == b'\r' || data[j] == b'\n') {
+                j += 1;
+            }
+            if j + 5 <= data.len() && &data[j..j + 5] == b"/Page" &&
+                (j + 5 == data.len() || !(data[j + 5] as char).is_alphanumeric()) {
+                pages += 1;
+            }
+            i = j;
+        } else {
+            i += 1;
+        }
+    }
+
+    if pages > 0 { Some(pages) } else { None }
+}
+
+/// Creates the spool directory, if it doesn't exist
+pub fn init(spool_dir: &str) -> Result<(), String> {
+    for dir in &[format!("{}/blobs", spool_dir), tmp_dir(spool_dir)] {
+        match create_dir_all(dir) {
+            Ok(_) => (),
+            Err(err) => return Err(format!("Error on creating the spool directory: {}", err)),
+        };
+    }
+    Ok(())
+}
+
+/// Returns the directory for the files, which are not stored in the spool yet (e.g. being downloaded)
+pub fn tmp_dir(spool_dir: &str) -> String {
+    format!("{}/tmp", spool_dir)
+}
+
+/// Returns path to the contents of the spooled file
+pub fn file_path(spool_dir: &str, meta: &FileMeta) -> String {
+    let legacy = legacy_path(spool_dir, &meta.id);
+    if Path::new(&legacy).exists() {
+        return legacy;
+    }
+    blob_path(spool_dir, &meta.sha256)
+}
+
+/// Saves the file to the spool and creates the submission record, returns it on Ok()
+///
+/// Contents are written only if there is no blob with the same SHA-256 yet.
+/// ID of the submission is derived from the contents, submitter and the time of receiving.
+///
+/// # Examples
+///
+/// ```rust
+/// let meta = store("spool", data, "report.pdf", "application/pdf", Channel::Telegram, "123456").unwrap();
+/// println!("{}", meta.id); // 28f158d186263820
+/// ```
+pub fn store(spool_dir: &str, data: Vec<u8>, original_name: &str, mime: &str,
+             channel: Channel, submitter: &str) -> Result<FileMeta, String> {
+    init(spool_dir)?;
+
+    let inspection = check(&data, mime)?;
+    let sha256 = hash::sha256_data(&data);
+    let meta = new_meta(sha256, data.len() as u64, &data, inspection, original_name, mime, channel, submitter);
+
+    let path = blob_path(spool_dir, &meta.sha256);
+    if !Path::new(&path).exists() {
+        match io_tools::write_bytes_to_file(&path, data) {
+            Ok(_) => (),
+            Err(err) => return Err(format!("Error on writing to file: {}", err)),
+        };
+    }
+
+    write_meta(spool_dir, &meta)?;
+    Ok(meta)
+}
+
+/// Moves already downloaded file to the spool and creates the submission record, returns it on Ok()
+///
+/// Works like `store`, but takes the file from `path` (it should be in the `tmp_dir` of the spool)
+/// with already computed SHA-256 and size. `source_url` is the link the file was downloaded from,
+/// if the user sent the link instead of the file.
+#[allow(clippy::too_many_arguments)]
+pub fn store_file(spool_dir: &str, path: &str, sha256: &str, size: u64, original_name: &str, mime: &str,
+                  channel: Channel, submitter: &str, source_url: Option<&str>) -> Result<FileMeta, String> {
+    init(spool_dir)?;
+
+    let data = match read(path) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on reading the file: {}", err)),
+    };
+    let inspection = match check(&data, mime) {
+        Ok(data) => data,
+        Err(err) => {
+            let _ = remove_file(path);
+            return Err(err);
+        }
+    };
+    let mut meta = new_meta(sha256.to_string(), size, &data, inspection, original_name, mime, channel, submitter);
+    meta.source_url = source_url.map(|url| url.to_string());
+
+    let blob = blob_path(spool_dir, &meta.sha256);
+    let result = if Path::new(&blob).exists() { remove_file(path) } else { rename(path, &blob) };
+    match result {
+        Ok(_) => (),
+        Err(err) => return Err(format!("Error on moving the file to the spool: {}", err)),
+    };
+
+    write_meta(spool_dir, &meta)?;
+    Ok(meta)
+}
+
+// Inspects PDF files, returns Err if the file can't be printed
+fn check(data: &[u8], mime: &str) -> Result<Option<inspect::Report>, String> {
+    if mime != "application/pdf" && !data.starts_with(b"%PDF-") {
+        return Ok(None);
+    }
+
+    let report = inspect::inspect(data);
+    match report.rejection() {
+        Some(reason) => Err(format!("File is rejected: {}", reason)),
+        None => Ok(Some(report)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn new_meta(sha256: String, size: u64, data: &[u8], inspection: Option<inspect::Report>, original_name: &str,
+            mime: &str, channel: Channel, submitter: &str) -> FileMeta {
+    let now = Utc::now();
+    let mut id_seed = Vec::from(format!("{}{}{}", sha256, submitter, now.timestamp_nanos_opt().unwrap_or_default()));
+    id_seed.push(rand::random::<u8>());
+
+    FileMeta {
+        id: hash::hash_data(&id_seed)[..16].to_string(),
+        original_name: original_name.to_string(),
+        mime: mime.to_string(),
+        size,
+        pages: inspection.as_ref().and_then(|report| report.pages).or_else(|| count_pages(data)),
+        sha256,
+        channel,
+        submitter: submitter.to_string(),
+        source_url: None,
+        derived_from: None,
+        options: None,
+        received: now.timestamp(),
+        printed: None,
+        job: None,
+        rejected: None,
+        pinned: false,
+        announced: false,
+        inspection,
+    }
+}
+
+/// Returns the files, which are waiting for the approval, the oldest first
+pub fn pending(spool_dir: &str) -> Result<Vec<FileMeta>, String> {
+    let mut files: Vec<FileMeta> = list(spool_dir)?.into_iter().filter(|meta| meta.is_pending()).collect();
+    files.sort_by_key(|meta| meta.received);
+    Ok(files)
+}
+
+/// Records, that the admin was notified about the file
+pub fn mark_announced(spool_dir: &str, id: &str) -> Result<(), String> {
+    let mut meta = read_meta(spool_dir, id)?;
+    meta.announced = true;
+    write_meta(spool_dir, &meta)
+}
+
+/// Returns earlier submissions of the same document by the same submitter,
+/// received within `window` seconds before the given one
+pub fn duplicates(spool_dir: &str, meta: &FileMeta, window: i64) -> Result<Vec<FileMeta>, String> {
+    Ok(list(spool_dir)?.into_iter()
+        .filter(|other| other.id != meta.id && other.sha256 == meta.sha256 &&
+            other.channel == meta.channel && other.submitter == meta.submitter &&
+            other.received <= meta.received && meta.received - other.received <= window)
+        .collect())
+}
+
+/// Returns warning for the admin if the submission looks like an accidental double-send
+///
+/// `window` is set in minutes, `0` disables the check.
+pub fn duplicate_warning(spool_dir: &str, meta: &FileMeta, window: u64) -> Option<String> {
+    if window == 0 {
+        return None;
+    }
+
+    let earlier = match duplicates(spool_dir, meta, window as i64 * 60) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on looking for duplicates: {}", err);
+            return None;
+        }
+    };
+
+    if earlier.is_empty() {
+        return None;
+    }
+
+    let ids: Vec<String> = earlier.iter()
+        .map(|other| format!("{} ({})", other.id, format_time(other.received)))
+        .collect();
+    Some(format!("Warning: {:?} {} has already sent this document within {} minutes: {}",
+                 meta.channel, meta.submitter_label(), window, ids.join(", ")))
+}
+
+/// Writes metadata record of the file
+pub fn write_meta(spool_dir: &str, meta: &FileMeta) -> Result<(), String> {
+    let meta_str = match toml::to_string(meta) {
+        Ok(value) => value,
+        Err(err) => return Err(format!("Error on serializing the metadata: {}", err)),
+    };
+
+    match io_tools::write_to_file(&meta_path(spool_dir, &meta.id), meta_str) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error on writing the metadata: {}", err)),
+    }
+}
+
+/// Reads metadata record of the file by its ID
+pub fn read_meta(spool_dir: &str, id: &str) -> Result<FileMeta, String> {
+    let path = meta_path(spool_dir, id);
+    if id.contains('/') || !io_tools::exists(&path) {
+        return Err(format!("No such file in the spool: {}", id));
+    }
+
+    match toml::from_str(&io_tools::read_str(&path)) {
+        Ok(value) => Ok(value),
+        Err(err) => Err(format!("Error on reading the metadata of {}: {}", id, err)),
+    }
+}
+
+/// Returns metadata of all spooled files, oldest first
+pub fn list(spool_dir: &str) -> Result<Vec<FileMeta>, String> {
+    if !io_tools::exists(spool_dir) {
+        return Ok(vec![]);
+    }
+
+    let entries = match read_dir(spool_dir) {
+        Ok(data) => data,
+        Err(err) => return Err(format!("Error on getting the list of the files: {}", err)),
+    };
+
+    let mut metas: Vec<FileMeta> = vec![];
+    for entry in entries {
+        let path = match entry {
+            Ok(data) => data.path(),
+            Err(_) => continue,
+        };
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+        let id = match path.file_stem() {
+            Some(stem) => String::from(stem.to_string_lossy()),
+            None => continue,
+        };
+        match read_meta(spool_dir, &id) {
+            Ok(meta) => metas.push(meta),
+            Err(err) => error!("{}", err),
+        };
+    }
+
+    metas.sort_by_key(|meta| meta.received);
+    Ok(metas)
+}
+
+/// Deletes the file and its metadata from the spool
+///
+/// The contents are deleted only when no other submission refers to them.
+/// Returns true on Ok() if the contents were deleted.
+pub fn delete(spool_dir: &str, id: &str) -> Result<bool, String> {
+    let meta = read_meta(spool_dir, id)?;
+    let mut references = references(spool_dir, &list(spool_dir)?);
+    remove(spool_dir, &meta, &mut references)
+}
+
+// Counts the records, which refer to every stored contents
+fn references(spool_dir: &str, metas: &Vec<FileMeta>) -> HashMap<String, usize> {
+    let mut references: HashMap<String, usize> = HashMap::new();
+    for meta in metas {
+        *references.entry(file_path(spool_dir, meta)).or_insert(0) += 1;
+    }
+    references
+}
+
+// Deletes the record and the contents, if `references` has no other records of them
+fn remove(spool_dir: &str, meta: &FileMeta, references: &mut HashMap<String, usize>) -> Result<bool, String> {
+    match remove_file(meta_path(spool_dir, &meta.id)) {
+        Ok(_) => (),
+        Err(err) => return Err(format!("Error on deleting the metadata: {:?}", err)),
+    };
+
+    let path = file_path(spool_dir, meta);
+    let left = match references.get_mut(&path) {
+        Some(count) => {
+            *count = count.saturating_sub(1);
+            *count
+        }
+        None => 0,
+    };
+    if left > 0 || !Path::new(&path).exists() {
+        return Ok(false);
+    }
+
+    match remove_file(&path) {
+        Ok(_) => Ok(true),
+        Err(err) => Err(format!("Error on deleting the file: {:?}", err)),
+    }
+}
+
+/// Returns the list of the spooled files with their details, ready for sending to the Telegram
+pub fn describe_files(spool_dir: &str) -> Result<String, String> {
+    let metas = list(spool_dir)?;
+
+    let mut v_entries: Vec<String> = vec![];
+    v_entries.push("Files:".to_string());
+    for meta in metas {
+        v_entries.push(meta.describe());
+    }
+
+    Ok(v_entries.join("\n\n"))
+}
+
+
+fn stored_size(spool_dir: &str, metas: &[FileMeta]) -> u64 {
+    let mut paths: HashSet<String> = HashSet::new();
+    metas.iter()
+        .filter(|meta| paths.insert(file_path(spool_dir, meta)))
+        .map(|meta| meta.size)
+        .sum()
+}
+
+/// Returns the number of the submissions and the total size of the stored files in bytes
+pub fn usage(spool_dir: &str) -> Result<(usize, u64), String> {
+    let metas = list(spool_dir)?;
+    Ok((metas.len(), stored_size(spool_dir, &metas)))
+}
+
+fn removal_reason(config: &SpoolConfig, meta: &FileMeta, now: i64) -> Option<Removal> {
+    // the admin may still approve the pending file
+    if meta.pinned || meta.is_pending() {
+        return None;
+    }
+
+    if config.delete_rejected && meta.rejected.is_some() {
+        return Some(Removal::Rejected);
+    }
+
+    if let Some(printed) = meta.printed {
+        if config.delete_printed_after > 0 && now - printed >= config.delete_printed_after as i64 * 3600 {
+            return Some(Removal::Printed);
+        }
+    }
+
+    if config.max_age > 0 && now - meta.received >= config.max_age as i64 * 3600 {
+        return Some(Removal::Expired);
+    }
+
+    None
+}
+
+/// Applies retention policies to the spool, returns the list of removed files on Ok()
+///
+/// Printed and rejected files are removed after the configured delay, then files older than `max_age`
+/// are removed. If the spool is still bigger than `max_size`, the oldest files are evicted.
+/// Pinned files and the files, waiting for the approval, are never removed.
+///
+/// # Examples
+///
+/// ```rust
+/// let removed = cleanup(&config.spool, Utc::now().timestamp()).unwrap();
+/// ```
+pub fn cleanup(config: &SpoolConfig, now: i64) -> Result<Vec<(FileMeta, Removal)>, String> {
+    let metas = list(&config.dir)?;
+    let mut references = references(&config.dir, &metas);
+
+    let mut removed: Vec<(FileMeta, Removal)> = vec![];
+    let mut kept: Vec<FileMeta> = vec![];
+    for meta in metas {
+        match removal_reason(config, &meta, now) {
+            Some(reason) => match remove(&config.dir, &meta, &mut references) {
+                Ok(_) => removed.push((meta, reason)),
+                Err(err) => error!("Error on removing {}: {}", meta.id, err),
+            },
+            None => kept.push(meta),
+        };
+    }
+
+    if config.max_size > 0 {
+        let limit = config.max_size * 1024 * 1024;
+        let mut total: u64 = stored_size(&config.dir, &kept);
+        for meta in kept {
+            if total <= limit {
+                break;
+            }
+            if meta.pinned || meta.is_pending() {
+                continue;
+            }
+            match remove(&config.dir, &meta, &mut references) {
+                Ok(freed) => {
+                    if freed {
+                        total -= meta.size;
+                    }
+                    removed.push((meta, Removal::Evicted));
+                }
+                Err(err) => error!("Error on removing {}: {}", meta.id, err),
+            };
+        }
+    }
+
+    Ok(removed)
+}
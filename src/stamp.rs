@@ -0,0 +1,190 @@
+extern crate lopdf;
+
+use self::lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use self::lopdf::content::{Content, Operation};
+use self::lopdf::dictionary;
+
+use config::StampConfig;
+use convert::{self, Font, PAGE_HEIGHT, PAGE_WIDTH};
+use inspect;
+use spool::{self, FileMeta};
+
+const FONT_NAME: &str = "TPStamp";
+const FOOTER_SIZE: f64 = 7.0;
+const FOOTER_OFFSET: f64 = 8.0;
+const WATERMARK_OPACITY: f64 = 0.15;
+const COVER_SIZE: f64 = 14.0;
+
+
+fn to_pdf_error(err: lopdf::Error) -> String {
+    format!("Error on stamping PDF: {:?}", err)
+}
+
+/// Returns the line, which identifies the job on the printout
+///
+/// Mail and API tokens are printed by their fingerprints, because they give access to the printer.
+pub fn job_label(meta: &FileMeta, now: i64) -> String {
+    format!("{:?} {} | job {} | {}", meta.channel, meta.submitter_label(), meta.id, spool::format_time(now))
+}
+
+fn text(operations: &mut Vec<Operation>, line: &str) {
+    operations.push(Operation::new("Tj", vec![Object::String(convert::encode_line(line), StringFormat::Literal)]));
+}
+
+// Effective resources of the page, they may be inherited from the page tree
+fn page_resources(doc: &Document, page_id: ObjectId) -> Dictionary {
+    match doc.get_page_resources(page_id) {
+        (Some(dict), _) => dict.clone(),
+        (None, ids) => ids.first().and_then(|id| doc.get_dictionary(*id).ok()).cloned().unwrap_or_default(),
+    }
+}
+
+fn add_resource(doc: &Document, resources: &mut Dictionary, category: &str, id: ObjectId) {
+    let mut entries = match resources.get(category.as_bytes()).and_then(|object| doc.dereference(object)) {
+        Ok((_, object)) => object.as_dict().ok().cloned().unwrap_or_default(),
+        Err(_) => Dictionary::new(),
+    };
+    entries.set(FONT_NAME, id);
+    resources.set(category, entries);
+}
+
+fn stamp_page(doc: &mut Document, page_id: ObjectId, label: &str, config: &StampConfig,
+              font_id: ObjectId, state_id: ObjectId) -> Result<(), String> {
+    let rect = inspect::media_box(doc, page_id).unwrap_or([0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT]);
+    let mut resources = page_resources(doc, page_id);
+    add_resource(doc, &mut resources, "Font", font_id);
+    add_resource(doc, &mut resources, "ExtGState", state_id);
+
+    // the page contents are wrapped into q/Q, so the stamp doesn't depend on their graphics state
+    let mut operations = vec![Operation::new("Q", vec![])];
+    if config.footer {
+        operations.push(Operation::new("BT", vec![]));
+        operations.push(Operation::new("g", vec![0.into()]));
+        operations.push(Operation::new("Tf", vec![FONT_NAME.into(), FOOTER_SIZE.into()]));
+        operations.push(Operation::new("Td", vec![(rect[0] + 2.0 * FOOTER_OFFSET).into(), (rect[1] + FOOTER_OFFSET).into()]));
+        text(&mut operations, label);
+        operations.push(Operation::new("ET", vec![]));
+    }
+    if config.watermark {
+        let (width, height) = (rect[2] - rect[0], rect[3] - rect[1]);
+        let diagonal = (width * width + height * height).sqrt();
+        let size = (diagonal * 0.8 / (label.chars().count() as f64 * Font::Helvetica.char_width())).clamp(8.0, 60.0);
+        let length = label.chars().count() as f64 * Font::Helvetica.char_width() * size;
+        let (cos, sin) = (width / diagonal, height / diagonal);
+        let (x, y) = (rect[0] + (width - cos * length) / 2.0, rect[1] + (height - sin * length) / 2.0);
+
+        operations.push(Operation::new("q", vec![]));
+        operations.push(Operation::new("gs", vec![FONT_NAME.into()]));
+        operations.push(Operation::new("g", vec![0.5.into()]));
+        operations.push(Operation::new("BT", vec![]));
+        operations.push(Operation::new("Tf", vec![FONT_NAME.into(), size.into()]));
+        operations.push(Operation::new("Tm", vec![cos.into(), sin.into(), (-sin).into(), cos.into(), x.into(), y.into()]));
+        text(&mut operations, label);
+        operations.push(Operation::new("ET", vec![]));
+        operations.push(Operation::new("Q", vec![]));
+    }
+
+    let after = Content { operations }.encode().map_err(to_pdf_error)?;
+    let before_id = doc.add_object(Stream::new(dictionary! {}, b"q\n".to_vec()));
+    let after_id = doc.add_object(Stream::new(dictionary! {}, after));
+
+    let mut contents: Vec<Object> = vec![before_id.into()];
+    contents.extend(doc.get_page_contents(page_id).into_iter().map(Object::Reference));
+    contents.push(after_id.into());
+
+    let page = doc.get_object_mut(page_id).and_then(|object| object.as_dict_mut()).map_err(to_pdf_error)?;
+    page.set("Contents", contents);
+    page.set("Resources", resources);
+    Ok(())
+}
+
+fn add_cover(doc: &mut Document, meta: &FileMeta, now: i64, font_id: ObjectId) -> Result<(), String> {
+    let pages_id = doc.catalog().and_then(|catalog| catalog.get(b"Pages"))
+        .and_then(|object| object.as_reference()).map_err(to_pdf_error)?;
+
+    let lines = vec![
+        format!("Print job {}", meta.id),
+        "".to_string(),
+        format!("File: {}", meta.original_name),
+        format!("Pages: {}", meta.pages.map_or("?".to_string(), |pages| format!("{}", pages))),
+        format!("Channel: {:?}", meta.channel),
+        format!("Submitter: {}", meta.submitter_label()),
+        format!("Received: {}", spool::format_time(meta.received)),
+        format!("Printed: {}", spool::format_time(now)),
+    ];
+    let mut operations = vec![
+        Operation::new("BT", vec![]),
+        Operation::new("Tf", vec![FONT_NAME.into(), COVER_SIZE.into()]),
+        Operation::new("TL", vec![(COVER_SIZE * 1.5).into()]),
+        Operation::new("Td", vec![72.0.into(), (PAGE_HEIGHT - 144.0).into()]),
+    ];
+    for line in lines {
+        text(&mut operations, &line);
+        operations.push(Operation::new("T*", vec![]));
+    }
+    operations.push(Operation::new("ET", vec![]));
+
+    let content_id = doc.add_object(Stream::new(dictionary! {},
+                                                Content { operations }.encode().map_err(to_pdf_error)?));
+    let cover_id = doc.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "MediaBox" => vec![0.into(), 0.into(), PAGE_WIDTH.into(), PAGE_HEIGHT.into()],
+        "Contents" => content_id,
+        "Resources" => dictionary! {
+            "Font" => dictionary! {
+                FONT_NAME => font_id
+            }
+        }
+    });
+
+    let pages = doc.get_object_mut(pages_id).and_then(|object| object.as_dict_mut()).map_err(to_pdf_error)?;
+    let count = pages.get(b"Count").and_then(|object| object.as_i64()).unwrap_or(0);
+    pages.get_mut(b"Kids").and_then(|object| object.as_array_mut()).map_err(to_pdf_error)?.insert(0, cover_id.into());
+    pages.set("Count", count + 1);
+    Ok(())
+}
+
+/// Stamps the job information on the document, returns the stamped PDF on Ok()
+///
+/// Depending on the config it adds the footer with the submitter, channel, job ID and time to every page,
+/// the same text as the diagonal semi-transparent watermark, and the cover page with the job details.
+///
+/// # Examples
+///
+/// ```rust
+/// let stamped = stamp(&read(spool::file_path("spool", &meta)).unwrap(), &meta,
+///                     config.stamp_config("HP_LaserJet"), Utc::now().timestamp()).unwrap();
+/// ```
+pub fn stamp(data: &[u8], meta: &FileMeta, config: &StampConfig, now: i64) -> Result<Vec<u8>, String> {
+    let mut doc = inspect::load_pdf(data).map_err(|err| format!("Error on stamping PDF: {}", err))?;
+    let font_id = doc.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica",
+        "Encoding" => "WinAnsiEncoding"
+    });
+    let state_id = doc.add_object(dictionary! {
+        "Type" => "ExtGState",
+        "ca" => WATERMARK_OPACITY,
+        "CA" => WATERMARK_OPACITY
+    });
+
+    if config.footer || config.watermark {
+        let label = job_label(meta, now);
+        let pages: Vec<ObjectId> = doc.get_pages().values().cloned().collect();
+        for page_id in pages {
+            stamp_page(&mut doc, page_id, &label, config, font_id, state_id)?;
+        }
+    }
+    if config.cover {
+        add_cover(&mut doc, meta, now, font_id)?;
+    }
+    doc.compress();
+
+    let mut buffer: Vec<u8> = vec![];
+    match doc.save_to(&mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(err) => Err(format!("Error on writing PDF: {}", err)),
+    }
+}
@@ -0,0 +1,207 @@
+extern crate libc;
+
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use alerts::{self, Alert, Event};
+use config::{Config, Transport};
+use database;
+use spool;
+use webhook;
+
+// Worker, which has run longer than this, is treated as stable, and its backoff is reset
+const STABLE_RUN: u64 = 10 * 60;
+const TICK: u64 = 1;
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Subsystem, which runs in its own thread and is restarted if it exits or panics
+pub struct Worker {
+    pub name: String,
+    pub run: Arc<dyn Fn() + Send + Sync>,
+}
+
+/// Work, which should be finished before the shutdown, it is counted until the guard is dropped
+pub struct Task {
+    _private: (),
+}
+
+impl Worker {
+    pub fn new<F: Fn() + Send + Sync + 'static>(name: &str, run: F) -> Worker {
+        Worker {
+            name: name.to_string(),
+            run: Arc::new(run),
+        }
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGTERM and SIGINT start the graceful shutdown instead of killing the process
+pub fn install_signal_handlers() {
+    unsafe {
+        libc::signal(libc::SIGTERM, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+/// Returns true after the shutdown has started, the workers should stop taking new work
+pub fn shutting_down() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Sleeps for the duration, but wakes up on the shutdown, returns false if the shutdown has started
+///
+/// # Examples
+///
+/// ```rust
+/// while sleep(Duration::from_secs(60 * 60)) {
+///     cleanup();
+/// }
+/// ```
+pub fn sleep(duration: Duration) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        if shutting_down() {
+            return false;
+        }
+        let left = duration - started.elapsed();
+        thread::sleep(if left < Duration::from_secs(TICK) { left } else { Duration::from_secs(TICK) });
+    }
+    !shutting_down()
+}
+
+/// Registers the work, which the shutdown waits for, like the download
+///
+/// # Examples
+///
+/// ```rust
+/// let _task = task();
+/// download(...);
+/// ```
+pub fn task() -> Task {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    Task { _private: () }
+}
+
+/// Returns the number of the running tasks
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(text), _) => text.to_string(),
+        (_, Some(text)) => text.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+fn notify_admin(text: &str, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    let admin = { a_users_table.lock().unwrap().get_admin() };
+    alerts::notify_admin(&config, admin, Alert::new(Event::WorkerFailed, text));
+}
+
+// Runs the worker and restarts it with the exponential backoff until the shutdown
+fn watch(worker: Worker, a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let (initial, max) = {
+        let config = a_config.lock().unwrap();
+        (config.supervisor.backoff.max(1), config.supervisor.max_backoff)
+    };
+    let mut backoff = initial;
+
+    loop {
+        let started = Instant::now();
+        let run = Arc::clone(&worker.run);
+        let result = match thread::Builder::new().name(worker.name.clone()).spawn(move || run()) {
+            Ok(handle) => handle.join(),
+            Err(err) => {
+                error!("Error on starting {}: {}", worker.name, err);
+                Ok(())
+            }
+        };
+        if shutting_down() {
+            return;
+        }
+
+        let reason = match result {
+            Ok(_) => "exited".to_string(),
+            Err(payload) => format!("panicked: {}", panic_message(&payload)),
+        };
+        if started.elapsed() > Duration::from_secs(STABLE_RUN) {
+            backoff = initial;
+        }
+
+        error!("Worker {} {}, restarting in {} s", worker.name, reason, backoff);
+        notify_admin(&format!("Teleprint: {} {}, restarting in {} s", worker.name, reason, backoff),
+                     &a_config, &a_users_table);
+
+        if !sleep(Duration::from_secs(backoff)) {
+            return;
+        }
+        backoff = (backoff * 2).min(max);
+    }
+}
+
+
+/// Runs the workers, restarts them if they exit or panic, and waits for SIGTERM or SIGINT
+///
+/// After the signal it deletes the Telegram webhook, if it is used, waits up to `shutdown_timeout` seconds
+/// for the running tasks, like downloads, and returns, so the process can exit.
+///
+/// # Examples
+///
+/// ```rust
+/// install_signal_handlers();
+/// supervise(vec![
+///     Worker::new("cleaner", move || cleaner::run_cleaner(Arc::clone(&config), Arc::clone(&users_table))),
+/// ], Arc::clone(&config), Arc::clone(&users_table));
+/// ```
+pub fn supervise(workers: Vec<Worker>, a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    for worker in workers {
+        let (a_config, a_users_table) = (Arc::clone(&a_config), Arc::clone(&a_users_table));
+        thread::spawn(move || watch(worker, a_config, a_users_table));
+    }
+
+    while !shutting_down() {
+        thread::sleep(Duration::from_secs(TICK));
+    }
+
+    // Telegram holds the updates without the webhook, so nothing is posted to the stopping bot
+    let config = { a_config.lock().unwrap().clone() };
+    if config.telegram.transport == Transport::Webhook {
+        match webhook::delete_webhook(&config.token) {
+            Ok(_) => info!("Telegram webhook is deleted"),
+            Err(err) => error!("Error on deleting the Telegram webhook: {}", err),
+        };
+    }
+
+    let timeout = Duration::from_secs(config.supervisor.shutdown_timeout);
+    info!("Shutting down, waiting for {} tasks", in_flight());
+    let started = Instant::now();
+    while in_flight() > 0 && started.elapsed() < timeout {
+        thread::sleep(Duration::from_millis(100));
+    }
+    if in_flight() > 0 {
+        warn!("{} tasks didn't finish in time", in_flight());
+    }
+    match spool::pending(&config.spool.dir) {
+        Ok(files) => info!("{} files are waiting for the approval, they will be restored on the next start", files.len()),
+        Err(err) => error!("Error on listing the pending files: {}", err),
+    };
+    info!("Teleprint stopped");
+}
@@ -0,0 +1,426 @@
+extern crate lopdf;
+
+use std::collections::BTreeMap;
+
+use self::lopdf::{Document, Object, ObjectId, Stream};
+use self::lopdf::content::{Content, Operation};
+use self::lopdf::dictionary;
+
+use convert::{PAGE_HEIGHT, PAGE_WIDTH};
+use inspect;
+
+// Margin of the sheet in points for the scaled pages, printers can't print at the very edge
+const MARGIN: f64 = 14.0;
+const MAX_PAGES: u32 = 10000;
+
+// Width and height in points
+type Size = (f64, f64);
+// Place of the page on the sheet: x, y, width and height in points
+type Cell = (f64, f64, f64, f64);
+
+/// Transformation of the document, made before printing
+///
+/// Pages are selected first, then rotated, then placed on the sheets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    /// Page numbers (starting from 1) in the order of printing, all pages if None
+    pub pages: Option<Vec<u32>>,
+    /// Pages per sheet: 1, 2, 4, 6 or 9
+    pub nup: u32,
+    /// Clockwise rotation of every page: 0, 90, 180 or 270
+    pub rotate: i64,
+    /// Scale the pages to A4
+    pub fit: bool,
+    /// Impose the pages for the saddle-stitched booklet (2 pages on each side of the sheet)
+    pub booklet: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            pages: None,
+            nup: 1,
+            rotate: 0,
+            fit: false,
+            booklet: false,
+        }
+    }
+}
+
+
+fn parse_pages(value: &str) -> Result<Vec<u32>, String> {
+    let mut pages: Vec<u32> = vec![];
+    for part in value.split(',').filter(|part| !part.is_empty()) {
+        let mut bounds = part.splitn(2, '-').map(|bound| bound.trim().parse::<u32>());
+        let (first, last) = match (bounds.next(), bounds.next()) {
+            (Some(Ok(first)), None) => (first, first),
+            (Some(Ok(first)), Some(Ok(last))) => (first, last),
+            _ => return Err(format!("Bad page range: {}", part)),
+        };
+        if first == 0 || last == 0 || first.max(last) > MAX_PAGES {
+            return Err(format!("Bad page range: {}", part));
+        }
+
+        // reversed ranges like 7-3 print the pages backwards
+        if first <= last {
+            pages.extend(first..last + 1);
+        } else {
+            pages.extend((last..first + 1).rev());
+        }
+    }
+
+    if pages.is_empty() {
+        return Err("No pages were selected".to_string());
+    }
+    Ok(pages)
+}
+
+impl Options {
+    /// Parses options from the arguments of `/print`, like `pages=3-7,1 nup=2 rotate=90 fit booklet`
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// let options = Options::parse(&["pages=1-4", "nup=2"]).unwrap();
+    /// assert_eq!(options.pages, Some(vec![1, 2, 3, 4]));
+    /// ```
+    pub fn parse(args: &[&str]) -> Result<Options, String> {
+        let mut options = Options::default();
+        for arg in args {
+            let mut pair = arg.splitn(2, '=');
+            let (key, value) = (pair.next().unwrap_or("").to_lowercase(), pair.next().unwrap_or(""));
+            match key.as_str() {
+                "pages" => options.pages = Some(parse_pages(value)?),
+                "nup" => options.nup = match value.parse::<u32>() {
+                    Ok(count) if [1, 2, 4, 6, 9].contains(&count) => count,
+                    _ => return Err(format!("Pages per sheet should be 1, 2, 4, 6 or 9, not {}", value)),
+                },
+                "rotate" => options.rotate = match value.parse::<i64>() {
+                    Ok(angle) if angle % 90 == 0 => (angle % 360 + 360) % 360,
+                    _ => return Err(format!("Rotation should be a multiple of 90, not {}", value)),
+                },
+                "fit" => options.fit = true,
+                "booklet" => options.booklet = true,
+                _ => return Err(format!("Unknown option: {}", arg)),
+            };
+        }
+
+        if options.booklet && options.nup != 1 {
+            return Err("Booklet already places 2 pages per side, it can't be combined with nup".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Returns true if the document is printed as is
+    pub fn is_identity(&self) -> bool {
+        *self == Options::default()
+    }
+
+    /// Returns options in the form of the `/print` arguments
+    pub fn describe(&self) -> String {
+        let mut args: Vec<String> = vec![];
+        if let Some(ref pages) = self.pages {
+            let pages: Vec<String> = pages.iter().map(|page| format!("{}", page)).collect();
+            args.push(format!("pages={}", pages.join(",")));
+        }
+        if self.nup != 1 {
+            args.push(format!("nup={}", self.nup));
+        }
+        if self.rotate != 0 {
+            args.push(format!("rotate={}", self.rotate));
+        }
+        if self.fit {
+            args.push("fit".to_string());
+        }
+        if self.booklet {
+            args.push("booklet".to_string());
+        }
+        args.join(" ")
+    }
+}
+
+
+// Source page, turned into the Form XObject
+struct Form {
+    id: ObjectId,
+    bbox: [f64; 4],
+    rotate: i64,
+}
+
+impl Form {
+    fn size(&self) -> (f64, f64) {
+        let (width, height) = (self.bbox[2] - self.bbox[0], self.bbox[3] - self.bbox[1]);
+        if self.rotate % 180 == 0 { (width, height) } else { (height, width) }
+    }
+}
+
+fn to_pdf_error(err: lopdf::Error) -> String {
+    format!("Error on transforming PDF: {:?}", err)
+}
+
+fn make_form(doc: &mut Document, page_id: ObjectId, rotate: i64) -> Result<Form, String> {
+    let bbox = match inspect::media_box(doc, page_id) {
+        Some(data) => data,
+        None => return Err(format!("Page {:?} has no size", page_id)),
+    };
+
+    let mut content: Vec<u8> = vec![];
+    for id in doc.get_page_contents(page_id) {
+        if let Ok(Object::Stream(stream)) = doc.get_object(id) {
+            match stream.decompressed_content() {
+                Ok(data) => content.extend(data),
+                Err(_) => content.extend(stream.content.iter()),
+            };
+            content.push(b'\n');
+        }
+    }
+
+    let resources = match doc.get_page_resources(page_id) {
+        (Some(dict), _) => Object::Dictionary(dict.clone()),
+        (None, ids) => match ids.first() {
+            Some(id) => Object::Reference(*id),
+            None => Object::Dictionary(dictionary! {}),
+        },
+    };
+    let page_rotate = doc.get_dictionary(page_id).ok()
+        .and_then(|page| page.get(b"Rotate").and_then(|object| object.as_i64()).ok())
+        .unwrap_or(0);
+
+    let id = doc.add_object(Stream::new(dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Form",
+        "BBox" => bbox.iter().map(|value| (*value).into()).collect::<Vec<Object>>(),
+        "Resources" => resources
+    }, content));
+
+    Ok(Form { id, bbox, rotate: ((page_rotate + rotate) % 360 + 360) % 360 })
+}
+
+// Operations, which draw the form into the cell (x, y, width, height), scaled down to fit if `scale` is set
+fn place(form: &Form, name: &str, cell: Cell, scale: bool) -> Vec<Operation> {
+    let (x, y, width, height) = cell;
+    let (form_width, form_height) = form.size();
+    let factor = if scale { (width / form_width).min(height / form_height) } else { 1.0 };
+
+    // clockwise rotation around the center of the form, then moving it to the center of the cell
+    let (sin, cos) = match form.rotate {
+        90 => (1.0, 0.0),
+        180 => (0.0, -1.0),
+        270 => (-1.0, 0.0),
+        _ => (0.0, 1.0),
+    };
+    let (a, b, c, d) = (factor * cos, -factor * sin, factor * sin, factor * cos);
+    let (center_x, center_y) = ((form.bbox[0] + form.bbox[2]) / 2.0, (form.bbox[1] + form.bbox[3]) / 2.0);
+    let e = x + width / 2.0 - (a * center_x + c * center_y);
+    let f = y + height / 2.0 - (b * center_x + d * center_y);
+
+    vec![
+        Operation::new("q", vec![]),
+        Operation::new("re", vec![x.into(), y.into(), width.into(), height.into()]),
+        Operation::new("W", vec![]),
+        Operation::new("n", vec![]),
+        Operation::new("cm", vec![a.into(), b.into(), c.into(), d.into(), e.into(), f.into()]),
+        Operation::new("Do", vec![name.into()]),
+        Operation::new("Q", vec![]),
+    ]
+}
+
+// Returns sheet size and cells (left to right, top to bottom) for `count` pages per sheet,
+// choosing the grid and orientation, which gives the biggest pages
+fn layout(count: u32, page: Size) -> (Size, Vec<Cell>) {
+    let grids: Vec<(u32, u32)> = match count {
+        2 => vec![(2, 1), (1, 2)],
+        6 => vec![(3, 2), (2, 3)],
+        4 => vec![(2, 2)],
+        9 => vec![(3, 3)],
+        _ => vec![(1, 1)],
+    };
+
+    let mut best: Option<(f64, Size, (u32, u32))> = None;
+    for grid in grids {
+        for sheet in &[(PAGE_WIDTH, PAGE_HEIGHT), (PAGE_HEIGHT, PAGE_WIDTH)] {
+            let cell = ((sheet.0 - 2.0 * MARGIN) / grid.0 as f64, (sheet.1 - 2.0 * MARGIN) / grid.1 as f64);
+            let factor = (cell.0 / page.0).min(cell.1 / page.1);
+            if best.is_none_or(|(best_factor, _, _)| factor > best_factor) {
+                best = Some((factor, *sheet, grid));
+            }
+        }
+    }
+
+    let (_, sheet, (columns, rows)) = best.unwrap_or((1.0, (PAGE_WIDTH, PAGE_HEIGHT), (1, 1)));
+    let (width, height) = ((sheet.0 - 2.0 * MARGIN) / columns as f64, (sheet.1 - 2.0 * MARGIN) / rows as f64);
+    let mut cells = vec![];
+    for row in 0..rows {
+        for column in 0..columns {
+            cells.push((MARGIN + column as f64 * width, sheet.1 - MARGIN - (row + 1) as f64 * height, width, height));
+        }
+    }
+    (sheet, cells)
+}
+
+// Order of the pages for the booklet: sheets folded in half and put one into another,
+// both sides of every sheet hold 2 pages, None is the blank page
+fn booklet_order(pages: &[u32]) -> Vec<Option<u32>> {
+    let count = pages.len().div_ceil(4) * 4;
+    let page = |index: usize| pages.get(index).cloned();
+
+    let mut order = vec![];
+    for sheet in 0..count / 4 {
+        order.push(page(count - 1 - 2 * sheet));
+        order.push(page(2 * sheet));
+        order.push(page(2 * sheet + 1));
+        order.push(page(count - 2 - 2 * sheet));
+    }
+    order
+}
+
+/// Transforms the PDF according to options, returns the new PDF on Ok()
+///
+/// Pages of the source are embedded as Form XObjects and placed on the new sheets,
+/// so the contents are not rasterized. Links, bookmarks and form fields are not kept.
+/// Booklet should be printed on both sides, flipping on the short edge.
+///
+/// # Examples
+///
+/// ```rust
+/// let options = Options::parse(&["pages=3-7", "nup=2"]).unwrap();
+/// let derived = transform(&read("slides.pdf").unwrap(), &options).unwrap();
+/// ```
+pub fn transform(data: &[u8], options: &Options) -> Result<Vec<u8>, String> {
+    let mut doc = inspect::load_pdf(data).map_err(|err| format!("Error on transforming PDF: {}", err))?;
+    let pages: BTreeMap<u32, ObjectId> = doc.get_pages();
+
+    let selected = match options.pages {
+        Some(ref selected) => selected.clone(),
+        None => pages.keys().cloned().collect(),
+    };
+    if let Some(page) = selected.iter().find(|page| !pages.contains_key(page)) {
+        return Err(format!("There is no page {}, the document has {} pages", page, pages.len()));
+    }
+
+    let mut forms: BTreeMap<u32, Form> = BTreeMap::new();
+    for page in &selected {
+        if !forms.contains_key(page) {
+            let form = make_form(&mut doc, pages[page], options.rotate)?;
+            forms.insert(*page, form);
+        }
+    }
+
+    let order: Vec<Option<u32>> = if options.booklet {
+        booklet_order(&selected)
+    } else {
+        selected.iter().map(|page| Some(*page)).collect()
+    };
+    let per_sheet = if options.booklet { 2 } else { options.nup as usize };
+
+    let pages_id = doc.new_object_id();
+    let mut kids: Vec<Object> = vec![];
+    for sheet_pages in order.chunks(per_sheet) {
+        let first = match sheet_pages.iter().filter_map(|page| *page).next() {
+            Some(page) => &forms[&page],
+            None => &forms[&selected[0]],
+        };
+        let (sheet, cells, scale) = if per_sheet == 1 && !options.fit {
+            let size = first.size();
+            (size, vec![(0.0, 0.0, size.0, size.1)], false)
+        } else {
+            let (sheet, cells) = layout(per_sheet as u32, first.size());
+            (sheet, cells, true)
+        };
+
+        let mut operations: Vec<Operation> = vec![];
+        let mut xobjects = dictionary! {};
+        for (index, (page, cell)) in sheet_pages.iter().zip(cells).enumerate() {
+            if let Some(page) = *page {
+                let name = format!("P{}", index);
+                operations.extend(place(&forms[&page], &name, cell, scale));
+                xobjects.set(name, forms[&page].id);
+            }
+        }
+
+        let content = Content { operations };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().map_err(to_pdf_error)?));
+        let page_id = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), sheet.0.into(), sheet.1.into()],
+            "Contents" => content_id,
+            "Resources" => dictionary! {
+                "XObject" => xobjects
+            }
+        });
+        kids.push(page_id.into());
+    }
+
+    let count = kids.len() as i64;
+    doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+        "Type" => "Pages",
+        "Kids" => kids,
+        "Count" => count
+    }));
+
+    // the old page tree and everything pointing to its pages is dropped
+    let root_id = doc.trailer.get(b"Root").and_then(|object| object.as_reference()).map_err(to_pdf_error)?;
+    {
+        let catalog = doc.get_object_mut(root_id).and_then(|object| object.as_dict_mut()).map_err(to_pdf_error)?;
+        catalog.set("Pages", pages_id);
+        for key in &[&b"Outlines"[..], b"StructTreeRoot", b"OpenAction", b"Dests", b"PageLabels", b"AcroForm"] {
+            catalog.remove(key);
+        }
+    }
+    doc.prune_objects();
+    doc.compress();
+
+    let mut buffer: Vec<u8> = vec![];
+    match doc.save_to(&mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(err) => Err(format!("Error on writing PDF: {}", err)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_page_ranges() {
+        let options = Options::parse(&["pages=3-5,1,9-7"]).unwrap();
+        assert_eq!(options.pages, Some(vec![3, 4, 5, 1, 9, 8, 7]));
+        assert_eq!(Options::parse(&["pages=2,,4"]).unwrap().pages, Some(vec![2, 4]));
+    }
+
+    #[test]
+    fn rejects_bad_page_ranges() {
+        for pages in &["pages=", "pages=0", "pages=1-0", "pages=a", "pages=1-2-3", "pages=-3"] {
+            assert!(Options::parse(&[pages]).is_err(), "{} was accepted", pages);
+        }
+        assert!(Options::parse(&[&format!("pages=1-{}", MAX_PAGES + 1)]).is_err());
+    }
+
+    #[test]
+    fn parses_layout_options() {
+        let options = Options::parse(&["NUP=4", "rotate=-90", "fit"]).unwrap();
+        assert_eq!(options.nup, 4);
+        assert_eq!(options.rotate, 270);
+        assert!(options.fit);
+        assert_eq!(options.describe(), "nup=4 rotate=270 fit");
+
+        assert!(Options::parse(&["nup=3"]).is_err());
+        assert!(Options::parse(&["rotate=45"]).is_err());
+        assert!(Options::parse(&["booklet", "nup=2"]).is_err());
+        assert!(Options::parse(&["duplex"]).is_err());
+        assert!(Options::parse(&[]).unwrap().is_identity());
+    }
+
+    #[test]
+    fn orders_booklet_pages() {
+        let order = booklet_order(&(1..9).collect::<Vec<u32>>());
+        let expected = [8, 1, 2, 7, 6, 3, 4, 5];
+        assert_eq!(order, expected.iter().map(|&page| Some(page)).collect::<Vec<_>>());
+
+        // 5 pages take 2 sheets, the last 3 sides are blank
+        let order = booklet_order(&[1, 2, 3, 4, 5]);
+        assert_eq!(order, vec![None, Some(1), Some(2), None, None, Some(3), Some(4), Some(5)]);
+    }
+}
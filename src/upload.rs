@@ -0,0 +1,161 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use config::Config;
+use database;
+use http::{self, escape_html as escape, Handler, Part, Request, Response};
+use pipeline::{self, Attachment, Identity, IngestChannel, Notifier, Silent};
+use spool::{self, Channel};
+use supervisor;
+use transform;
+
+// Multipart headers and the form fields besides the file
+const FORM_OVERHEAD: u64 = 64 * 1024;
+// Delay of the failed upload, which slows down guessing of the tokens and codes
+const AUTH_DELAY: u64 = 1;
+// The status page is reloaded while the file waits for the approval
+const REFRESH: u64 = 15;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:0 auto;max-width:600px;padding:0 1em}\
+p{margin:.8em 0}input{width:100%;box-sizing:border-box;padding:.3em}\
+.message{background:#eef;padding:.5em;margin-bottom:1em}";
+
+/// The upload page as the source of the print requests, the page answers the submitter itself
+pub struct UploadChannel;
+
+impl IngestChannel for UploadChannel {
+    fn channel(&self) -> Channel {
+        Channel::Web
+    }
+
+    fn notifier(&self, _identity: &Identity) -> Box<dyn Notifier> {
+        Box::new(Silent)
+    }
+}
+
+
+fn html(status: u16, title: &str, body: &str, refresh: bool) -> Response {
+    let refresh = if refresh { format!("<meta http-equiv=\"refresh\" content=\"{}\">", REFRESH) } else { "".to_string() };
+    let page = format!("<!DOCTYPE html><html><head><meta charset=\"utf-8\">{}<title>{} - Teleprint</title>\
+                        <style>{}</style></head><body><h1>{}</h1>{}</body></html>",
+                       refresh, escape(title), STYLE, escape(title), body);
+    Response::new(status, "text/html; charset=utf-8", Vec::from(page))
+        .with_header("Cache-Control", "no-store")
+        .with_header("X-Frame-Options", "DENY")
+        .with_header("Content-Security-Policy", "default-src 'self'; style-src 'unsafe-inline'")
+}
+
+fn form_page(status: u16, message: &str) -> Response {
+    let message = if message.is_empty() { "".to_string() } else { format!("<div class=\"message\">{}</div>", escape(message)) };
+    html(status, "Print a file",
+         &format!("{}<form method=\"post\" action=\"/upload\" enctype=\"multipart/form-data\">\
+                   <p><input name=\"access\" placeholder=\"Token or one-time code\" autocomplete=\"off\" autofocus></p>\
+                   <p><input name=\"file\" type=\"file\" accept=\"application/pdf,image/*\"></p>\
+                   <p><input name=\"options\" placeholder=\"Options: pages=1-4 nup=2 fit\"></p>\
+                   <p>PDF files and images are accepted, they are printed after the approval of the admin.</p>\
+                   <button>Send</button></form>", message), false)
+}
+
+fn text_field(parts: &[Part], name: &str) -> String {
+    parts.iter().find(|part| part.name == name)
+        .map_or("".to_string(), |part| String::from_utf8_lossy(&part.data).trim().to_string())
+}
+
+// Sends the uploaded file to the admin for the approval like the files from the bots
+fn upload(request: &Request, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    if supervisor::shutting_down() {
+        return form_page(503, "Teleprint is restarting, send the file again in a minute");
+    }
+    let parts = match request.multipart() {
+        Ok(data) => data,
+        Err(err) => return form_page(400, &err),
+    };
+    let file = match parts.iter().find(|part| part.name == "file" && !part.data.is_empty()) {
+        Some(data) => data,
+        None => return form_page(400, "No file was sent"),
+    };
+    let options = text_field(&parts, "options");
+    let options = match transform::Options::parse(&options.split_whitespace().collect::<Vec<&str>>()) {
+        Ok(data) => data,
+        Err(err) => return form_page(400, &format!("Error in the print options: {}", err)),
+    };
+
+    let access = text_field(&parts, "access");
+    let name = file.filename.clone().unwrap_or("upload".to_string());
+    let attachment = Attachment::Document { name, data: file.data.clone() };
+    let meta = match pipeline::submit(UploadChannel.request(Identity::TokenOrCode(access), attachment, options),
+                                      a_config, a_users_table) {
+        Ok(data) => data,
+        Err(ref err) if err == pipeline::UNAUTHORIZED => {
+            thread::sleep(Duration::from_secs(AUTH_DELAY));
+            return form_page(401, "Unknown token or code");
+        }
+        Err(err) => return form_page(400, &err),
+    };
+
+    Response::redirect(&format!("/jobs/{}", meta.id))
+}
+
+// Status of the uploaded file, its random ID is known only to the submitter
+fn status_page(config: &Config, id: &str) -> Response {
+    let meta = match spool::read_meta(&config.spool.dir, id) {
+        Ok(ref data) if data.channel == Channel::Web => data.clone(),
+        _ => return html(404, "No such file", "<p><a href=\"/\">Send a file</a></p>", false),
+    };
+
+    let status = if meta.is_pending() { "waiting for the approval".to_string() } else { meta.status() };
+    let rows = [("Name", meta.original_name.clone()),
+        ("Pages", meta.pages.map_or("?".to_string(), |pages| format!("{}", pages))),
+        ("Options", meta.options.clone().unwrap_or("-".to_string())),
+        ("Status", status)];
+    let rows: Vec<String> = rows.iter().map(|&(name, ref value)| format!("<p><b>{}:</b> {}</p>", name, escape(value))).collect();
+    html(200, &meta.original_name, &format!("{}<p><a href=\"/\">Send another file</a></p>", rows.join("")),
+         meta.is_pending())
+}
+
+
+fn handle(request: &Request, a_config: &Arc<Mutex<Config>>, a_users_table: &Arc<Mutex<database::UsersTable>>) -> Response {
+    let config = { a_config.lock().unwrap().clone() };
+    let parts: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), parts.as_slice()) {
+        ("GET", [""]) => form_page(200, ""),
+        ("POST", ["upload"]) => upload(request, a_config, a_users_table),
+        ("GET", ["jobs", id]) => status_page(&config, id),
+        _ => Response::text(404, "Not found"),
+    }
+}
+
+/// Runs the upload page, if it is enabled in the `[upload]` section
+///
+/// Anybody with a mail token or a one-time code from `/gencode` can send the PDF file or the image
+/// with the print options and follow its status. The files go to the admin for the approval
+/// like the files from Telegram and mail.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let users_table = Arc::new(Mutex::new(read_users().unwrap()));
+/// let upload = thread::spawn(move || {
+///        run_server(Arc::clone(&config), Arc::clone(&users_table));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>, a_users_table: Arc<Mutex<database::UsersTable>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if !config.upload.enabled {
+        return;
+    }
+
+    let max_body = if config.upload.max_size == 0 {
+        http::MAX_BODY
+    } else {
+        config.upload.max_size.saturating_mul(1024 * 1024).saturating_add(FORM_OVERHEAD)
+    };
+    let handler: Handler = Arc::new(move |request: &Request| handle(request, &a_config, &a_users_table));
+    match http::serve(&config.upload.address, max_body, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the upload page: {}", err),
+    };
+}
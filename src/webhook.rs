@@ -0,0 +1,209 @@
+extern crate futures;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate telebot;
+
+use std::sync::{Arc, Mutex};
+
+use self::futures::stream::Stream;
+use self::futures::sync::mpsc::{self, UnboundedSender};
+use self::serde::Serialize;
+use self::telebot::objects::Update;
+use self::telebot::RcBot;
+
+use config::{Config, Transport};
+use hash;
+use health;
+use http::{self, Handler, Request, Response};
+use metrics;
+
+// The updates contain only the IDs of the files, so the bigger bodies aren't from Telegram
+const MAX_UPDATE: u64 = 1024 * 1024;
+
+lazy_static! {
+    // Queue of the running bot, the updates are refused while the bot is restarted
+    static ref UPDATES: Mutex<Option<UnboundedSender<Update>>> = Mutex::new(None);
+    // Secret of the webhook, if it isn't set in the config
+    static ref GENERATED_SECRET: String = hash::random_hex();
+}
+
+#[derive(Serialize)]
+struct SetWebhook {
+    url: String,
+    secret_token: String,
+    max_connections: u32,
+}
+
+#[derive(Serialize)]
+struct DeleteWebhook {
+    drop_pending_updates: bool,
+}
+
+#[derive(Deserialize)]
+struct ApiAnswer {
+    ok: bool,
+    description: Option<String>,
+}
+
+
+fn secret(config: &Config) -> String {
+    if config.telegram.secret_token.is_empty() {
+        GENERATED_SECRET.clone()
+    } else {
+        config.telegram.secret_token.clone()
+    }
+}
+
+// Calls the method of the Bot API, Telegram explains the refusals in `description`
+fn call<T: Serialize>(token: &str, method: &str, params: &T) -> Result<(), String> {
+    let url = format!("https://api.telegram.org/bot{}/{}", token, method);
+    let result = match reqwest::Client::new().post(&url).json(params).send() {
+        Ok(mut resp) => match resp.json::<ApiAnswer>() {
+            Ok(ref answer) if answer.ok => Ok(()),
+            Ok(answer) => Err(answer.description.unwrap_or(format!("Telegram API responded with {}", resp.status()))),
+            Err(_) => Err(format!("Telegram API responded with {}", resp.status())),
+        },
+        // the error of reqwest contains the URL with the bot token
+        Err(err) => Err(err.get_ref().map_or("request failed".to_string(), |err| format!("{}", err))),
+    };
+    match result {
+        Ok(_) => health::record_ok(health::TELEGRAM),
+        Err(ref err) => {
+            metrics::inc(metrics::TELEGRAM_ERRORS, &[("method", method)]);
+            health::record_error(health::TELEGRAM, &format!("{}: {}", method, err));
+        }
+    };
+    result
+}
+
+/// Registers `webhook_url` of the `[telegram]` section in Telegram with the secret token
+///
+/// Telegram keeps the updates, which it couldn't deliver, and posts them after the registration,
+/// so nothing is lost while the bot is restarted.
+///
+/// # Examples
+///
+/// ```rust
+/// set_webhook(&config).unwrap();
+/// ```
+pub fn set_webhook(config: &Config) -> Result<(), String> {
+    if config.telegram.webhook_url.is_empty() {
+        return Err("webhook_url isn't set in the [telegram] section".to_string());
+    }
+    call(&config.token, "setWebhook", &SetWebhook {
+        url: config.telegram.webhook_url.clone(),
+        secret_token: secret(config),
+        max_connections: config.telegram.max_connections,
+    })
+}
+
+/// Deletes the webhook, so the updates can be polled again, the waiting updates are kept
+///
+/// # Examples
+///
+/// ```rust
+/// delete_webhook(&config.token).unwrap();
+/// ```
+pub fn delete_webhook(token: &str) -> Result<(), String> {
+    call(token, "deleteWebhook", &DeleteWebhook { drop_pending_updates: false })
+}
+
+/// Returns the stream of the updates from the webhook for the bot, which replaces `get_stream`
+///
+/// The commands go to the handlers of `new_cmd` without the command word like in the polling,
+/// the other updates are returned.
+///
+/// # Examples
+///
+/// ```rust
+/// let handle = updates(&bot).and_then(|(bot, upd)| { ... });
+/// lp.run(handle.for_each(|_| Ok(())).into_future()).unwrap();
+/// ```
+pub fn updates<E>(bot: &RcBot) -> impl Stream<Item = (RcBot, Update), Error = E> {
+    let (sender, receiver) = mpsc::unbounded();
+    *UPDATES.lock().unwrap() = Some(sender);
+
+    let bot = bot.clone();
+    receiver.filter_map(move |mut update: Update| {
+        let command = update.message.as_ref()
+            .and_then(|msg| msg.text.as_ref())
+            .and_then(|text| text.split_whitespace().next())
+            .filter(|word| word.starts_with('/'))
+            // the commands in the groups end with the name of the bot: /print@teleprint_bot
+            .map(|word| word.split('@').next().unwrap_or(word).to_string());
+
+        if let Some(command) = command {
+            if let Some(handler) = bot.inner.handlers.borrow().get(&command) {
+                let mut msg = match update.message.take() {
+                    Some(data) => data,
+                    None => return None,
+                };
+                msg.text = msg.text.map(|text| text.split_whitespace().skip(1).collect::<Vec<&str>>().join(" "));
+                match handler.unbounded_send((bot.clone(), msg)) {
+                    Ok(_) => (),
+                    Err(err) => error!("Error on passing the command {}: {}", command, err),
+                };
+                return None;
+            }
+        }
+        Some((bot.clone(), update))
+    }).map_err(|_| unreachable!())
+}
+
+
+fn handle(request: &Request, secret: &str) -> Response {
+    if request.method != "POST" {
+        return Response::text(405, "Method not allowed");
+    }
+
+    // compared in constant time, so the secret can't be guessed by the response time
+    let given = request.header("X-Telegram-Bot-Api-Secret-Token").unwrap_or("");
+    if given.len() != secret.len() ||
+        given.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+        return Response::text(401, "Wrong secret token");
+    }
+
+    // the broken update would be posted again and again, so it is dropped
+    let update: Update = match serde_json::from_slice(&request.body) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("Error on parsing the Telegram update: {}", err);
+            return Response::text(200, "Ignored");
+        }
+    };
+
+    let updates = UPDATES.lock().unwrap();
+    match *updates {
+        Some(ref sender) if sender.unbounded_send(update).is_ok() => Response::text(200, "Ok"),
+        // Telegram posts it again later
+        _ => Response::text(503, "The bot is restarting"),
+    }
+}
+
+/// Runs the endpoint of the Telegram webhook, if `transport` of the `[telegram]` section is `Webhook`
+///
+/// Telegram posts only to HTTPS, so the endpoint on `address` should be behind the reverse proxy,
+/// which serves `webhook_url`. The updates without the secret token are refused.
+///
+/// # Examples
+///
+/// ```rust
+/// let config = Arc::new(Mutex::new(read_config().unwrap()));
+/// let webhook = thread::spawn(move || {
+///        run_server(Arc::clone(&config));
+///  });
+/// ```
+pub fn run_server(a_config: Arc<Mutex<Config>>) {
+    let config = { a_config.lock().unwrap().clone() };
+    if config.telegram.transport != Transport::Webhook {
+        return;
+    }
+
+    let secret = secret(&config);
+    let handler: Handler = Arc::new(move |request: &Request| handle(request, &secret));
+    match http::serve(&config.telegram.address, MAX_UPDATE, handler) {
+        Ok(_) => (),
+        Err(err) => error!("Error on running the Telegram webhook: {}", err),
+    };
+}